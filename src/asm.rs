@@ -0,0 +1,954 @@
+//! Turns assembly in Cowgod's syntax into ROMs.
+//!
+//! Besides the mnemonics produced by [`disasm`](crate::disasm) the assembler
+//! understands:
+//! * labels (`loop:`), which may be followed by an instruction on the same line
+//! * constants (`SPEED EQU 3`)
+//! * data (`DB 0x10, "text"` and `DW 0x1234`)
+//! * other source files (`INCLUDE "sprites.asm"`)
+//! * expressions made of numbers (`42`, `0x2A`, `0b101010`), symbols, the
+//!   current address `$`, parentheses and the operators
+//!   `+ - * / % << >> & ^ | ~`
+//!
+//! Comments start with `;`. Mnemonics and register names are case
+//! insensitive, symbols are not. Register names and keywords like `DT` can't
+//! be used as symbols.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{emulator::ROM_OFFSET, instruction::Op, ram::XO_CHIP_RAM_SIZE};
+
+/// How deeply `INCLUDE`s may be nested before a cycle is assumed.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// An error in the assembled source. Lines and columns start at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.line, self.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Assembles `source` into a ROM that can be loaded at [`ROM_OFFSET`].
+/// Included files are looked up relative to the working directory.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut lines = Vec::new();
+    read_lines(source, "<source>", Path::new(""), 0, &mut lines)?;
+    Assembler::default().run(&lines)
+}
+
+/// Assembles the file at `path`. Included files are looked up relative to the
+/// file including them.
+pub fn assemble_file(path: impl AsRef<Path>) -> Result<Vec<u8>, AsmError> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|error| AsmError {
+        file: path.display().to_string(),
+        line: 0,
+        column: 0,
+        message: error.to_string(),
+    })?;
+
+    let mut lines = Vec::new();
+    read_lines(
+        &source,
+        &path.display().to_string(),
+        path.parent().unwrap_or_else(|| Path::new("")),
+        0,
+        &mut lines,
+    )?;
+    Assembler::default().run(&lines)
+}
+
+/// A line of source with comments removed and includes resolved.
+struct Line {
+    file: String,
+    number: usize,
+    text: String,
+}
+
+impl Line {
+    fn error(&self, column: usize, message: impl Into<String>) -> AsmError {
+        AsmError {
+            file: self.file.clone(),
+            line: self.number,
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+/// Splits `source` into [`Line`]s, replacing `INCLUDE` directives by the lines
+/// of the included file.
+fn read_lines(
+    source: &str,
+    file: &str,
+    dir: &Path,
+    depth: usize,
+    lines: &mut Vec<Line>,
+) -> Result<(), AsmError> {
+    for (index, text) in source.lines().enumerate() {
+        let line = Line {
+            file: file.to_string(),
+            number: index + 1,
+            text: strip_comment(text).to_string(),
+        };
+
+        let trimmed = line.text.trim_start();
+        let is_include = trimmed
+            .get(..7)
+            .is_some_and(|word| word.eq_ignore_ascii_case("include"))
+            && trimmed[7..].starts_with(char::is_whitespace);
+        if !is_include {
+            lines.push(line);
+            continue;
+        }
+
+        let column = line.text.len() - trimmed.len() + 1;
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(line.error(column, "Includes are nested too deeply"));
+        }
+        let name = trimmed[7..].trim();
+        let name = name
+            .strip_prefix('"')
+            .and_then(|name| name.strip_suffix('"'))
+            .ok_or_else(|| line.error(column + 8, "Expected a quoted file name"))?;
+
+        let path: PathBuf = dir.join(name);
+        let included = fs::read_to_string(&path)
+            .map_err(|error| line.error(column, format!("{}: {}", path.display(), error)))?;
+        read_lines(
+            &included,
+            &path.display().to_string(),
+            path.parent().unwrap_or(dir),
+            depth + 1,
+            lines,
+        )?;
+    }
+    Ok(())
+}
+
+/// Removes everything after a `;` that is not part of a string.
+fn strip_comment(text: &str) -> &str {
+    let mut in_string = false;
+    for (index, char) in text.char_indices() {
+        match char {
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &text[..index],
+            _ => {}
+        }
+    }
+    text
+}
+
+/// A comma separated part of a statement and the column it starts at.
+#[derive(Clone)]
+struct Operand {
+    text: String,
+    column: usize,
+}
+
+enum Statement {
+    Instruction {
+        mnemonic: String,
+        column: usize,
+        operands: Vec<Operand>,
+    },
+    Bytes(Vec<Operand>),
+    Words(Vec<Operand>),
+}
+
+/// A statement of a [`Line`] placed at an address.
+struct Placed<'a> {
+    line: &'a Line,
+    address: usize,
+    statement: Statement,
+}
+
+/// A constant that still has to be evaluated.
+struct Constant<'a> {
+    line: &'a Line,
+    address: usize,
+    name: String,
+    value: Operand,
+}
+
+#[derive(Default)]
+struct Assembler {
+    symbols: HashMap<String, i64>,
+}
+
+impl Assembler {
+    fn run(mut self, lines: &[Line]) -> Result<Vec<u8>, AsmError> {
+        // First pass: find out where everything goes
+        let mut placed = Vec::new();
+        let mut constants = Vec::new();
+        let mut address = ROM_OFFSET;
+
+        for line in lines {
+            let (label, rest, rest_column) = split_label(line)?;
+            if let Some((name, column)) = label {
+                self.define(line, column, name, address as i64)?;
+            }
+
+            let rest_trimmed = rest.trim_start();
+            if rest_trimmed.is_empty() {
+                continue;
+            }
+            let column = rest_column + rest.len() - rest_trimmed.len();
+            let (word, operands) = split_word(rest_trimmed, column);
+
+            // `NAME EQU value`
+            if let Some((text, operands_column)) = operands {
+                let (second, value) = split_word(text, operands_column);
+                if second.eq_ignore_ascii_case("equ") {
+                    let (text, value_column) =
+                        value.ok_or_else(|| line.error(column, "Expected a value after EQU"))?;
+                    if !is_symbol(word) {
+                        return Err(line.error(column, format!("Invalid symbol name: {}", word)));
+                    }
+                    if is_reserved(word) {
+                        return Err(line.error(column, format!("Reserved name: {}", word)));
+                    }
+                    constants.push(Constant {
+                        line,
+                        address,
+                        name: word.to_string(),
+                        value: Operand {
+                            text: text.trim_end().to_string(),
+                            column: value_column,
+                        },
+                    });
+                    continue;
+                }
+            }
+
+            let operands = match operands {
+                Some((text, column)) => split_operands(line, text, column)?,
+                None => Vec::new(),
+            };
+            let statement = match word.to_ascii_uppercase().as_str() {
+                "DB" => Statement::Bytes(operands),
+                "DW" => Statement::Words(operands),
+                mnemonic => Statement::Instruction {
+                    mnemonic: mnemonic.to_string(),
+                    column,
+                    operands,
+                },
+            };
+
+            let size = statement_size(&statement);
+            placed.push(Placed {
+                line,
+                address,
+                statement,
+            });
+            address += size;
+            if address > XO_CHIP_RAM_SIZE {
+                return Err(line.error(column, "Program does not fit into memory"));
+            }
+        }
+
+        self.resolve_constants(constants)?;
+
+        // Second pass: emit the bytes
+        let mut rom = Vec::with_capacity(address - ROM_OFFSET);
+        for placed in &placed {
+            let line = placed.line;
+            match &placed.statement {
+                Statement::Bytes(operands) => {
+                    for operand in operands {
+                        match string_literal(&operand.text) {
+                            Some(string) => rom.extend(string.bytes()),
+                            None => rom.push(self.byte(line, placed.address, operand)?),
+                        }
+                    }
+                }
+                Statement::Words(operands) => {
+                    for operand in operands {
+                        let value = self.value(line, placed.address, operand, -0x8000, 0xFFFF)?;
+                        rom.extend((value as u16).to_be_bytes());
+                    }
+                }
+                Statement::Instruction {
+                    mnemonic,
+                    column,
+                    operands,
+                } => {
+                    let (op, long) =
+                        self.encode(line, placed.address, mnemonic, *column, operands)?;
+                    rom.extend(op.encode());
+                    if let Some(long) = long {
+                        rom.extend(long.to_be_bytes());
+                    }
+                }
+            }
+        }
+        Ok(rom)
+    }
+
+    fn define(
+        &mut self,
+        line: &Line,
+        column: usize,
+        name: &str,
+        value: i64,
+    ) -> Result<(), AsmError> {
+        if self.symbols.insert(name.to_string(), value).is_some() {
+            return Err(line.error(column, format!("Symbol defined twice: {}", name)));
+        }
+        Ok(())
+    }
+
+    /// Evaluates constants in any order, as they may refer to each other.
+    fn resolve_constants(&mut self, mut pending: Vec<Constant>) -> Result<(), AsmError> {
+        while !pending.is_empty() {
+            let mut progress = false;
+            let mut first_error = None;
+            let mut remaining = Vec::new();
+
+            for constant in pending {
+                match eval(&constant.value, constant.address, &self.symbols) {
+                    Ok(value) => {
+                        self.define(constant.line, constant.value.column, &constant.name, value)?;
+                        progress = true;
+                    }
+                    Err((column, message)) => {
+                        first_error.get_or_insert_with(|| constant.line.error(column, message));
+                        remaining.push(constant);
+                    }
+                }
+            }
+
+            if !progress {
+                return Err(first_error.expect("unresolved constants have an error"));
+            }
+            pending = remaining;
+        }
+        Ok(())
+    }
+
+    fn value(
+        &self,
+        line: &Line,
+        address: usize,
+        operand: &Operand,
+        min: i64,
+        max: i64,
+    ) -> Result<i64, AsmError> {
+        let value = eval(operand, address, &self.symbols)
+            .map_err(|(column, message)| line.error(column, message))?;
+        if value < min || value > max {
+            return Err(line.error(
+                operand.column,
+                format!("Value {} out of range {}..={}", value, min, max),
+            ));
+        }
+        Ok(value)
+    }
+
+    /// Negative bytes are stored as two's complement.
+    fn byte(&self, line: &Line, address: usize, operand: &Operand) -> Result<u8, AsmError> {
+        Ok(self.value(line, address, operand, -0x80, 0xFF)? as u8)
+    }
+
+    fn nibble(&self, line: &Line, address: usize, operand: &Operand) -> Result<u8, AsmError> {
+        Ok(self.value(line, address, operand, 0, 0xF)? as u8)
+    }
+
+    fn addr(&self, line: &Line, address: usize, operand: &Operand) -> Result<u16, AsmError> {
+        Ok(self.value(line, address, operand, 0, 0xFFF)? as u16)
+    }
+
+    /// Encodes an instruction. The second value is the address following
+    /// `LD I, LONG`.
+    fn encode(
+        &self,
+        line: &Line,
+        address: usize,
+        mnemonic: &str,
+        column: usize,
+        operands: &[Operand],
+    ) -> Result<(Op, Option<u16>), AsmError> {
+        let args = operands.iter().map(Arg::parse).collect::<Vec<_>>();
+        let byte = |operand| self.byte(line, address, operand);
+        let nibble = |operand| self.nibble(line, address, operand);
+        let addr = |operand| self.addr(line, address, operand);
+
+        use Arg::*;
+        let op = match (mnemonic, args.as_slice()) {
+            ("CLS", []) => Op::Cls,
+            ("RET", []) => Op::Ret,
+            ("SCD", [Expr(n)]) => Op::ScrollDown(nibble(n)?),
+            ("SCU", [Expr(n)]) => Op::ScrollUp(nibble(n)?),
+            ("SCR", []) => Op::ScrollRight,
+            ("SCL", []) => Op::ScrollLeft,
+            ("EXIT", []) => Op::Exit,
+            ("LOW", []) => Op::Low,
+            ("HIGH", []) => Op::High,
+            ("SYS", [Expr(a)]) => Op::Sys(addr(a)?),
+            ("JP", [Expr(a)]) => Op::Jp(addr(a)?),
+            ("JP", [V(0), Expr(a)]) => Op::JpOffset(addr(a)?),
+            ("CALL", [Expr(a)]) => Op::Call(addr(a)?),
+            ("SE", [V(x), V(y)]) => Op::SeVxVy { x: *x, y: *y },
+            ("SE", [V(x), Expr(b)]) => Op::SeVxByte {
+                x: *x,
+                byte: byte(b)?,
+            },
+            ("SNE", [V(x), V(y)]) => Op::SneVxVy { x: *x, y: *y },
+            ("SNE", [V(x), Expr(b)]) => Op::SneVxByte {
+                x: *x,
+                byte: byte(b)?,
+            },
+            ("LD", [IndirectI, Range(x, y)]) => Op::SaveRange { x: *x, y: *y },
+            ("LD", [Range(x, y), IndirectI]) => Op::LoadRange { x: *x, y: *y },
+            ("LD", [V(x), V(y)]) => Op::LdVxVy { x: *x, y: *y },
+            ("LD", [V(x), Dt]) => Op::LdVxDt(*x),
+            ("LD", [V(x), K]) => Op::LdVxK(*x),
+            ("LD", [V(x), IndirectI]) => Op::LoadRegs(*x),
+            ("LD", [V(x), R]) => Op::LoadFlags(*x),
+            ("LD", [V(x), Expr(b)]) => Op::LdVxByte {
+                x: *x,
+                byte: byte(b)?,
+            },
+            ("LD", [I, Long(a)]) => {
+                let long = self.value(line, address, a, 0, 0xFFFF)? as u16;
+                return Ok((Op::LdILong, Some(long)));
+            }
+            ("LD", [I, Expr(a)]) => Op::LdI(addr(a)?),
+            ("LD", [Dt, V(x)]) => Op::LdDtVx(*x),
+            ("LD", [St, V(x)]) => Op::LdStVx(*x),
+            ("LD", [F, V(x)]) => Op::LdFVx(*x),
+            ("LD", [Hf, V(x)]) => Op::LdHfVx(*x),
+            ("LD", [B, V(x)]) => Op::LdBVx(*x),
+            ("LD", [IndirectI, V(x)]) => Op::StoreRegs(*x),
+            ("LD", [R, V(x)]) => Op::StoreFlags(*x),
+            ("LD", [Pitch, V(x)]) => Op::Pitch(*x),
+            ("ADD", [I, V(x)]) => Op::AddIVx(*x),
+            ("ADD", [V(x), V(y)]) => Op::AddVxVy { x: *x, y: *y },
+            ("ADD", [V(x), Expr(b)]) => Op::AddVxByte {
+                x: *x,
+                byte: byte(b)?,
+            },
+            ("OR", [V(x), V(y)]) => Op::Or { x: *x, y: *y },
+            ("AND", [V(x), V(y)]) => Op::And { x: *x, y: *y },
+            ("XOR", [V(x), V(y)]) => Op::Xor { x: *x, y: *y },
+            ("SUB", [V(x), V(y)]) => Op::Sub { x: *x, y: *y },
+            ("SUBN", [V(x), V(y)]) => Op::Subn { x: *x, y: *y },
+            // Without VY the shift works the same regardless of the quirks
+            ("SHR", [V(x)]) => Op::Shr { x: *x, y: *x },
+            ("SHR", [V(x), V(y)]) => Op::Shr { x: *x, y: *y },
+            ("SHL", [V(x)]) => Op::Shl { x: *x, y: *x },
+            ("SHL", [V(x), V(y)]) => Op::Shl { x: *x, y: *y },
+            ("RND", [V(x), Expr(b)]) => Op::Rnd {
+                x: *x,
+                byte: byte(b)?,
+            },
+            ("DRW", [V(x), V(y), Expr(n)]) => Op::Drw {
+                x: *x,
+                y: *y,
+                n: nibble(n)?,
+            },
+            ("SKP", [V(x)]) => Op::Skp(*x),
+            ("SKNP", [V(x)]) => Op::Sknp(*x),
+            ("PLANE", [Expr(n)]) => Op::Plane(nibble(n)?),
+            ("AUDIO", []) => Op::Audio,
+            (
+                "CLS" | "RET" | "SCD" | "SCU" | "SCR" | "SCL" | "EXIT" | "LOW" | "HIGH" | "SYS"
+                | "JP" | "CALL" | "SE" | "SNE" | "LD" | "ADD" | "OR" | "AND" | "XOR" | "SUB"
+                | "SUBN" | "SHR" | "SHL" | "RND" | "DRW" | "SKP" | "SKNP" | "PLANE" | "AUDIO",
+                _,
+            ) => {
+                return Err(line.error(column, format!("Invalid operands for {}", mnemonic)));
+            }
+            _ => return Err(line.error(column, format!("Unknown mnemonic: {}", mnemonic))),
+        };
+        Ok((op, None))
+    }
+}
+
+/// An operand of an instruction.
+enum Arg<'a> {
+    V(u8),
+    Range(u8, u8),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    Pitch,
+    Long(Operand),
+    Expr(&'a Operand),
+}
+
+impl<'a> Arg<'a> {
+    fn parse(operand: &'a Operand) -> Self {
+        let upper = operand.text.to_ascii_uppercase();
+        if let Some(x) = register(&upper) {
+            return Arg::V(x);
+        }
+        if let Some((x, y)) = upper.split_once('-') {
+            if let (Some(x), Some(y)) = (register(x.trim()), register(y.trim())) {
+                return Arg::Range(x, y);
+            }
+        }
+        if let Some(rest) = upper.strip_prefix("LONG") {
+            if rest.starts_with(char::is_whitespace) {
+                let offset = operand.text.len() - rest.trim_start().len();
+                return Arg::Long(Operand {
+                    text: operand.text[offset..].to_string(),
+                    column: operand.column + offset,
+                });
+            }
+        }
+        match upper.as_str() {
+            "I" => Arg::I,
+            "[I]" => Arg::IndirectI,
+            "DT" => Arg::Dt,
+            "ST" => Arg::St,
+            "K" => Arg::K,
+            "F" => Arg::F,
+            "HF" => Arg::Hf,
+            "B" => Arg::B,
+            "R" => Arg::R,
+            "PITCH" => Arg::Pitch,
+            _ => Arg::Expr(operand),
+        }
+    }
+}
+
+/// Parses `V0` to `VF`.
+fn register(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix('V')?;
+    if digit.len() == 1 {
+        u8::from_str_radix(digit, 16).ok()
+    } else {
+        None
+    }
+}
+
+fn statement_size(statement: &Statement) -> usize {
+    match statement {
+        Statement::Bytes(operands) => operands
+            .iter()
+            .map(|operand| string_literal(&operand.text).map_or(1, str::len))
+            .sum(),
+        Statement::Words(operands) => operands.len() * 2,
+        Statement::Instruction {
+            mnemonic, operands, ..
+        } => {
+            let is_long = mnemonic == "LD"
+                && operands
+                    .get(1)
+                    .is_some_and(|operand| matches!(Arg::parse(operand), Arg::Long(_)));
+            if is_long {
+                4
+            } else {
+                2
+            }
+        }
+    }
+}
+
+fn string_literal(text: &str) -> Option<&str> {
+    text.strip_prefix('"')?.strip_suffix('"')
+}
+
+fn is_symbol(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .is_some_and(|char| char.is_ascii_alphabetic() || char == '_')
+        && chars.all(|char| char.is_ascii_alphanumeric() || char == '_' || char == '.')
+}
+
+/// Whether `text` names a register or a keyword operand, which would shadow
+/// a symbol of the same name.
+fn is_reserved(text: &str) -> bool {
+    let upper = text.to_ascii_uppercase();
+    register(&upper).is_some()
+        || matches!(
+            upper.as_str(),
+            "I" | "DT" | "ST" | "K" | "F" | "HF" | "B" | "R" | "PITCH" | "LONG"
+        )
+}
+
+/// Splits a leading `label:` off a line. Returns the label with its column
+/// and the rest of the line with its column.
+#[allow(clippy::type_complexity)]
+fn split_label(line: &Line) -> Result<(Option<(&str, usize)>, &str, usize), AsmError> {
+    let text = line.text.as_str();
+    let trimmed = text.trim_start();
+    let column = text.len() - trimmed.len() + 1;
+
+    let end = trimmed
+        .find(|char: char| !(char.is_ascii_alphanumeric() || char == '_' || char == '.'))
+        .unwrap_or(trimmed.len());
+    if end > 0 && trimmed[end..].starts_with(':') {
+        let name = &trimmed[..end];
+        if !is_symbol(name) {
+            return Err(line.error(column, format!("Invalid label name: {}", name)));
+        }
+        if is_reserved(name) {
+            return Err(line.error(column, format!("Reserved name: {}", name)));
+        }
+        return Ok((Some((name, column)), &trimmed[end + 1..], column + end + 1));
+    }
+    Ok((None, text, 1))
+}
+
+/// Splits the first word off `text`, which starts at `column`. Returns the
+/// word and the trimmed rest with its column, if there is one.
+fn split_word(text: &str, column: usize) -> (&str, Option<(&str, usize)>) {
+    match text.find(char::is_whitespace) {
+        Some(end) => {
+            let rest = text[end..].trim_start();
+            if rest.is_empty() {
+                (&text[..end], None)
+            } else {
+                (&text[..end], Some((rest, column + text.len() - rest.len())))
+            }
+        }
+        None => (text, None),
+    }
+}
+
+/// Splits the operands at every comma outside of strings and parentheses.
+fn split_operands(line: &Line, text: &str, column: usize) -> Result<Vec<Operand>, AsmError> {
+    let mut operands = Vec::new();
+    let mut start = 0;
+    let mut depth = 0;
+    let mut in_string = false;
+
+    let mut push = |start: usize, end: usize| {
+        let raw = &text[start..end];
+        let trimmed = raw.trim_start();
+        let operand = Operand {
+            text: trimmed.trim_end().to_string(),
+            column: column + start + raw.len() - trimmed.len(),
+        };
+        if operand.text.is_empty() {
+            Err(line.error(operand.column, "Expected an operand"))
+        } else {
+            operands.push(operand);
+            Ok(())
+        }
+    };
+
+    for (index, char) in text.char_indices() {
+        match char {
+            '"' => in_string = !in_string,
+            '(' if !in_string => depth += 1,
+            ')' if !in_string => depth -= 1,
+            ',' if !in_string && depth == 0 => {
+                push(start, index)?;
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    push(start, text.len())?;
+    Ok(operands)
+}
+
+/// Evaluates an expression. `address` is the value of `$`. Errors contain the
+/// column they occurred at.
+fn eval(
+    operand: &Operand,
+    address: usize,
+    symbols: &HashMap<String, i64>,
+) -> Result<i64, (usize, String)> {
+    let mut parser = ExprParser {
+        text: operand.text.as_bytes(),
+        position: 0,
+        column: operand.column,
+        address,
+        symbols,
+    };
+    let value = parser.binary(0)?;
+    parser.skip_whitespace();
+    if parser.position < parser.text.len() {
+        return Err(parser.error("Unexpected character"));
+    }
+    Ok(value)
+}
+
+/// A precedence climbing parser for expressions.
+struct ExprParser<'a> {
+    text: &'a [u8],
+    position: usize,
+    column: usize,
+    address: usize,
+    symbols: &'a HashMap<String, i64>,
+}
+
+impl ExprParser<'_> {
+    fn error(&self, message: impl Into<String>) -> (usize, String) {
+        (self.column + self.position, message.into())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .text
+            .get(self.position)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.position += 1;
+        }
+    }
+
+    /// Consumes the next binary operator if it binds at least as tightly as
+    /// `min_precedence`.
+    fn operator(&mut self, min_precedence: u8) -> Option<(&'static str, u8)> {
+        const OPERATORS: [(&str, u8); 10] = [
+            ("|", 0),
+            ("^", 1),
+            ("&", 2),
+            ("<<", 3),
+            (">>", 3),
+            ("+", 4),
+            ("-", 4),
+            ("*", 5),
+            ("/", 5),
+            ("%", 5),
+        ];
+        self.skip_whitespace();
+        let rest = &self.text[self.position..];
+        let (operator, precedence) = OPERATORS
+            .iter()
+            .find(|(operator, _)| rest.starts_with(operator.as_bytes()))?;
+        if *precedence < min_precedence {
+            return None;
+        }
+        self.position += operator.len();
+        Some((operator, *precedence))
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> Result<i64, (usize, String)> {
+        let mut left = self.unary()?;
+        while let Some((operator, precedence)) = self.operator(min_precedence) {
+            let column = self.position;
+            let right = self.binary(precedence + 1)?;
+            left = match operator {
+                "|" => left | right,
+                "^" => left ^ right,
+                "&" => left & right,
+                "<<" => left.checked_shl(right as u32).unwrap_or(0),
+                ">>" => left.checked_shr(right as u32).unwrap_or(0),
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                "*" => left.wrapping_mul(right),
+                "/" | "%" if right == 0 => {
+                    return Err((self.column + column, "Division by zero".to_string()))
+                }
+                "/" => left.wrapping_div(right),
+                _ => left.wrapping_rem(right),
+            };
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<i64, (usize, String)> {
+        self.skip_whitespace();
+        match self.text.get(self.position) {
+            Some(b'-') => {
+                self.position += 1;
+                Ok(self.unary()?.wrapping_neg())
+            }
+            Some(b'~') => {
+                self.position += 1;
+                Ok(!self.unary()?)
+            }
+            Some(b'(') => {
+                self.position += 1;
+                let value = self.binary(0)?;
+                self.skip_whitespace();
+                if self.text.get(self.position) != Some(&b')') {
+                    return Err(self.error("Expected )"));
+                }
+                self.position += 1;
+                Ok(value)
+            }
+            Some(b'$') => {
+                self.position += 1;
+                Ok(self.address as i64)
+            }
+            Some(char) if char.is_ascii_digit() => self.number(),
+            Some(char) if char.is_ascii_alphabetic() || *char == b'_' => self.symbol(),
+            Some(_) => Err(self.error("Unexpected character")),
+            None => Err(self.error("Expected a value")),
+        }
+    }
+
+    fn word(&mut self) -> &str {
+        let start = self.position;
+        while self
+            .text
+            .get(self.position)
+            .is_some_and(|char| char.is_ascii_alphanumeric() || *char == b'_' || *char == b'.')
+        {
+            self.position += 1;
+        }
+        // Only ASCII characters were consumed
+        std::str::from_utf8(&self.text[start..self.position]).unwrap()
+    }
+
+    fn number(&mut self) -> Result<i64, (usize, String)> {
+        let start = self.position;
+        let word = self.word().to_ascii_lowercase();
+        let (digits, radix) = if let Some(digits) = word.strip_prefix("0x") {
+            (digits, 16)
+        } else if let Some(digits) = word.strip_prefix("0b") {
+            (digits, 2)
+        } else {
+            (word.as_str(), 10)
+        };
+        i64::from_str_radix(digits, radix)
+            .map_err(|_| (self.column + start, format!("Invalid number: {}", word)))
+    }
+
+    fn symbol(&mut self) -> Result<i64, (usize, String)> {
+        let start = self.position;
+        let name = self.word().to_string();
+        self.symbols
+            .get(&name)
+            .copied()
+            .ok_or_else(|| (self.column + start, format!("Undefined symbol: {}", name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        disasm::{disassemble, format_op, Syntax},
+        platform::Platform,
+    };
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_instructions() {
+        let rom = assemble("CLS\nld v1, 0x20\nDRW V1, V2, 5\nLD I, LONG 0x1234").unwrap();
+        assert_eq!(
+            rom,
+            [0x00, 0xE0, 0x61, 0x20, 0xD1, 0x25, 0xF0, 0x00, 0x12, 0x34]
+        );
+    }
+
+    #[test]
+    fn test_labels_and_constants() {
+        let source = "
+            SPRITE_HEIGHT EQU HEIGHT_TIMES_TWO / 2
+            HEIGHT_TIMES_TWO EQU 10
+            start:  LD I, sprite
+                    DRW V0, V0, SPRITE_HEIGHT
+            loop:   JP loop ; spin forever
+            sprite: DB 0xF0, 0x90, 0b11110000, \"A;\"
+                    DW sprite + 1, -1
+        ";
+        let rom = assemble(source).unwrap();
+        assert_eq!(
+            rom,
+            [
+                0xA2, 0x06, 0xD0, 0x05, 0x12, 0x04, 0xF0, 0x90, 0xF0, b'A', b';', 0x02, 0x07, 0xFF,
+                0xFF
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expressions() {
+        let rom = assemble("DB 1 + 2 * 3, (1 + 2) * 3, 1 << 4 | 1, ~0 & 0xF, -1\nDW $").unwrap();
+        assert_eq!(rom, [7, 9, 17, 15, 0xFF, 0x02, 0x05]);
+
+        // Overflows wrap around instead of panicking
+        let rom = assemble("DB (-9223372036854775807-1)%-1").unwrap();
+        assert_eq!(rom, [0]);
+        let error = assemble("DW (-9223372036854775807-1)/-1").unwrap_err();
+        assert_eq!((error.line, error.column), (1, 4));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = assemble("CLS\n  LD V0, missing").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 10));
+        assert_eq!(error.message, "Undefined symbol: missing");
+
+        let error = assemble("JP 0x1000").unwrap_err();
+        assert_eq!((error.line, error.column), (1, 4));
+
+        let error = assemble("label:\nlabel: CLS").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 1));
+
+        let error = assemble("  FOO V0").unwrap_err();
+        assert_eq!(error.message, "Unknown mnemonic: FOO");
+
+        let error = assemble("X EQU Y\nY EQU X").unwrap_err();
+        assert_eq!(error.message, "Undefined symbol: Y");
+
+        let error = assemble("v0 EQU 5").unwrap_err();
+        assert_eq!(error.message, "Reserved name: v0");
+        let error = assemble("CLS\nDT: CLS").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 1));
+        assert_eq!(error.message, "Reserved name: DT");
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("chip8-asm-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("sprite.asm"), "sprite: DB 0xFF\n").unwrap();
+        fs::write(
+            dir.join("main.asm"),
+            "LD I, sprite\nINCLUDE \"sprite.asm\"\n",
+        )
+        .unwrap();
+        let rom = assemble_file(dir.join("main.asm"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(rom, Ok(vec![0xA2, 0x02, 0xFF]));
+    }
+
+    #[test]
+    fn test_round_trip_ops() {
+        let labels = BTreeMap::new();
+        for opcode in 0..=u16::MAX {
+            let [first, second] = opcode.to_be_bytes();
+            let op = match Op::decode(first, second) {
+                Ok(Op::LdILong) | Err(_) => continue,
+                Ok(op) => op,
+            };
+            let text = format_op(&op, 0, Syntax::Cowgod, &labels);
+            assert_eq!(assemble(&text), Ok(vec![first, second]), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_disassembly() {
+        let rom = [
+            0x00, 0xE0, 0x22, 0x0A, 0x12, 0x04, 0xAB, 0xCD, 0xEF, 0x00, 0x30, 0x00, 0xF0, 0x00,
+            0x12, 0x34, 0x00, 0xEE,
+        ];
+        let source = disassemble(&rom, Platform::XoChip).format(Syntax::Cowgod);
+        assert_eq!(assemble(&source), Ok(rom.to_vec()));
+    }
+}
@@ -0,0 +1,445 @@
+use alloc::boxed::Box;
+
+use crate::savestate::{Persist, Reader, StateError, Writer};
+
+#[cfg(feature = "sdl")]
+pub use sdl::SDLAudio;
+#[cfg(feature = "std")]
+pub use synth::{PatternPlayer, Synth, Tone, WavAudio, Waveform};
+
+pub const PATTERN_SIZE: usize = 16;
+
+/// The XO-CHIP audio pattern buffer and pitch register.
+///
+/// The buffer holds a 1-bit waveform of 128 samples which is played in a
+/// loop at [`AudioPattern::playback_rate()`] while the sound timer is active.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioPattern {
+    pub buffer: [u8; PATTERN_SIZE],
+    pub pitch: u8,
+}
+
+impl AudioPattern {
+    /// The amount of samples of the buffer played per second.
+    #[cfg(feature = "std")]
+    pub fn playback_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((f32::from(self.pitch) - 64.0) / 48.0)
+    }
+
+    /// Gets the sample at `index`. The index wraps around at the end of the
+    /// buffer.
+    pub fn sample(&self, index: usize) -> bool {
+        let bit = index % (PATTERN_SIZE * 8);
+        self.buffer[bit / 8] & (0x80 >> (bit % 8)) != 0
+    }
+}
+
+impl Default for AudioPattern {
+    /// A 500 Hz square wave, which is what programs that never load a
+    /// pattern expect to hear.
+    fn default() -> Self {
+        Self {
+            buffer: [0xF0; PATTERN_SIZE],
+            pitch: 64,
+        }
+    }
+}
+
+impl Persist for AudioPattern {
+    fn save(&self, out: &mut Writer) {
+        out.bytes(&self.buffer);
+        out.u8(self.pitch);
+    }
+
+    fn restore(input: &mut Reader) -> Result<Self, StateError> {
+        Ok(Self {
+            buffer: input.array()?,
+            pitch: input.u8()?,
+        })
+    }
+}
+
+/// An error raised by an [`Audio`].
+pub type AudioError = Box<dyn core::error::Error + Send + Sync>;
+
+/// A trait to play the sound of the emulator, the counterpart of
+/// [`Render`](crate::display::Render).
+pub trait Audio {
+    /// Called once per frame, i.e. on every timer tick, with the pattern to
+    /// play during the next frame. It is `None` while the sound timer is 0.
+    /// Implementations should fall silent if no frame follows, e.g. while the
+    /// emulator is paused.
+    fn frame(&mut self, sound: Option<&AudioPattern>) -> Result<(), AudioError>;
+}
+
+/// An [`Audio`] which plays nothing.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NullAudio;
+
+impl Audio for NullAudio {
+    fn frame(&mut self, _: Option<&AudioPattern>) -> Result<(), AudioError> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+mod synth {
+    use std::{
+        f32::consts::TAU,
+        fmt,
+        io::{self, Seek, SeekFrom, Write},
+        str::FromStr,
+    };
+
+    use super::{Audio, AudioError, AudioPattern, PATTERN_SIZE};
+    use crate::emulator::FRAME_RATE;
+
+    /// Resamples an [`AudioPattern`] to the sample rate of an output device.
+    #[derive(Default)]
+    pub struct PatternPlayer {
+        // Position in the pattern, measured in pattern samples
+        position: f32,
+    }
+
+    impl PatternPlayer {
+        /// Fills `out` with the next samples of `pattern`, played back at
+        /// `sample_rate` with an amplitude of `volume`.
+        pub fn fill(
+            &mut self,
+            pattern: &AudioPattern,
+            sample_rate: u32,
+            volume: f32,
+            out: &mut [f32],
+        ) {
+            let step = pattern.playback_rate() / sample_rate as f32;
+            for sample in out.iter_mut() {
+                *sample = if pattern.sample(self.position as usize) {
+                    volume
+                } else {
+                    -volume
+                };
+                self.position = (self.position + step) % (PATTERN_SIZE * 8) as f32;
+            }
+        }
+    }
+
+    /// The shape of a [`Tone`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum Waveform {
+        #[default]
+        Square,
+        Triangle,
+        Sawtooth,
+        Sine,
+    }
+
+    impl Waveform {
+        /// The sample at `phase`, which ranges from 0 to 1 over a period.
+        fn sample(self, phase: f32) -> f32 {
+            match self {
+                Self::Square if phase < 0.5 => 1.0,
+                Self::Square => -1.0,
+                Self::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+                Self::Sawtooth => 2.0 * phase - 1.0,
+                Self::Sine => (phase * TAU).sin(),
+            }
+        }
+    }
+
+    impl fmt::Display for Waveform {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(match self {
+                Self::Square => "square",
+                Self::Triangle => "triangle",
+                Self::Sawtooth => "sawtooth",
+                Self::Sine => "sine",
+            })
+        }
+    }
+
+    impl FromStr for Waveform {
+        type Err = String;
+
+        /// Parses the names produced by the [`Display`](fmt::Display)
+        /// implementation.
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "square" => Ok(Self::Square),
+                "triangle" => Ok(Self::Triangle),
+                "sawtooth" => Ok(Self::Sawtooth),
+                "sine" => Ok(Self::Sine),
+                _ => Err(format!("Unknown waveform: {}", s)),
+            }
+        }
+    }
+
+    /// The tone played while the sound timer is active.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct Tone {
+        /// In Hz.
+        pub frequency: f32,
+        /// The amplitude from 0 to 1.
+        pub volume: f32,
+        pub waveform: Waveform,
+    }
+
+    impl Default for Tone {
+        /// The same square wave as the default [`AudioPattern`].
+        fn default() -> Self {
+            Self {
+                frequency: 500.0,
+                volume: 0.25,
+                waveform: Waveform::Square,
+            }
+        }
+    }
+
+    /// Produces the samples for an [`Audio`]. ROMs that loaded an XO-CHIP
+    /// pattern hear that pattern, all others the [`Tone`].
+    pub struct Synth {
+        pub tone: Tone,
+        player: PatternPlayer,
+        // Position within the period of the tone, from 0 to 1
+        phase: f32,
+    }
+
+    impl Synth {
+        pub fn new(tone: Tone) -> Self {
+            Self {
+                tone,
+                player: PatternPlayer::default(),
+                phase: 0.0,
+            }
+        }
+
+        /// Fills `out` with the next samples of `sound` at `sample_rate`.
+        pub fn fill(&mut self, sound: Option<&AudioPattern>, sample_rate: u32, out: &mut [f32]) {
+            match sound {
+                None => out.fill(0.0),
+                Some(pattern) if *pattern != AudioPattern::default() => {
+                    self.player
+                        .fill(pattern, sample_rate, self.tone.volume, out)
+                }
+                Some(_) => {
+                    let step = self.tone.frequency / sample_rate as f32;
+                    for sample in out.iter_mut() {
+                        *sample = self.tone.waveform.sample(self.phase) * self.tone.volume;
+                        self.phase = (self.phase + step).fract();
+                    }
+                }
+            }
+        }
+    }
+
+    /// An [`Audio`] writing 16-bit mono PCM to a WAV file, e.g. to check the
+    /// sound of a headless run. The header is kept up to date after every
+    /// frame, so the file is valid even if the emulator is not dropped.
+    pub struct WavAudio<W: Write + Seek> {
+        out: W,
+        synth: Synth,
+        sample_rate: u32,
+        frames: u64,
+        samples: u64,
+    }
+
+    impl<W: Write + Seek> WavAudio<W> {
+        pub fn new(mut out: W, sample_rate: u32, tone: Tone) -> io::Result<Self> {
+            out.write_all(b"RIFF")?;
+            out.write_all(&36u32.to_le_bytes())?;
+            out.write_all(b"WAVEfmt ")?;
+            out.write_all(&16u32.to_le_bytes())?;
+            // PCM, 1 channel
+            out.write_all(&1u16.to_le_bytes())?;
+            out.write_all(&1u16.to_le_bytes())?;
+            out.write_all(&sample_rate.to_le_bytes())?;
+            // Bytes per second, bytes per sample and bits per sample
+            out.write_all(&(sample_rate * 2).to_le_bytes())?;
+            out.write_all(&2u16.to_le_bytes())?;
+            out.write_all(&16u16.to_le_bytes())?;
+            out.write_all(b"data")?;
+            out.write_all(&0u32.to_le_bytes())?;
+            Ok(Self {
+                out,
+                synth: Synth::new(tone),
+                sample_rate,
+                frames: 0,
+                samples: 0,
+            })
+        }
+
+        /// Returns the writer, e.g. to read back the WAV data.
+        pub fn into_inner(self) -> W {
+            self.out
+        }
+    }
+
+    impl<W: Write + Seek> Audio for WavAudio<W> {
+        fn frame(&mut self, sound: Option<&AudioPattern>) -> Result<(), AudioError> {
+            // Counting from the start avoids rounding errors adding up
+            self.frames += 1;
+            let end = self.frames * u64::from(self.sample_rate) / u64::from(FRAME_RATE);
+            let mut samples = vec![0.0; (end - self.samples) as usize];
+            self.synth.fill(sound, self.sample_rate, &mut samples);
+            for sample in samples {
+                let sample = (sample * f32::from(i16::MAX)) as i16;
+                self.out.write_all(&sample.to_le_bytes())?;
+            }
+            self.samples = end;
+
+            let size = u32::try_from(self.samples * 2)?;
+            self.out.seek(SeekFrom::Start(4))?;
+            self.out.write_all(&(36 + size).to_le_bytes())?;
+            self.out.seek(SeekFrom::Start(40))?;
+            self.out.write_all(&size.to_le_bytes())?;
+            self.out.seek(SeekFrom::End(0))?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "sdl")]
+mod sdl {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+
+    use super::{Audio, AudioError, AudioPattern, Synth, Tone};
+
+    /// How long the sound of a frame keeps playing without the next frame.
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    // The sound of the last frame and when it started
+    type Shared = Arc<Mutex<(Option<AudioPattern>, Instant)>>;
+
+    struct Callback {
+        shared: Shared,
+        synth: Synth,
+        sample_rate: u32,
+    }
+
+    impl AudioCallback for Callback {
+        type Channel = f32;
+
+        fn callback(&mut self, out: &mut [f32]) {
+            let (sound, started) = *self.shared.lock().unwrap();
+            let sound = sound.filter(|_| started.elapsed() < TIMEOUT);
+            self.synth.fill(sound.as_ref(), self.sample_rate, out);
+        }
+    }
+
+    /// The built-in [`Audio`] using SDL. It falls silent shortly after the
+    /// emulator stops producing frames.
+    pub struct SDLAudio {
+        shared: Shared,
+        _device: AudioDevice<Callback>,
+    }
+
+    impl SDLAudio {
+        /// Opens the default playback device of a [`sdl2::Sdl`] context.
+        pub fn new(ctx: &sdl2::Sdl, tone: Tone) -> Result<Self, String> {
+            let shared = Arc::new(Mutex::new((None, Instant::now())));
+            let device = ctx.audio()?.open_playback(
+                None,
+                &AudioSpecDesired {
+                    freq: Some(44100),
+                    channels: Some(1),
+                    // About 12 ms, so short beeps are not missed
+                    samples: Some(512),
+                },
+                |spec| Callback {
+                    shared: Arc::clone(&shared),
+                    synth: Synth::new(tone),
+                    sample_rate: spec.freq as u32,
+                },
+            )?;
+            device.resume();
+            Ok(Self {
+                shared,
+                _device: device,
+            })
+        }
+    }
+
+    impl Audio for SDLAudio {
+        fn frame(&mut self, sound: Option<&AudioPattern>) -> Result<(), AudioError> {
+            *self.shared.lock().unwrap() = (sound.copied(), Instant::now());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_playback_rate() {
+        let mut pattern = AudioPattern::default();
+        assert_eq!(pattern.playback_rate(), 4000.0);
+        pattern.pitch = 112;
+        assert_eq!(pattern.playback_rate(), 8000.0);
+    }
+
+    #[test]
+    fn test_sample_wraps() {
+        let mut pattern = AudioPattern::default();
+        pattern.buffer[0] = 0x80;
+        assert!(pattern.sample(0));
+        assert!(!pattern.sample(1));
+        assert!(pattern.sample(PATTERN_SIZE * 8));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_player_follows_pattern() {
+        let pattern = AudioPattern::default();
+        let mut player = PatternPlayer::default();
+        let mut out = [0.0; 8];
+        player.fill(&pattern, 4000, 0.5, &mut out);
+        assert_eq!(out, [0.5, 0.5, 0.5, 0.5, -0.5, -0.5, -0.5, -0.5]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_synth() {
+        let mut synth = Synth::new(Tone {
+            frequency: 1000.0,
+            volume: 0.5,
+            waveform: Waveform::Sawtooth,
+        });
+        let mut out = [1.0; 4];
+        synth.fill(None, 4000, &mut out);
+        assert_eq!(out, [0.0; 4]);
+        synth.fill(Some(&AudioPattern::default()), 4000, &mut out);
+        assert_eq!(out, [-0.5, -0.25, 0.0, 0.25]);
+
+        // Loaded patterns replace the tone
+        let pattern = AudioPattern {
+            buffer: [0x00; PATTERN_SIZE],
+            pitch: 64,
+        };
+        synth.fill(Some(&pattern), 4000, &mut out);
+        assert_eq!(out, [-0.5; 4]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_wav() {
+        let mut wav =
+            WavAudio::new(std::io::Cursor::new(Vec::new()), 6000, Tone::default()).unwrap();
+        wav.frame(Some(&AudioPattern::default())).unwrap();
+        wav.frame(None).unwrap();
+        let data = wav.into_inner().into_inner();
+
+        // 100 samples per frame
+        assert_eq!(data.len(), 44 + 2 * 200);
+        assert_eq!(&data[4..8], &(36u32 + 400).to_le_bytes());
+        assert_eq!(&data[40..44], &400u32.to_le_bytes());
+        assert_ne!(&data[44..46], &[0, 0]);
+        assert_eq!(&data[244..246], &[0, 0]);
+    }
+}
@@ -0,0 +1,27 @@
+use std::{fs, path::PathBuf};
+
+use chip_8::asm::assemble_file;
+use clap::Parser;
+
+#[derive(Parser)]
+#[command(author, version, about = "A CHIP-8 assembler")]
+struct Cli {
+    /// The source file to assemble
+    source_file: PathBuf,
+
+    /// Where to write the ROM [default: the source file with a .ch8 extension]
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let rom = assemble_file(&cli.source_file)?;
+    let output = cli
+        .output
+        .unwrap_or_else(|| cli.source_file.with_extension("ch8"));
+    fs::write(output, rom)?;
+
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use std::fs;
+
+use chip_8::{
+    disasm::{disassemble, Syntax},
+    platform::Platform,
+};
+use clap::Parser;
+
+#[derive(Parser)]
+#[command(author, version, about = "A CHIP-8 disassembler")]
+struct Cli {
+    /// The ROM file to disassemble
+    rom_file: String,
+
+    /// Which instruction set the ROM uses [chip8, schip, xochip]
+    #[arg(short, long, default_value_t = Platform::Chip8)]
+    platform: Platform,
+
+    /// The syntax of the output [cowgod, octo]
+    #[arg(short, long, default_value_t = Syntax::Cowgod)]
+    syntax: Syntax,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let rom = fs::read(cli.rom_file)?;
+    print!("{}", disassemble(&rom, cli.platform).format(cli.syntax));
+
+    Ok(())
+}
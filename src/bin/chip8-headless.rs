@@ -0,0 +1,227 @@
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+};
+
+use anyhow::Context;
+use chip_8::{
+    audio::{Tone, WavAudio},
+    cartridge::{self, Cartridge},
+    cpu::{Cpu, KeyState},
+    display::{FrameBuffer, NullRenderer, DEFAULT_PALETTE},
+    emulator::{Emulator, EmulatorState, BIG_FONT, FONT},
+    movie::{Movie, Replay},
+    platform::Platform,
+    quirks::Quirks,
+};
+use clap::Parser;
+use serde_json::json;
+
+#[derive(Parser)]
+#[command(
+    author,
+    version,
+    about = "Runs a CHIP-8 ROM without a display and dumps the final state"
+)]
+struct Cli {
+    /// The ROM file or Octo cartridge to run
+    rom_file: String,
+
+    /// How many cpu cycles per second [default: 500]
+    #[arg(short, long)]
+    cycles: Option<u32>,
+
+    /// Which instruction set the ROM uses [chip8, schip, xochip] [default:
+    /// chip8]
+    #[arg(short, long)]
+    platform: Option<Platform>,
+
+    /// Which interpreter's quirks to emulate [vip, chip48, schip, xochip]
+    /// [default: depends on the platform]
+    #[arg(short, long)]
+    quirks: Option<Quirks>,
+
+    /// Seed for the random numbers of CXNN
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Stop after this many instructions
+    #[arg(short = 'n', long, conflicts_with = "frames")]
+    steps: Option<u64>,
+
+    /// Stop after this many frames [default: 600]
+    #[arg(short, long)]
+    frames: Option<u64>,
+
+    /// Hold keys from a frame on, e.g. `30:5A` holds 5 and A from frame 30
+    /// and `60:` releases all keys
+    #[arg(short, long, value_name = "FRAME:KEYS", value_parser = parse_input)]
+    input: Vec<(u64, KeyState)>,
+
+    /// Replay the input of a movie file, using the settings it was recorded with
+    #[arg(long, value_name = "FILE", conflicts_with = "input")]
+    replay: Option<String>,
+
+    /// Write the frame buffer as text, `-` for stdout [default: - unless
+    /// --png or --json is given]
+    #[arg(long, value_name = "FILE")]
+    text: Option<String>,
+
+    /// Write the frame buffer as PNG
+    #[arg(long, value_name = "FILE")]
+    png: Option<String>,
+
+    /// The size of a pixel in the PNG
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    scale: u32,
+
+    /// Write the registers, the stack and the timers as JSON, `-` for stdout
+    #[arg(long, value_name = "FILE")]
+    json: Option<String>,
+
+    /// Write the sound as WAV
+    #[arg(long, value_name = "FILE")]
+    wav: Option<String>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let (rom, cartridge) = cartridge::load(fs::read(&cli.rom_file)?).map_err(anyhow::Error::msg)?;
+    // The options of a cartridge apply unless they are given as arguments
+    let detected = cartridge
+        .as_ref()
+        .map(Cartridge::settings)
+        .unwrap_or_default();
+    let movie = match &cli.replay {
+        Some(path) => {
+            let movie = Movie::from_bytes(&fs::read(path)?)?;
+            movie.check_rom(&rom)?;
+            movie
+        }
+        None => {
+            let platform = cli.platform.or(detected.platform).unwrap_or_default();
+            let quirks = cli
+                .quirks
+                .unwrap_or_else(|| detected.quirks.resolve(platform));
+            let cycles = cli.cycles.or(detected.cycles).unwrap_or(500);
+            let mut movie = Movie::new(&rom, platform, quirks, cycles, cli.seed);
+            for (frame, keys) in &cli.input {
+                movie.set_keys(*frame, *keys);
+            }
+            movie
+        }
+    };
+
+    let mut emulator = Emulator::new(NullRenderer, movie.cycles, movie.platform, movie.quirks);
+    emulator.cpu.fault_policy = movie.fault_policy;
+    emulator.cpu.key_edge = movie.key_edge;
+    emulator.seed(movie.seed);
+    emulator.load_font(&FONT)?;
+    emulator.load_big_font(&BIG_FONT)?;
+    emulator.load_rom(&rom)?;
+    if let Some(path) = &cli.wav {
+        let out = BufWriter::new(File::create(path)?);
+        emulator.set_audio(Box::new(WavAudio::new(out, 44100, Tone::default())?));
+    }
+
+    let mut replay = Replay::new(movie);
+    let mut steps = 0;
+    loop {
+        let done = match cli.steps {
+            Some(limit) => steps >= limit,
+            None => emulator.frame_count() >= cli.frames.unwrap_or(600),
+        };
+        if done || emulator.cpu.halted {
+            break;
+        }
+        replay.apply(&mut emulator);
+        emulator
+            .step()
+            .with_context(|| format!("at {:#05X}", emulator.cpu.pc))?;
+        steps += 1;
+    }
+
+    if let Some(path) = &cli.png {
+        write_png(path, &emulator.state.frame_buffer, cli.scale)?;
+    }
+    if let Some(path) = &cli.json {
+        output(path, &registers(&emulator.cpu, &emulator.state)?)?;
+    }
+    match &cli.text {
+        Some(path) => output(path, &emulator.state.frame_buffer.to_string())?,
+        None if cli.png.is_none() && cli.json.is_none() => {
+            print!("{}", emulator.state.frame_buffer)
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// Parses `FRAME:KEYS` where `KEYS` are the hexadecimal digits of the held
+/// keys.
+fn parse_input(s: &str) -> Result<(u64, KeyState), String> {
+    let (frame, digits) = s
+        .split_once(':')
+        .ok_or_else(|| format!("Expected FRAME:KEYS, got {}", s))?;
+    let frame = frame
+        .parse()
+        .map_err(|_| format!("Invalid frame: {}", frame))?;
+    let mut keys = [false; 16];
+    for digit in digits.chars() {
+        let key = digit
+            .to_digit(16)
+            .ok_or_else(|| format!("Invalid key: {}", digit))?;
+        keys[key as usize] = true;
+    }
+    Ok((frame, keys))
+}
+
+/// Writes `text` to the file at `path` or to stdout if `path` is `-`.
+fn output(path: &str, text: &str) -> anyhow::Result<()> {
+    if path == "-" {
+        print!("{}", text);
+    } else {
+        fs::write(path, text)?;
+    }
+    Ok(())
+}
+
+fn write_png(path: &str, frame_buffer: &FrameBuffer, scale: u32) -> anyhow::Result<()> {
+    let (width, height) = (frame_buffer.width() as u32, frame_buffer.height() as u32);
+    let rgb = frame_buffer.to_rgb(&DEFAULT_PALETTE);
+    let mut scaled = Vec::with_capacity(rgb.len() * (scale * scale) as usize);
+    for row in rgb.chunks(width as usize * 3) {
+        let row: Vec<u8> = row
+            .chunks(3)
+            .flat_map(|pixel| pixel.repeat(scale as usize))
+            .collect();
+        for _ in 0..scale {
+            scaled.extend_from_slice(&row);
+        }
+    }
+
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width * scale, height * scale);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&scaled)?;
+    Ok(())
+}
+
+/// Formats the cpu registers, the stack and the timers as JSON object.
+fn registers(cpu: &Cpu, state: &EmulatorState) -> anyhow::Result<String> {
+    let registers: Vec<u8> = (0..16).map(|x| cpu.get_register(x).unwrap()).collect();
+    let stack: Vec<u16> = cpu.stack.iter().copied().collect();
+    let json = json!({
+        "pc": cpu.pc,
+        "i": cpu.i,
+        "v": registers,
+        "stack": stack,
+        "delay_timer": state.delay_timer.get(),
+        "sound_timer": state.sound_timer.get(),
+        "halted": cpu.halted,
+        "waiting_for_key": cpu.waiting_for_key(),
+    });
+    Ok(serde_json::to_string_pretty(&json)? + "\n")
+}
@@ -1,104 +1,860 @@
-use std::{fs::File, io::Read, time::Duration};
-
-use chip_8::{cpu::KeyState, display::SDLRenderer, emulator::Emulator, ram::RAM_SIZE};
-use clap::{command, Parser};
-use sdl2::{event::Event, keyboard::Keycode};
-
-const FONT: [u8; 80] = [
-    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-    0x20, 0x60, 0x20, 0x20, 0x70, // 1
-    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
-];
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, Read},
+    path::PathBuf,
+    sync::mpsc,
+    thread,
+};
+
+use anyhow::bail;
+use chip_8::{
+    audio::Waveform,
+    cartridge,
+    clock::FrameClock,
+    config::{Config, QuirkSettings, Settings},
+    cpu::{FaultPolicy, KeyEdge, KeyState},
+    database::{Database, RomInfo},
+    debugger::{Command, Debugger, Stop},
+    display::Render,
+    emulator::{Emulator, EmulatorError, BIG_FONT, FONT},
+    keymap::{Key, Keymap, Layout},
+    movie::{rom_hash_hex, Movie, Recorder, Replay},
+    platform::Platform,
+    quirks::Quirks,
+    ram::RAM_SIZE,
+    terminal::TerminalRenderer,
+};
+use clap::{Parser, ValueEnum};
 
 #[derive(Parser)]
 #[command(author, version, about = "A CHIP-8 emulator")]
 struct Cli {
-    /// The ROM file to run
+    /// The ROM file or Octo cartridge to run
     rom_file: String,
 
-    /// How many cpu cycles per second
-    #[arg(short, long, default_value_t = 500)]
-    cycles: u32,
+    /// How many instructions to execute per second, rounded to a whole number
+    /// per frame [default: 500]
+    #[arg(short, long)]
+    cycles: Option<u32>,
+
+    /// Which instruction set the ROM uses [chip8, schip, xochip] [default:
+    /// chip8]
+    #[arg(short, long)]
+    platform: Option<Platform>,
+
+    /// Which interpreter's quirks to emulate [default: depends on the platform]
+    #[arg(short, long, value_enum)]
+    quirks: Option<QuirksProfile>,
+
+    /// What to do when the ROM executes an invalid instruction
+    #[arg(long, value_enum, default_value_t = FaultArg::Halt)]
+    on_fault: FaultArg,
+
+    /// When FX0A gets the key it waits for
+    #[arg(long, value_enum, default_value_t = KeyEdgeArg::Release)]
+    key_edge: KeyEdgeArg,
+
+    /// Seed for the random numbers of CXNN, making runs reproducible
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Record the input into a movie file
+    #[arg(long, value_name = "FILE")]
+    record: Option<String>,
+
+    /// Replay the input of a movie file, using the settings it was recorded with
+    #[arg(long, value_name = "FILE", conflicts_with = "record")]
+    replay: Option<String>,
+
+    /// How many MiB of memory to use for rewinding, 0 disables it
+    #[arg(long, default_value_t = 32)]
+    rewind: usize,
+
+    /// Start paused and read debugger commands from stdin
+    #[arg(short, long)]
+    debug: bool,
+
+    /// Where to show the emulator
+    #[arg(long, value_enum, default_value_t = FrontendArg::default())]
+    frontend: FrontendArg,
+
+    /// How many pixels of the window make up a pixel in low resolution mode
+    /// [default: 20]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    scale: Option<u32>,
+
+    /// The frequency of the tone in Hz, unless the ROM sets an audio pattern
+    /// [default: 500]
+    #[arg(long)]
+    frequency: Option<f32>,
+
+    /// The volume of the sound from 0 to 1 [default: 0.25]
+    #[arg(long)]
+    volume: Option<f32>,
+
+    /// The waveform of the tone [square, triangle, sawtooth, sine] [default:
+    /// square]
+    #[arg(long)]
+    waveform: Option<Waveform>,
+
+    /// Which preset maps the keyboard to the CHIP-8 keys [qwerty, qwertz,
+    /// azerty, numpad] [default: qwerty]
+    #[arg(long)]
+    layout: Option<Layout>,
+
+    /// Change the key map as described in this file, followed by
+    /// `<rom>.keymap` if it exists
+    #[arg(long, value_name = "FILE")]
+    keymap: Option<String>,
+
+    /// Read the settings from this file instead of the config file in the
+    /// config directory of the user
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Look the ROM up in the CHIP-8 database in this directory instead of
+    /// the one in the data directory of the user
+    #[arg(long, value_name = "DIR")]
+    database: Option<PathBuf>,
+
+    /// Print what the ROM database knows about the ROM and exit
+    #[arg(long)]
+    info: bool,
+}
+
+impl Cli {
+    /// The settings given as arguments, which override the config file.
+    fn settings(&self) -> Settings {
+        Settings {
+            platform: self.platform,
+            quirks: QuirkSettings {
+                preset: self.quirks.map(Quirks::from),
+                ..QuirkSettings::default()
+            },
+            cycles: self.cycles,
+            scale: self.scale,
+            layout: self.layout,
+            frequency: self.frequency,
+            volume: self.volume,
+            waveform: self.waveform,
+            ..Settings::default()
+        }
+    }
+}
+
+/// How many instructions are executed per second unless configured otherwise.
+const DEFAULT_CYCLES: u32 = 500;
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum FrontendArg {
+    /// A window using SDL
+    #[cfg(feature = "sdl")]
+    #[default]
+    Sdl,
+    /// The terminal, two pixels per character. Stdin is used for the keys
+    #[cfg_attr(not(feature = "sdl"), default)]
+    Terminal,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FaultArg {
+    /// Stop the emulation but keep the window open
+    Halt,
+    /// Report the fault and continue with the next instruction
+    Skip,
+    /// Silently continue with the next instruction
+    Noop,
+}
+
+impl From<FaultArg> for FaultPolicy {
+    fn from(policy: FaultArg) -> Self {
+        match policy {
+            FaultArg::Halt => FaultPolicy::Halt,
+            FaultArg::Skip => FaultPolicy::Skip,
+            FaultArg::Noop => FaultPolicy::NoOp,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum KeyEdgeArg {
+    /// Once the key was pressed and released, like on the COSMAC VIP
+    Release,
+    /// As soon as the key is pressed
+    Press,
+}
+
+impl From<KeyEdgeArg> for KeyEdge {
+    fn from(edge: KeyEdgeArg) -> Self {
+        match edge {
+            KeyEdgeArg::Release => KeyEdge::Release,
+            KeyEdgeArg::Press => KeyEdge::Press,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum QuirksProfile {
+    /// COSMAC VIP
+    Vip,
+    /// CHIP-48
+    Chip48,
+    /// SUPER-CHIP 1.1
+    Schip,
+    /// XO-CHIP
+    Xochip,
+}
+
+impl From<QuirksProfile> for Quirks {
+    fn from(profile: QuirksProfile) -> Self {
+        match profile {
+            QuirksProfile::Vip => Quirks::COSMAC_VIP,
+            QuirksProfile::Chip48 => Quirks::CHIP_48,
+            QuirksProfile::Schip => Quirks::SUPER_CHIP,
+            QuirksProfile::Xochip => Quirks::XO_CHIP,
+        }
+    }
+}
+
+/// What the user asked for through a [`Frontend`], besides pressing CHIP-8
+/// keys.
+enum Action {
+    Quit,
+    Debug(Command),
+    SelectSlot(u8),
+    SaveState,
+    LoadState,
+    /// Starts or stops rewinding.
+    Rewind(bool),
+    /// Starts or stops running at [`TURBO_SPEED`].
+    Turbo(bool),
+}
+
+/// How many frames are run per frame in turbo mode.
+const TURBO_SPEED: u32 = 8;
+
+/// Everything a front-end does besides drawing the screen, which is done by
+/// the [`Render`] of the emulator.
+trait Frontend {
+    /// The [`Render`] of the emulator.
+    type Display: Render;
+
+    /// Handles the input since the last call. CHIP-8 keys update `keys`,
+    /// everything else is returned. `paused` is whether the debugger is
+    /// paused.
+    fn poll(&mut self, paused: bool, keys: &mut KeyState) -> anyhow::Result<Vec<Action>>;
+
+    /// Shows a status message or debugger output.
+    fn message(&mut self, text: &str);
+
+    fn error(&mut self, text: &str) {
+        self.message(text);
+    }
+
+    /// Called when the ROM starts or stops waiting for a key with `FX0A`.
+    fn waiting_for_key(&mut self, display: &mut Self::Display, waiting: bool) {
+        let _ = (display, waiting);
+    }
 }
 
 fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
     // Arbitrary value really. I don't know how big ROMs for CHIP-8 usually are.
-    let mut rom = Vec::with_capacity(RAM_SIZE / 2);
-    File::open(cli.rom_file)?.read_to_end(&mut rom)?;
+    let mut data = Vec::with_capacity(RAM_SIZE / 2);
+    File::open(&cli.rom_file)?.read_to_end(&mut data)?;
+    // Octo cartridges are compiled and come with the settings of the program
+    let (rom, cartridge) = cartridge::load(data).map_err(anyhow::Error::msg)?;
+
+    let config = match cli.config.clone().or_else(Config::path) {
+        Some(path) => Config::load(&path).map_err(anyhow::Error::msg)?,
+        None => Config::default(),
+    };
+    let database = match cli.database.clone().or_else(Database::dir) {
+        Some(dir) => Database::load(&dir).map_err(anyhow::Error::msg)?,
+        None => Database::default(),
+    };
+    let info = database.lookup(&rom).map_err(anyhow::Error::msg)?;
+    if cli.info {
+        println!("SHA-1:     {}", rom_hash_hex(&rom));
+        match &info {
+            Some(info) => print!("{}", info),
+            None => println!("The ROM is not in the database"),
+        }
+        return Ok(());
+    }
+    // The config for the ROM with what the database and the cartridge know
+    // about it, overridden by the arguments
+    let mut detected = info.as_ref().map(RomInfo::settings);
+    if let Some(cartridge) = &cartridge {
+        detected
+            .get_or_insert_with(Settings::default)
+            .merge(&cartridge.settings());
+    }
+    let mut profile = config.settings(&rom, detected.as_ref());
+    profile.merge(&cli.settings());
+
+    let replay = match &cli.replay {
+        Some(path) => {
+            let movie = Movie::from_bytes(&fs::read(path)?)?;
+            movie.check_rom(&rom)?;
+            Some(Replay::new(movie))
+        }
+        None => None,
+    };
+    // The settings of the run, taken from the movie when replaying
+    let settings = match &replay {
+        Some(replay) => replay.movie().clone(),
+        None => {
+            let platform = profile.platform.unwrap_or_default();
+            let quirks = profile.quirks.resolve(platform);
+            let cycles = profile.cycles.unwrap_or(DEFAULT_CYCLES);
+            let seed = cli.seed.unwrap_or_else(rand::random);
+            let mut movie = Movie::new(&rom, platform, quirks, cycles, seed);
+            movie.fault_policy = cli.on_fault.into();
+            movie.key_edge = cli.key_edge.into();
+            movie
+        }
+    };
+    let mut recorder = cli
+        .record
+        .is_some()
+        .then(|| Recorder::new(settings.clone()));
+    let keymap = keymap(&cli, &profile)?;
+    let hints = info.as_ref().and_then(|info| key_hints(info, &keymap));
 
-    let sdl2_ctx = sdl2::init().map_err(anyhow::Error::msg)?;
-    let mut event_pump = sdl2_ctx.event_pump().map_err(anyhow::Error::msg)?;
+    match cli.frontend {
+        #[cfg(feature = "sdl")]
+        FrontendArg::Sdl => {
+            let sdl2_ctx = sdl2::init().map_err(anyhow::Error::msg)?;
+            let mut frontend = sdl_frontend::SdlFrontend::new(&sdl2_ctx, keymap)?;
+            if let Some(hints) = &hints {
+                frontend.message(hints);
+            }
+            let mut display = match profile.scale {
+                Some(scale) => chip_8::display::SDLRenderer::with_scale(&sdl2_ctx, scale),
+                None => chip_8::display::SDLRenderer::new(&sdl2_ctx),
+            };
+            if let Some(palette) = profile.palette {
+                display.set_palette(palette);
+            }
+            let mut emulator = emulator(display, &cli, &settings, &rom)?;
+            let default = chip_8::audio::Tone::default();
+            let tone = chip_8::audio::Tone {
+                frequency: profile.frequency.unwrap_or(default.frequency),
+                volume: profile.volume.unwrap_or(default.volume),
+                waveform: profile.waveform.unwrap_or(default.waveform),
+            };
+            let audio =
+                chip_8::audio::SDLAudio::new(&sdl2_ctx, tone).map_err(anyhow::Error::msg)?;
+            emulator.set_audio(Box::new(audio));
+            run(&cli, &mut emulator, &mut frontend, &mut recorder, replay)?;
+        }
+        FrontendArg::Terminal => {
+            if cli.debug {
+                bail!("The terminal front-end reads keys from stdin, so it can't be debugged");
+            }
+            // The renderer is dropped first and restores the screen before
+            // raw mode is left
+            let mut frontend = terminal_frontend::TerminalFrontend::new(keymap)?;
+            if let Some(hints) = &hints {
+                frontend.message(hints);
+            }
+            let mut display = TerminalRenderer::new()?;
+            if let Some(palette) = profile.palette {
+                display.set_palette(palette);
+            }
+            let mut emulator = emulator(display, &cli, &settings, &rom)?;
+            run(&cli, &mut emulator, &mut frontend, &mut recorder, replay)?;
+        }
+    }
 
-    let display = SDLRenderer::new(&sdl2_ctx);
+    if let (Some(recorder), Some(path)) = (recorder, &cli.record) {
+        fs::write(path, recorder.finish().to_bytes())?;
+    }
+    Ok(())
+}
 
-    let mut emulator = Emulator::new(display, cli.cycles);
+/// Creates an emulator with the ROM loaded and configured for `settings`.
+fn emulator<R: Render>(
+    display: R,
+    cli: &Cli,
+    settings: &Movie,
+    rom: &[u8],
+) -> anyhow::Result<Emulator<R>> {
+    let mut emulator = Emulator::new(display, settings.cycles, settings.platform, settings.quirks);
+    emulator.cpu.fault_policy = settings.fault_policy;
+    emulator.cpu.key_edge = settings.key_edge;
+    let budget = cli
+        .rewind
+        .checked_mul(1 << 20)
+        .ok_or_else(|| anyhow::anyhow!("Can't use {} MiB for rewinding", cli.rewind))?;
+    emulator.set_rewind_budget(budget);
+    emulator.seed(settings.seed);
     emulator.load_font(&FONT)?;
-    // TODO: Remove this unnecessary copy and make the emulator directly load from the file.
-    emulator.load_rom(rom.as_mut())?;
-
-    'running: loop {
-        for event in event_pump.poll_iter() {
-            match event {
-                Event::Quit { .. }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
-                    ..
-                } => {
-                    break 'running;
+    emulator.load_big_font(&BIG_FONT)?;
+    emulator.load_rom(rom)?;
+    Ok(emulator)
+}
+
+/// Runs the emulator until the user quits or the program exits.
+fn run<F: Frontend>(
+    cli: &Cli,
+    emulator: &mut Emulator<F::Display>,
+    frontend: &mut F,
+    recorder: &mut Option<Recorder>,
+    mut replay: Option<Replay>,
+) -> anyhow::Result<()> {
+    let mut debugger = Debugger::new(cli.debug);
+    let commands = cli.debug.then(spawn_console);
+    if cli.debug {
+        frontend.message(&debugger.view(emulator));
+    }
+
+    let mut key_state = [false; 16];
+    let mut slot = 1;
+    // One frame is rewound per frame while rewinding
+    let mut rewinding = false;
+    let mut turbo = false;
+    let mut faulted = false;
+    let mut waiting_for_key = false;
+    let mut clock = FrameClock::new();
+    loop {
+        for action in frontend.poll(debugger.is_paused(), &mut key_state)? {
+            match action {
+                Action::Quit => return Ok(()),
+                Action::Debug(command) => {
+                    let output = debugger.handle(command, emulator);
+                    if !output.is_empty() {
+                        frontend.message(&output);
+                    }
+                    if debugger.is_paused() {
+                        frontend.message(&debugger.view(emulator));
+                    }
+                }
+                Action::SelectSlot(number) => {
+                    slot = number;
+                    frontend.message(&format!("Selected save state slot {}", slot));
+                }
+                Action::SaveState => {
+                    let path = state_path(&cli.rom_file, slot);
+                    match fs::write(&path, emulator.save_state()) {
+                        Ok(()) => frontend.message(&format!("Saved state to slot {}", slot)),
+                        Err(error) => {
+                            frontend.error(&format!("Failed to write {}: {}", path, error))
+                        }
+                    }
+                }
+                // The movie would continue from a state it never reached
+                Action::LoadState if recorder.is_some() || replay.is_some() => {
+                    frontend.error("States can't be loaded while recording or replaying a movie")
                 }
-                Event::KeyDown {
-                    keycode: Some(keycode),
-                    ..
-                } => handle_keypress(keycode, true, &mut emulator.state.key_state),
-                Event::KeyUp {
-                    keycode: Some(keycode),
-                    ..
-                } => handle_keypress(keycode, false, &mut emulator.state.key_state),
-                _ => {}
+                Action::LoadState => {
+                    let path = state_path(&cli.rom_file, slot);
+                    match fs::read(&path)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|data| {
+                            emulator.load_state(&data)?;
+                            Ok(())
+                        }) {
+                        Ok(()) => {
+                            faulted = emulator.cpu.halted;
+                            frontend.message(&format!("Loaded state from slot {}", slot));
+                        }
+                        Err(error) => {
+                            frontend.error(&format!("Failed to load {}: {}", path, error))
+                        }
+                    }
+                }
+                Action::Rewind(true) if replay.is_some() => {
+                    frontend.error("Replays can't be rewound")
+                }
+                Action::Rewind(active) => rewinding = active,
+                Action::Turbo(active) => turbo = active,
+            }
+        }
+        for line in commands.iter().flat_map(|commands| commands.try_iter()) {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match line.parse::<Command>() {
+                Ok(command) => print_output(&debugger.handle(command, emulator)),
+                Err(error) => eprintln!("{}", error),
+            }
+        }
+        if rewinding {
+            // Recordings drop the keys of the rewound frames
+            match recorder {
+                Some(recorder) => recorder.rewind(emulator, 1)?,
+                None => emulator.rewind(1)?,
+            };
+            faulted = emulator.cpu.halted;
+            clock.wait();
+            continue;
+        }
+        for _ in 0..if turbo { TURBO_SPEED } else { 1 } {
+            match (&mut *recorder, &mut replay) {
+                (Some(recorder), _) => recorder.apply(emulator, &key_state),
+                (_, Some(replay)) => replay.apply(emulator),
+                _ => emulator.state.key_state = key_state,
+            }
+            // A faulted cpu is halted as well but the window is kept open
+            if emulator.cpu.halted && !faulted {
+                return Ok(());
+            }
+            match debugger.run_frame(emulator) {
+                Ok(Some(stop)) => {
+                    if stop != Stop::Step {
+                        frontend.message(&stop.to_string());
+                    }
+                    frontend.message(&debugger.view(emulator));
+                }
+                Ok(None) => {}
+                Err(EmulatorError::Cpu(error)) => {
+                    frontend.error(&error.to_string());
+                    faulted = emulator.cpu.halted;
+                }
+                Err(error) => return Err(error.into()),
+            }
+            if debugger.is_paused() {
+                break;
             }
         }
-        emulator.step()?;
-        ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / cli.cycles));
+        // Frames end by themselves, but the debugger stops within frames and
+        // states are loaded between them
+        emulator.present()?;
+        if emulator.cpu.waiting_for_key() != waiting_for_key {
+            waiting_for_key = !waiting_for_key;
+            frontend.waiting_for_key(emulator.display_mut(), waiting_for_key);
+        }
+        clock.wait();
     }
+}
 
-    Ok(())
+/// Reads debugger commands from stdin on a separate thread, so the window
+/// stays responsive while waiting for input.
+fn spawn_console() -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
 }
 
-fn handle_keypress(keycode: Keycode, is_up: bool, key_state: &mut KeyState) {
-    let index = match keycode {
-        Keycode::Num1 => 0x1,
-        Keycode::Num2 => 0x2,
-        Keycode::Num3 => 0x3,
-        Keycode::Num4 => 0xC,
-        Keycode::Q => 0x4,
-        Keycode::W => 0x5,
-        Keycode::E => 0x6,
-        Keycode::R => 0x7,
-        Keycode::A => 0x8,
-        Keycode::S => 0x9,
-        Keycode::D => 0xA,
-        Keycode::F => 0xB,
-        Keycode::Y => 0xC,
-        Keycode::X => 0xD,
-        Keycode::C => 0xE,
-        Keycode::V => 0xF,
-        _ => return,
+/// The actions of the function keys: F5 saves the state and F9 loads it, F6
+/// pauses and resumes, F7 steps, F8 steps over calls and F10 runs until the
+/// current subroutine returns.
+fn function_key(number: u8, paused: bool) -> Option<Action> {
+    match number {
+        5 => Some(Action::SaveState),
+        9 => Some(Action::LoadState),
+        6 if paused => Some(Action::Debug(Command::Continue)),
+        6 => Some(Action::Debug(Command::Pause)),
+        7 => Some(Action::Debug(Command::Step)),
+        8 => Some(Action::Debug(Command::StepOver)),
+        10 => Some(Action::Debug(Command::StepOut)),
+        _ => None,
+    }
+}
+
+/// Save states are stored next to the ROM.
+fn state_path(rom_file: &str, slot: u8) -> String {
+    format!("{}.state{}", rom_file, slot)
+}
+
+fn print_output(output: &str) {
+    if !output.is_empty() {
+        println!("{}", output);
+    }
+}
+
+/// What the keys of the ROM do according to the database, named by the keys of
+/// the keyboard pressing them.
+fn key_hints(info: &RomInfo, keymap: &Keymap) -> Option<String> {
+    if info.keys.is_empty() {
+        return None;
+    }
+    let keys: Vec<_> = info
+        .keys
+        .iter()
+        .map(|(action, chip8)| match keymap.keys(*chip8).next() {
+            Some(key) => format!("{} {}", action, key),
+            None => format!("{} (CHIP-8 key {:X})", action, chip8),
+        })
+        .collect();
+    Some(format!("{}: {}", info.title, keys.join(", ")))
+}
+
+/// The preset of the layout, changed by the key map of the config file, the
+/// key map file and the overrides of the ROM.
+fn keymap(cli: &Cli, profile: &Settings) -> anyhow::Result<Keymap> {
+    let mut keymap = Keymap::new(profile.layout.unwrap_or_default());
+    keymap
+        .apply(&profile.keymap)
+        .map_err(|error| anyhow::anyhow!("Key map of the config file: {}", error))?;
+    if let Some(path) = &cli.keymap {
+        keymap
+            .apply(&fs::read_to_string(path)?)
+            .map_err(|error| anyhow::anyhow!("{}: {}", path, error))?;
+    }
+    let path = format!("{}.keymap", cli.rom_file);
+    match fs::read_to_string(&path) {
+        Ok(config) => keymap
+            .apply(&config)
+            .map_err(|error| anyhow::anyhow!("{}: {}", path, error))?,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error.into()),
+    }
+    Ok(keymap)
+}
+
+#[cfg(feature = "sdl")]
+mod sdl_frontend {
+    use chip_8::display::SDLRenderer;
+    use sdl2::{
+        event::Event,
+        keyboard::{Keycode, Mod},
+        EventPump, Sdl,
     };
 
-    key_state[index] = is_up;
+    use super::*;
+
+    /// A window, whose contents are drawn by the
+    /// [`SDLRenderer`](chip_8::display::SDLRenderer).
+    pub struct SdlFrontend {
+        event_pump: EventPump,
+        keymap: Keymap,
+    }
+
+    impl SdlFrontend {
+        pub fn new(sdl2_ctx: &Sdl, keymap: Keymap) -> anyhow::Result<Self> {
+            let event_pump = sdl2_ctx.event_pump().map_err(anyhow::Error::msg)?;
+            Ok(Self { event_pump, keymap })
+        }
+    }
+
+    impl Frontend for SdlFrontend {
+        type Display = SDLRenderer;
+
+        fn poll(&mut self, paused: bool, keys: &mut KeyState) -> anyhow::Result<Vec<Action>> {
+            let mut actions = Vec::new();
+            for event in self.event_pump.poll_iter() {
+                match event {
+                    Event::Quit { .. }
+                    | Event::KeyDown {
+                        keycode: Some(Keycode::Escape),
+                        ..
+                    } => actions.push(Action::Quit),
+                    Event::KeyDown {
+                        keycode: Some(keycode),
+                        repeat: false,
+                        ..
+                    } if function_key_number(keycode)
+                        .and_then(|number| function_key(number, paused))
+                        .is_some() =>
+                    {
+                        let number = function_key_number(keycode).unwrap();
+                        actions.extend(function_key(number, paused));
+                    }
+                    Event::KeyDown {
+                        keycode: Some(keycode),
+                        keymod,
+                        repeat: false,
+                        ..
+                    } if slot_number(keycode, keymod).is_some() => {
+                        actions.extend(slot_number(keycode, keymod).map(Action::SelectSlot));
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Backspace),
+                        ..
+                    } => actions.push(Action::Rewind(true)),
+                    Event::KeyUp {
+                        keycode: Some(Keycode::Backspace),
+                        ..
+                    } => actions.push(Action::Rewind(false)),
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Tab),
+                        ..
+                    } => actions.push(Action::Turbo(true)),
+                    Event::KeyUp {
+                        keycode: Some(Keycode::Tab),
+                        ..
+                    } => actions.push(Action::Turbo(false)),
+                    Event::KeyDown {
+                        keycode: Some(keycode),
+                        ..
+                    } => handle_keypress(&self.keymap, keycode, true, keys),
+                    Event::KeyUp {
+                        keycode: Some(keycode),
+                        ..
+                    } => handle_keypress(&self.keymap, keycode, false, keys),
+                    _ => {}
+                }
+            }
+            Ok(actions)
+        }
+
+        fn message(&mut self, text: &str) {
+            println!("{}", text);
+        }
+
+        fn error(&mut self, text: &str) {
+            eprintln!("{}", text);
+        }
+
+        fn waiting_for_key(&mut self, display: &mut SDLRenderer, waiting: bool) {
+            let status = waiting.then_some("Waiting for a key");
+            if let Err(error) = display.set_status(status) {
+                self.error(&error.to_string());
+            }
+        }
+    }
+
+    fn function_key_number(keycode: Keycode) -> Option<u8> {
+        let offset = (keycode as i32).checked_sub(Keycode::F1 as i32)?;
+        (0..12).contains(&offset).then_some(offset as u8 + 1)
+    }
+
+    /// Ctrl+0 to Ctrl+9 select the save state slot used by F5 and F9.
+    fn slot_number(keycode: Keycode, keymod: Mod) -> Option<u8> {
+        if !keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) {
+            return None;
+        }
+        let digit = (keycode as i32).checked_sub(Keycode::Num0 as i32)?;
+        (0..10).contains(&digit).then_some(digit as u8)
+    }
+
+    fn handle_keypress(keymap: &Keymap, keycode: Keycode, pressed: bool, keys: &mut KeyState) {
+        if let Some(index) = key(keycode).and_then(|key| keymap.get(key)) {
+            keys[index] = pressed;
+        }
+    }
+
+    fn key(keycode: Keycode) -> Option<Key> {
+        let numpad = match keycode {
+            Keycode::Kp0 => '0',
+            Keycode::Kp1 => '1',
+            Keycode::Kp2 => '2',
+            Keycode::Kp3 => '3',
+            Keycode::Kp4 => '4',
+            Keycode::Kp5 => '5',
+            Keycode::Kp6 => '6',
+            Keycode::Kp7 => '7',
+            Keycode::Kp8 => '8',
+            Keycode::Kp9 => '9',
+            Keycode::KpDivide => '/',
+            Keycode::KpMultiply => '*',
+            Keycode::KpMinus => '-',
+            Keycode::KpPlus => '+',
+            Keycode::KpPeriod => '.',
+            Keycode::KpEnter => '\n',
+            // The keycodes of the other keys that type something are their
+            // characters
+            _ => {
+                return char::from_u32(keycode as u32)
+                    .filter(|c| !c.is_control())
+                    .map(|c| Key::Char(c.to_ascii_lowercase()))
+            }
+        };
+        Some(Key::Numpad(numpad))
+    }
+}
+
+mod terminal_frontend {
+    use std::io::{Stdout, Write};
+
+    use chip_8::terminal::TerminalInput;
+    use crossterm::{
+        cursor::MoveTo,
+        event::{KeyCode, KeyEventKind, KeyModifiers},
+        queue,
+        style::Print,
+        terminal::{Clear, ClearType},
+    };
+
+    use super::*;
+
+    /// Reads keys from stdin. Messages are shown below the screen drawn by
+    /// the [`TerminalRenderer`].
+    pub struct TerminalFrontend {
+        input: TerminalInput,
+        out: Stdout,
+        keymap: Keymap,
+    }
+
+    impl TerminalFrontend {
+        pub fn new(keymap: Keymap) -> anyhow::Result<Self> {
+            Ok(Self {
+                input: TerminalInput::new()?,
+                out: io::stdout(),
+                keymap,
+            })
+        }
+
+        // Most terminals send the keys of the numeric keypad like the other
+        // keys, so they are only told apart if the main block is not mapped
+        fn chip8_key(&self, c: char) -> Option<usize> {
+            let c = c.to_ascii_lowercase();
+            self.keymap
+                .get(Key::Char(c))
+                .or_else(|| self.keymap.get(Key::Numpad(c)))
+        }
+    }
+
+    impl Frontend for TerminalFrontend {
+        type Display = TerminalRenderer;
+
+        fn poll(&mut self, paused: bool, keys: &mut KeyState) -> anyhow::Result<Vec<Action>> {
+            let mut actions = Vec::new();
+            for event in self.input.poll()? {
+                let pressed = event.kind != KeyEventKind::Release;
+                match event.code {
+                    KeyCode::Esc => actions.push(Action::Quit),
+                    KeyCode::F(number) if pressed => actions.extend(function_key(number, paused)),
+                    // Few terminals report Ctrl+digit, so Alt works as well
+                    KeyCode::Char(digit @ '0'..='9')
+                        if pressed
+                            && event
+                                .modifiers
+                                .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+                    {
+                        actions.push(Action::SelectSlot(digit as u8 - b'0'))
+                    }
+                    KeyCode::Backspace => actions.push(Action::Rewind(pressed)),
+                    KeyCode::Tab => actions.push(Action::Turbo(pressed)),
+                    KeyCode::Char(c) => {
+                        if let Some(index) = self.chip8_key(c) {
+                            keys[index] = pressed;
+                        }
+                    }
+                    KeyCode::Enter => {
+                        if let Some(index) = self.chip8_key('\n') {
+                            keys[index] = pressed;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(actions)
+        }
+
+        fn message(&mut self, text: &str) {
+            // Below the screen in high resolution mode
+            let top = (chip_8::display::HIRES_HEIGHT / 2) as u16 + 1;
+            // Nothing can be done if the terminal can't be written to
+            let _ = queue!(self.out, MoveTo(0, top), Clear(ClearType::FromCursorDown));
+            for (row, line) in text.lines().enumerate() {
+                let _ = queue!(self.out, MoveTo(0, top + row as u16), Print(line));
+            }
+            let _ = self.out.flush();
+        }
+
+        fn waiting_for_key(&mut self, _: &mut TerminalRenderer, waiting: bool) {
+            self.message(if waiting { "Waiting for a key" } else { "" });
+        }
+    }
 }
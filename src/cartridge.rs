@@ -0,0 +1,246 @@
+//! Octo cartridges.
+//!
+//! Octo shares programs as GIF images with the source and the options of the
+//! program hidden in the low two bits of every pixel. Taken from the pixels
+//! of all frames in order, four pixels make up a byte, most significant bits
+//! first. The first four bytes are the big-endian length of the JSON that
+//! follows, `{"program": "...", "options": {...}}`. The source is compiled
+//! with [`octo::compile`](crate::octo::compile).
+
+use serde_json::{Map, Value};
+
+use crate::{
+    config::{self, QuirkSettings, Settings},
+    database,
+    display::Palette,
+    emulator::FRAME_RATE,
+    octo,
+    platform::Platform,
+    quirks::Quirks,
+};
+
+/// Octo's options for the colours, in the order of a [`Palette`].
+const COLORS: [&str; 4] = ["backgroundColor", "fillColor", "fillColor2", "blendColor"];
+
+/// A program loaded from an Octo cartridge.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cartridge {
+    /// The Octo source of the program.
+    pub source: String,
+    pub rom: Vec<u8>,
+    /// How many instructions are executed per frame.
+    pub tickrate: Option<u32>,
+    pub palette: Option<Palette>,
+    pub quirks: Quirks,
+}
+
+impl Cartridge {
+    /// Whether `data` is a GIF image and thus may be a cartridge.
+    pub fn is_cartridge(data: &[u8]) -> bool {
+        data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a")
+    }
+
+    /// Extracts the program and its options from a cartridge and compiles
+    /// the program.
+    pub fn decode(data: &[u8]) -> Result<Self, String> {
+        let json = payload(data)?;
+        let json: Value =
+            serde_json::from_str(&json).map_err(|error| format!("Invalid cartridge: {}", error))?;
+        let source = json
+            .get("program")
+            .and_then(Value::as_str)
+            .ok_or("The cartridge contains no program")?
+            .to_string();
+        let empty = Map::new();
+        let options = json
+            .get("options")
+            .and_then(Value::as_object)
+            .unwrap_or(&empty);
+
+        let tickrate = options
+            .get("tickrate")
+            .map(database::tickrate)
+            .transpose()?;
+        let colors: Vec<&str> = COLORS
+            .iter()
+            .map_while(|name| options.get(*name).and_then(Value::as_str))
+            .collect();
+        let palette = match colors.len() {
+            0 => None,
+            _ => Some(config::palette(&colors)?),
+        };
+
+        // Without options these are XO-CHIP's quirks, the options enable the
+        // quirks of older interpreters
+        let quirk = |name: &str| options.get(name).and_then(Value::as_bool) == Some(true);
+        let quirks = Quirks {
+            shift_uses_vy: !quirk("shiftQuirks"),
+            load_store_increments_i: !quirk("loadStoreQuirks"),
+            clip_sprites: quirk("clipQuirks"),
+            jump_uses_vx: quirk("jumpQuirks"),
+            display_wait: quirk("vBlankQuirks"),
+            vf_reset: quirk("logicQuirks"),
+        };
+
+        let rom = octo::compile(&source).map_err(|error| error.to_string())?;
+        Ok(Self {
+            source,
+            rom,
+            tickrate,
+            palette,
+            quirks,
+        })
+    }
+
+    /// The settings to run the program with, see
+    /// [`Config::settings`](config::Config::settings).
+    pub fn settings(&self) -> Settings {
+        Settings {
+            platform: Some(Platform::XoChip),
+            quirks: QuirkSettings {
+                preset: Some(self.quirks),
+                ..QuirkSettings::default()
+            },
+            // Parsing rejects tickrates for which this overflows
+            cycles: self.tickrate.map(|tickrate| tickrate * FRAME_RATE),
+            palette: self.palette,
+            ..Settings::default()
+        }
+    }
+}
+
+/// Loads the contents of a ROM file, which is compiled first if it is an Octo
+/// cartridge.
+pub fn load(data: Vec<u8>) -> Result<(Vec<u8>, Option<Cartridge>), String> {
+    if Cartridge::is_cartridge(&data) {
+        let cartridge = Cartridge::decode(&data)?;
+        Ok((cartridge.rom.clone(), Some(cartridge)))
+    } else {
+        Ok((data, None))
+    }
+}
+
+/// The JSON hidden in the pixels of the GIF `data`.
+fn payload(data: &[u8]) -> Result<String, String> {
+    let error = |error: gif::DecodingError| format!("Invalid cartridge: {}", error);
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(data).map_err(error)?;
+
+    let mut pixels = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().map_err(error)? {
+        pixels.extend_from_slice(&frame.buffer);
+    }
+    let mut bytes = pixels.chunks_exact(4).map(|pixels| {
+        pixels
+            .iter()
+            .fold(0, |byte, pixel| byte << 2 | pixel & 0b11)
+    });
+
+    let length: Vec<u8> = bytes.by_ref().take(4).collect();
+    let length = match <[u8; 4]>::try_from(length) {
+        Ok(length) => u32::from_be_bytes(length) as usize,
+        Err(_) => return Err("The image is too small to be a cartridge".to_string()),
+    };
+    // Octo encodes the characters of the JSON as single bytes
+    let json: String = bytes.take(length).map(char::from).collect();
+    if json.chars().count() < length {
+        return Err("The cartridge is truncated".to_string());
+    }
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Encodes `json` the way Octo does, split over two frames.
+    fn cartridge(json: &Value) -> Vec<u8> {
+        let json = json.to_string();
+        let mut bytes = (json.len() as u32).to_be_bytes().to_vec();
+        bytes.extend(json.bytes());
+        let mut pixels: Vec<u8> = bytes
+            .iter()
+            .flat_map(|byte| [6, 4, 2, 0].map(|shift| byte >> shift & 0b11 | 0b100))
+            .collect();
+        let width = 64;
+        let height = (pixels.len() / width / 2 + 1) as u16;
+        pixels.resize(width * height as usize * 2, 0);
+
+        let mut out = Vec::new();
+        let palette = [0; 8 * 3];
+        let mut encoder = gif::Encoder::new(&mut out, width as u16, height, &palette).unwrap();
+        for frame in pixels.chunks(width * height as usize) {
+            let frame = gif::Frame::from_indexed_pixels(width as u16, height, frame, None);
+            encoder.write_frame(&frame).unwrap();
+        }
+        drop(encoder);
+        out
+    }
+
+    #[test]
+    fn test_decode() {
+        let data = cartridge(&json!({
+            "program": ": main\n  v0 := 1\n  loop again",
+            "options": {
+                "tickrate": 20,
+                "backgroundColor": "#000000",
+                "fillColor": "#FF0000",
+                "fillColor2": "#00FF00",
+                "blendColor": "#0000FF",
+                "shiftQuirks": true,
+                "vBlankQuirks": true,
+                "logicQuirks": false,
+            }
+        }));
+        assert!(Cartridge::is_cartridge(&data));
+        let cartridge = Cartridge::decode(&data).unwrap();
+        assert_eq!(cartridge.rom, [0x60, 0x01, 0x12, 0x02]);
+        assert_eq!(cartridge.tickrate, Some(20));
+        assert_eq!(
+            cartridge.palette,
+            Some([[0, 0, 0], [255, 0, 0], [0, 255, 0], [0, 0, 255]])
+        );
+        assert_eq!(
+            cartridge.quirks,
+            Quirks {
+                shift_uses_vy: false,
+                display_wait: true,
+                ..Quirks::XO_CHIP
+            }
+        );
+
+        let settings = cartridge.settings();
+        assert_eq!(settings.platform, Some(Platform::XoChip));
+        assert_eq!(settings.cycles, Some(1200));
+        assert_eq!(settings.quirks.resolve(Platform::Chip8), cartridge.quirks);
+    }
+
+    #[test]
+    fn test_load() {
+        let rom = vec![0x12, 0x00];
+        assert_eq!(load(rom.clone()), Ok((rom, None)));
+
+        let data = cartridge(&json!({"program": ": main exit"}));
+        let (rom, cartridge) = load(data).unwrap();
+        assert_eq!(rom, [0x00, 0xFD]);
+        let cartridge = cartridge.unwrap();
+        assert_eq!((cartridge.tickrate, cartridge.palette), (None, None));
+        assert_eq!(cartridge.quirks, Quirks::XO_CHIP);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Cartridge::decode(b"GIF89a").is_err());
+        let error = Cartridge::decode(&cartridge(&json!({
+            "program": ": main exit",
+            "options": {"tickrate": 4_000_000_000u32}
+        })));
+        assert_eq!(error.unwrap_err(), "Invalid tickrate: 4000000000");
+        let error = Cartridge::decode(&cartridge(&json!({"options": {}}))).unwrap_err();
+        assert_eq!(error, "The cartridge contains no program");
+        let error = Cartridge::decode(&cartridge(&json!({"program": ": main jump nowhere"})));
+        assert_eq!(error.unwrap_err(), "<source>:1:13: Undefined name: nowhere");
+    }
+}
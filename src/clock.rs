@@ -0,0 +1,107 @@
+//! Paces front-ends to the frame rate of the emulator using the wall clock.
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::emulator::FRAME_RATE;
+
+/// How far a front-end may fall behind before the missed frames are skipped.
+pub const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// Waits for the start of every frame.
+///
+/// Frames are scheduled from the first frame on instead of from the end of
+/// the previous wait, so sleeping too long in one frame is made up for by
+/// sleeping less in the next ones and the emulator does not drift. If the
+/// front-end falls behind by more than [`MAX_LAG`], e.g. because it was
+/// suspended, the missed frames are dropped instead of running them as fast
+/// as possible.
+pub struct FrameClock {
+    start: Instant,
+    // The number of the next frame counted from `start`
+    frame: u32,
+}
+
+impl Default for FrameClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameClock {
+    /// Creates a clock whose first frame starts now.
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            frame: 0,
+        }
+    }
+
+    /// Sleeps until the next frame starts.
+    pub fn wait(&mut self) {
+        if let Some(delay) = self.delay(Instant::now()) {
+            thread::sleep(delay);
+        }
+    }
+
+    // Schedules the next frame and returns how long there is until it starts
+    // at `now`
+    fn delay(&mut self, now: Instant) -> Option<Duration> {
+        self.frame += 1;
+        let next = self.start + Duration::from_secs(1) * self.frame / FRAME_RATE;
+        if now > next + MAX_LAG {
+            self.start = now;
+            self.frame = 0;
+            return None;
+        }
+        // Restarting now and then keeps the frame number from overflowing
+        if self.frame == FRAME_RATE {
+            self.start = next;
+            self.frame = 0;
+        }
+        next.checked_duration_since(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compensates_drift() {
+        let mut clock = FrameClock::new();
+        let start = clock.start;
+        let frames = |count| Duration::from_secs(1) * count / FRAME_RATE;
+        let at = |millis| start + Duration::from_millis(millis);
+
+        assert_eq!(clock.delay(start), Some(frames(1)));
+        // Oversleeping shortens the next frame
+        assert_eq!(
+            clock.delay(at(20)),
+            Some(frames(2) - Duration::from_millis(20))
+        );
+        // Falling behind a little runs the late frames right away
+        assert_eq!(clock.delay(at(70)), None);
+        assert_eq!(clock.delay(at(70)), None);
+        assert!(clock.delay(at(70)).is_some());
+
+        // Exactly one second after the start for any number of frames
+        let mut clock = FrameClock::new();
+        let start = clock.start;
+        for _ in 0..FRAME_RATE * 3 - 1 {
+            clock.delay(start);
+        }
+        assert_eq!(clock.delay(start), Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn test_skips_frames() {
+        let mut clock = FrameClock::new();
+        let start = clock.start;
+        let late = start + Duration::from_secs(1);
+        assert_eq!(clock.delay(late), None);
+        assert_eq!(clock.delay(late), Some(Duration::from_secs(1) / FRAME_RATE));
+    }
+}
@@ -0,0 +1,430 @@
+//! The configuration file of the emulator.
+//!
+//! The file is written in TOML and lives at [`Config::path`]. Its top level
+//! holds the defaults for all ROMs, tables under `roms` keyed by the SHA-1
+//! hash of a ROM override them for that ROM:
+//!
+//! ```toml
+//! cycles = 700
+//! palette = ["#000000", "#FFCC00"]
+//! scale = 15
+//! layout = "qwertz"
+//! volume = 0.1
+//!
+//! [keymap]
+//! 5 = ["space", "w"]
+//!
+//! [roms.0123456789abcdef0123456789abcdef01234567]
+//! platform = "schip"
+//! quirks = { preset = "schip", clip_sprites = false }
+//! ```
+//!
+//! Every setting is optional, command line arguments take precedence over
+//! both levels.
+
+use std::{collections::BTreeMap, fs, io, path::PathBuf, str::FromStr};
+
+use toml::{Table, Value};
+
+use crate::{
+    audio::Waveform,
+    display::{Palette, DEFAULT_PALETTE},
+    keymap::{Keymap, Layout},
+    movie::rom_hash_hex,
+    platform::Platform,
+    quirks::Quirks,
+};
+
+/// The quirks to emulate, as preset with single quirks changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QuirkSettings {
+    /// If this is `None` the default quirks of the platform are used.
+    pub preset: Option<Quirks>,
+    pub shift_uses_vy: Option<bool>,
+    pub jump_uses_vx: Option<bool>,
+    pub load_store_increments_i: Option<bool>,
+    pub vf_reset: Option<bool>,
+    pub clip_sprites: Option<bool>,
+    pub display_wait: Option<bool>,
+}
+
+impl QuirkSettings {
+    /// The quirks to emulate for ROMs targeting `platform`.
+    pub fn resolve(&self, platform: Platform) -> Quirks {
+        let quirks = self.preset.unwrap_or_else(|| platform.default_quirks());
+        Quirks {
+            shift_uses_vy: self.shift_uses_vy.unwrap_or(quirks.shift_uses_vy),
+            jump_uses_vx: self.jump_uses_vx.unwrap_or(quirks.jump_uses_vx),
+            load_store_increments_i: self
+                .load_store_increments_i
+                .unwrap_or(quirks.load_store_increments_i),
+            vf_reset: self.vf_reset.unwrap_or(quirks.vf_reset),
+            clip_sprites: self.clip_sprites.unwrap_or(quirks.clip_sprites),
+            display_wait: self.display_wait.unwrap_or(quirks.display_wait),
+        }
+    }
+
+    /// Overrides the settings that are set in `other`. A preset in `other`
+    /// also discards the single quirks changed so far.
+    pub fn merge(&mut self, other: &Self) {
+        if other.preset.is_some() {
+            *self = Self::default();
+        }
+        merge(&mut self.preset, other.preset);
+        merge(&mut self.shift_uses_vy, other.shift_uses_vy);
+        merge(&mut self.jump_uses_vx, other.jump_uses_vx);
+        merge(
+            &mut self.load_store_increments_i,
+            other.load_store_increments_i,
+        );
+        merge(&mut self.vf_reset, other.vf_reset);
+        merge(&mut self.clip_sprites, other.clip_sprites);
+        merge(&mut self.display_wait, other.display_wait);
+    }
+
+    /// Parses either the name of a preset or a table with an optional
+    /// `preset` and the quirks to change.
+    fn parse(value: &Value) -> Result<Self, String> {
+        let table = match value {
+            Value::String(preset) => {
+                return Ok(Self {
+                    preset: Some(preset.parse()?),
+                    ..Self::default()
+                })
+            }
+            Value::Table(table) => table,
+            _ => return Err(format!("Expected a preset or a table, got {}", value)),
+        };
+        let mut quirks = Self::default();
+        for (key, value) in table {
+            let setting = match key.as_str() {
+                "preset" => {
+                    quirks.preset = Some(parse(value)?);
+                    continue;
+                }
+                "shift_uses_vy" => &mut quirks.shift_uses_vy,
+                "jump_uses_vx" => &mut quirks.jump_uses_vx,
+                "load_store_increments_i" => &mut quirks.load_store_increments_i,
+                "vf_reset" => &mut quirks.vf_reset,
+                "clip_sprites" => &mut quirks.clip_sprites,
+                "display_wait" => &mut quirks.display_wait,
+                _ => return Err(format!("Unknown quirk: {}", key)),
+            };
+            *setting = Some(
+                value
+                    .as_bool()
+                    .ok_or_else(|| format!("{}: Expected a boolean, got {}", key, value))?,
+            );
+        }
+        Ok(quirks)
+    }
+}
+
+/// The settings of one level of the config file. Settings that are `None`
+/// are left to the level below.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Settings {
+    pub platform: Option<Platform>,
+    pub quirks: QuirkSettings,
+    /// How many instructions are executed per second.
+    pub cycles: Option<u32>,
+    pub palette: Option<Palette>,
+    /// How many pixels of the window make up a pixel in low resolution mode.
+    pub scale: Option<u32>,
+    pub layout: Option<Layout>,
+    /// Key bindings in the format of [`Keymap::apply`], applied after the
+    /// preset of the layout.
+    pub keymap: String,
+    pub frequency: Option<f32>,
+    pub volume: Option<f32>,
+    pub waveform: Option<Waveform>,
+}
+
+impl Settings {
+    /// Overrides the settings that are set in `other`. Key bindings are
+    /// applied after the ones already set.
+    pub fn merge(&mut self, other: &Self) {
+        merge(&mut self.platform, other.platform);
+        self.quirks.merge(&other.quirks);
+        merge(&mut self.cycles, other.cycles);
+        merge(&mut self.palette, other.palette);
+        merge(&mut self.scale, other.scale);
+        merge(&mut self.layout, other.layout);
+        self.keymap.push_str(&other.keymap);
+        merge(&mut self.frequency, other.frequency);
+        merge(&mut self.volume, other.volume);
+        merge(&mut self.waveform, other.waveform);
+    }
+
+    /// Parses the settings of `table`, skipping the keys in `skip`.
+    fn parse(table: &Table, skip: &[&str]) -> Result<Self, String> {
+        let mut settings = Self::default();
+        for (key, value) in table.iter().filter(|(key, _)| !skip.contains(&&***key)) {
+            let error = |message: String| format!("{}: {}", key, message);
+            match key.as_str() {
+                "platform" => settings.platform = Some(parse(value).map_err(error)?),
+                "quirks" => settings.quirks = QuirkSettings::parse(value).map_err(error)?,
+                "cycles" => settings.cycles = Some(integer(value).map_err(error)?),
+                "palette" => settings.palette = Some(colors(value).map_err(error)?),
+                "scale" => {
+                    settings.scale = Some(integer(value).map_err(error)?);
+                    if settings.scale == Some(0) {
+                        return Err(error("Must be at least 1".into()));
+                    }
+                }
+                "layout" => settings.layout = Some(parse(value).map_err(error)?),
+                "keymap" => settings.keymap = keymap(value).map_err(error)?,
+                "frequency" => settings.frequency = Some(float(value).map_err(error)?),
+                "volume" => settings.volume = Some(float(value).map_err(error)?),
+                "waveform" => settings.waveform = Some(parse(value).map_err(error)?),
+                _ => return Err(format!("Unknown setting: {}", key)),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+/// The contents of the config file.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Config {
+    /// The settings for all ROMs.
+    pub defaults: Settings,
+    /// The settings for single ROMs, keyed by the SHA-1 hash of the ROM in
+    /// lowercase hexadecimal.
+    pub roms: BTreeMap<String, Settings>,
+}
+
+impl Config {
+    /// Where the config file is looked up, `chip-8/config.toml` in the config
+    /// directory of the user. `None` if the system has no such directory.
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("chip-8").join("config.toml"))
+    }
+
+    /// Reads the config file at `path`. A missing file is the same as an
+    /// empty one.
+    pub fn load(path: &PathBuf) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(config) => config
+                .parse()
+                .map_err(|error| format!("{}: {}", path.display(), error)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(format!("{}: {}", path.display(), error)),
+        }
+    }
+
+    /// The settings for `rom`, the defaults overridden by its own section.
+    /// `detected` are settings known for the ROM from elsewhere, e.g. the
+    /// [ROM database](crate::database), which override the defaults but not
+    /// the section of the ROM.
+    pub fn settings(&self, rom: &[u8], detected: Option<&Settings>) -> Settings {
+        let mut settings = self.defaults.clone();
+        if let Some(detected) = detected {
+            settings.merge(detected);
+        }
+        if let Some(overrides) = self.roms.get(&rom_hash_hex(rom)) {
+            settings.merge(overrides);
+        }
+        settings
+    }
+}
+
+impl FromStr for Config {
+    type Err = String;
+
+    fn from_str(config: &str) -> Result<Self, Self::Err> {
+        let table: Table = config.parse().map_err(|error| format!("{}", error))?;
+        let mut roms = BTreeMap::new();
+        if let Some(sections) = table.get("roms") {
+            let sections = sections
+                .as_table()
+                .ok_or("roms: Expected a table of ROMs")?;
+            for (hash, section) in sections {
+                let error = |message: String| format!("roms.{}: {}", hash, message);
+                if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(error("Expected the SHA-1 hash of a ROM".into()));
+                }
+                let section = section
+                    .as_table()
+                    .ok_or_else(|| error("Expected a table".into()))?;
+                let settings = Settings::parse(section, &[]).map_err(error)?;
+                roms.insert(hash.to_ascii_lowercase(), settings);
+            }
+        }
+        Ok(Self {
+            defaults: Settings::parse(&table, &["roms"])?,
+            roms,
+        })
+    }
+}
+
+/// Overrides `setting` if `other` is set.
+fn merge<T>(setting: &mut Option<T>, other: Option<T>) {
+    if other.is_some() {
+        *setting = other;
+    }
+}
+
+fn parse<T: FromStr<Err = String>>(value: &Value) -> Result<T, String> {
+    value
+        .as_str()
+        .ok_or_else(|| format!("Expected a string, got {}", value))?
+        .parse()
+}
+
+fn integer(value: &Value) -> Result<u32, String> {
+    value
+        .as_integer()
+        .and_then(|integer| integer.try_into().ok())
+        .ok_or_else(|| format!("Expected a positive integer, got {}", value))
+}
+
+fn float(value: &Value) -> Result<f32, String> {
+    match value {
+        Value::Float(float) => Ok(*float as f32),
+        Value::Integer(integer) => Ok(*integer as f32),
+        _ => Err(format!("Expected a number, got {}", value)),
+    }
+}
+
+/// Parses two to four colours as `#RRGGBB`. Missing colours are taken from
+/// the [`DEFAULT_PALETTE`].
+pub(crate) fn palette(colors: &[&str]) -> Result<Palette, String> {
+    if !(2..=4).contains(&colors.len()) {
+        return Err(format!(
+            "Expected two to four colours, got {}",
+            colors.len()
+        ));
+    }
+    let mut palette = DEFAULT_PALETTE;
+    for (slot, color) in palette.iter_mut().zip(colors) {
+        let rgb = color
+            .strip_prefix('#')
+            .filter(|hex| hex.len() == 6)
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| format!("Expected a colour as #RRGGBB, got {}", color))?;
+        let [_, r, g, b] = rgb.to_be_bytes();
+        *slot = [r, g, b];
+    }
+    Ok(palette)
+}
+
+fn colors(value: &Value) -> Result<Palette, String> {
+    let colors = value
+        .as_array()
+        .and_then(|colors| colors.iter().map(Value::as_str).collect::<Option<Vec<_>>>())
+        .ok_or_else(|| format!("Expected a list of colours, got {}", value))?;
+    palette(&colors)
+}
+
+/// Converts a table of CHIP-8 keys to the name of a key or a list of them
+/// into lines for [`Keymap::apply`].
+fn keymap(value: &Value) -> Result<String, String> {
+    let table = value
+        .as_table()
+        .ok_or_else(|| format!("Expected a table of CHIP-8 keys, got {}", value))?;
+    let mut config = String::new();
+    for (chip8, value) in table {
+        let error = || format!("{}: Expected names of keys, got {}", chip8, value);
+        let keys: Vec<_> = match value {
+            Value::String(key) => vec![key.as_str()],
+            Value::Array(keys) => keys
+                .iter()
+                .map(Value::as_str)
+                .collect::<Option<_>>()
+                .ok_or_else(error)?,
+            _ => return Err(error()),
+        };
+        config.push_str(&format!("{} = {}\n", chip8, keys.join(", ")));
+    }
+    // Reports invalid keys now instead of when the key map is built
+    Keymap::default().apply(&config)?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r##"
+cycles = 700
+palette = ["#000000", "#FFCC00"]
+layout = "qwertz"
+quirks = "schip"
+
+[keymap]
+5 = ["space", "w"]
+
+[roms.da39a3ee5e6b4b0d3255bfef95601890afd80709]
+platform = "xochip"
+scale = 10
+quirks = { vf_reset = true }
+keymap = { F = "b" }
+"##;
+
+    #[test]
+    fn test_defaults() {
+        let config: Config = CONFIG.parse().unwrap();
+        let settings = config.settings(&[0x00, 0xE0], None);
+        assert_eq!(settings, config.defaults);
+        assert_eq!(settings.cycles, Some(700));
+        assert_eq!(settings.platform, None);
+        assert_eq!(settings.layout, Some(Layout::Qwertz));
+        let palette = settings.palette.unwrap();
+        assert_eq!(palette[1], [0xFF, 0xCC, 0x00]);
+        assert_eq!(palette[2..], DEFAULT_PALETTE[2..]);
+        assert_eq!(settings.quirks.resolve(Platform::Chip8), Quirks::SUPER_CHIP);
+        assert_eq!("".parse(), Ok(Config::default()));
+    }
+
+    #[test]
+    fn test_rom_overrides() {
+        // The section is keyed by the hash of the empty ROM
+        let config: Config = CONFIG.parse().unwrap();
+        let settings = config.settings(&[], None);
+        assert_eq!(settings.platform, Some(Platform::XoChip));
+        assert_eq!((settings.cycles, settings.scale), (Some(700), Some(10)));
+        let quirks = settings.quirks.resolve(Platform::XoChip);
+        assert_eq!(
+            quirks,
+            Quirks {
+                vf_reset: true,
+                ..Quirks::SUPER_CHIP
+            }
+        );
+
+        let mut keymap = Keymap::new(settings.layout.unwrap());
+        keymap.apply(&settings.keymap).unwrap();
+        assert_eq!(keymap.keys(0x5).count(), 2);
+        assert_eq!(keymap.keys(0xF).collect::<Vec<_>>(), ["b".parse().unwrap()]);
+
+        // Detected settings sit between the defaults and the section
+        let detected = Settings {
+            platform: Some(Platform::Chip8),
+            cycles: Some(900),
+            ..Settings::default()
+        };
+        let settings = config.settings(&[], Some(&detected));
+        assert_eq!(settings.platform, Some(Platform::XoChip));
+        assert_eq!(settings.cycles, Some(900));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |config: &str| config.parse::<Config>().unwrap_err();
+        assert_eq!(error("speed = 5"), "Unknown setting: speed");
+        assert_eq!(
+            error("platform = \"nes\""),
+            "platform: Unknown platform: nes"
+        );
+        assert_eq!(
+            error("cycles = -1"),
+            "cycles: Expected a positive integer, got -1"
+        );
+        assert!(error("palette = [\"#000\", \"#FFF\"]").starts_with("palette: "));
+        assert!(error("quirks = { wrap = true }").starts_with("quirks: Unknown quirk"));
+        assert!(error("[keymap]\nG = \"q\"").starts_with("keymap: Line 1"));
+        assert!(error("[roms.abc]\ncycles = 1").starts_with("roms.abc: "));
+        assert!("cycles = ".parse::<Config>().is_err());
+    }
+}
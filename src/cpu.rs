@@ -1,254 +1,650 @@
-use anyhow::{bail, Result};
-use rand::Rng;
+use core::{fmt, ops::Deref};
 
 use crate::{
-    emulator::{EmulatorState, FONT_OFFSET},
-    instruction::Instruction,
+    emulator::{EmulatorState, BIG_FONT_OFFSET, FONT_OFFSET},
+    instruction::Op,
+    platform::Platform,
+    quirks::Quirks,
+    random::Random,
+    savestate::{Persist, Reader, StateError, Writer},
 };
 
 pub type KeyState = [bool; 16];
 
+/// A fault raised while executing an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    /// The instruction at `address` is not part of the instruction set of the
+    /// selected [`Platform`].
+    UnknownOpcode { address: usize, opcode: u16 },
+    /// The instruction at `address` returned while the stack was empty.
+    StackUnderflow { address: usize },
+    /// The instruction at `address` called a subroutine while the stack was
+    /// full.
+    StackOverflow { address: usize },
+    /// The memory at `address` was accessed but only `size` bytes exist.
+    OutOfBounds { address: usize, size: usize },
+    /// A register other than V0 to VF was accessed.
+    InvalidRegister(u8),
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownOpcode { address, opcode } => {
+                write!(f, "Unknown instruction {:04X} at {:#05X}", opcode, address)
+            }
+            Self::StackUnderflow { address } => {
+                write!(f, "Popped from empty stack at {:#05X}", address)
+            }
+            Self::StackOverflow { address } => {
+                write!(f, "Pushed to full stack at {:#05X}", address)
+            }
+            Self::OutOfBounds { address, size } => {
+                write!(f, "Address out of bounds: {} >= {}", address, size)
+            }
+            Self::InvalidRegister(register) => write!(f, "Invalid register: {}", register),
+        }
+    }
+}
+
+impl core::error::Error for CpuError {}
+
+/// What the [`Cpu`] does when an instruction raises a [`CpuError`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FaultPolicy {
+    /// Stop executing with the pc pointing at the faulting instruction and
+    /// return the error.
+    #[default]
+    Halt,
+    /// Continue after the faulting instruction but still return the error so
+    /// it can be reported.
+    Skip,
+    /// Continue after the faulting instruction as if it was a no-op and
+    /// discard the error.
+    NoOp,
+}
+
+/// Which edge of a key press completes `FX0A`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyEdge {
+    /// The key was pressed and released again, like on the COSMAC VIP.
+    #[default]
+    Release,
+    /// The key was pressed.
+    Press,
+}
+
+/// The state of `FX0A` while it waits for a key. Keys held when the
+/// instruction executed only count once they were released and pressed again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct KeyWait {
+    register: u8,
+    // The bitmask of the keys held when the wait was last updated
+    held: u16,
+    // The bitmask of the keys pressed since the instruction executed
+    pressed: u16,
+}
+
+impl KeyWait {
+    fn new(register: u8, keys: &KeyState) -> Self {
+        Self {
+            register,
+            held: key_mask(keys),
+            pressed: 0,
+        }
+    }
+
+    /// Returns the key completing the wait if there is one in `keys`.
+    fn update(&mut self, keys: &KeyState, edge: KeyEdge) -> Option<u8> {
+        let held = key_mask(keys);
+        let (pressed, released) = (held & !self.held, self.held & !held);
+        self.held = held;
+        self.pressed |= pressed;
+        let completing = match edge {
+            KeyEdge::Press => pressed,
+            KeyEdge::Release => released & self.pressed,
+        };
+        (completing != 0).then(|| completing.trailing_zeros() as u8)
+    }
+}
+
+fn key_mask(keys: &KeyState) -> u16 {
+    (0..16).fold(0, |mask, key| mask | u16::from(keys[key]) << key)
+}
+
+/// The deepest call stack a [`Cpu`] can have.
+pub const MAX_STACK_DEPTH: usize = 16;
+
+/// The return addresses of the subroutine calls, stored in a fixed-size array
+/// so the cpu never allocates.
+#[derive(Debug, Clone)]
+pub struct Stack {
+    entries: [u16; MAX_STACK_DEPTH],
+    len: usize,
+}
+
+impl Stack {
+    /// Pushes `address` unless the stack already holds [`MAX_STACK_DEPTH`]
+    /// entries. Returns whether it was pushed.
+    pub fn push(&mut self, address: u16) -> bool {
+        if self.len == MAX_STACK_DEPTH {
+            return false;
+        }
+        self.entries[self.len] = address;
+        self.len += 1;
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<u16> {
+        self.len = self.len.checked_sub(1)?;
+        Some(self.entries[self.len])
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl Default for Stack {
+    fn default() -> Self {
+        Self {
+            entries: [0; MAX_STACK_DEPTH],
+            len: 0,
+        }
+    }
+}
+
+/// Derefs to the entries from the bottom to the top of the stack.
+impl Deref for Stack {
+    type Target = [u16];
+
+    fn deref(&self) -> &[u16] {
+        &self.entries[..self.len]
+    }
+}
+
+impl<'a> IntoIterator for &'a Stack {
+    type Item = &'a u16;
+    type IntoIter = core::slice::Iter<'a, u16>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl PartialEq for Stack {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Stack {}
+
 /// This struct plays the role of a cpu and executes CHIP-8 instructions.
-/// The fetching is done using [`Instruction::parse()`].
+/// The decoding is done using [`Op::decode()`].
 pub struct Cpu {
     registers: [u8; 16],
     pub pc: usize,
     pub i: u16,
-    pub stack: Vec<u16>,
+    pub stack: Stack,
+    stack_capacity: usize,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub fault_policy: FaultPolicy,
+    /// Which edge of a key press completes `FX0A`.
+    pub key_edge: KeyEdge,
+    /// Set once the program exits using `00FD` or faults with
+    /// [`FaultPolicy::Halt`].
+    pub halted: bool,
+    // Set by `DXYN` when the display wait quirk is enabled and cleared by the
+    // emulator on the next timer tick.
+    pub(crate) waiting_for_vblank: bool,
+    // Set by `FX0A` until a key completes it
+    key_wait: Option<KeyWait>,
 }
 
 impl Cpu {
     /// Creates a new [`Cpu`].
     ///
-    /// `stack_capacity` is used as **initial** capacity for the stack.
-    pub fn new(stack_capacity: usize) -> Self {
+    /// `stack_capacity` is the maximum depth of the stack.
+    /// `platform` selects the available instructions and `quirks` the
+    /// behaviour of instructions that differ between interpreters.
+    ///
+    /// # Panics
+    /// Panics if `stack_capacity` is bigger than [`MAX_STACK_DEPTH`].
+    pub fn new(stack_capacity: usize, platform: Platform, quirks: Quirks) -> Self {
+        assert!(
+            stack_capacity <= MAX_STACK_DEPTH,
+            "Stack capacity too big: {}",
+            stack_capacity
+        );
         Self {
             registers: [0u8; 16],
             pc: 0,
             i: 0,
-            stack: Vec::with_capacity(stack_capacity),
+            stack: Stack::default(),
+            stack_capacity,
+            platform,
+            quirks,
+            fault_policy: FaultPolicy::default(),
+            key_edge: KeyEdge::default(),
+            halted: false,
+            waiting_for_vblank: false,
+            key_wait: None,
         }
     }
 
+    /// Whether `FX0A` waits for a key, see [`Cpu::key_edge`].
+    pub fn waiting_for_key(&self) -> bool {
+        self.key_wait.is_some()
+    }
+
     /// Fetches and executes the next instruction.
-    pub fn execute(&mut self, state: &mut EmulatorState) -> Result<()> {
-        let instruction = Instruction::parse(state.ram.get(self.pc)?, state.ram.get(self.pc + 1)?);
+    ///
+    /// Does nothing while the cpu is waiting for a vertical blank (see
+    /// [`Quirks::display_wait`]) or after it has been halted. While `FX0A`
+    /// waits for a key only the key state is checked.
+    ///
+    /// `CXNN` takes its random numbers from `rng`.
+    ///
+    /// # Errors
+    /// Faults are handled according to the [`FaultPolicy`].
+    pub fn execute(
+        &mut self,
+        state: &mut EmulatorState,
+        rng: &mut dyn Random,
+    ) -> Result<(), CpuError> {
+        if self.waiting_for_vblank || self.halted {
+            return Ok(());
+        }
+        if let Some(wait) = &mut self.key_wait {
+            if let Some(key) = wait.update(&state.key_state, self.key_edge) {
+                self.registers[usize::from(wait.register)] = key;
+                self.key_wait = None;
+            }
+            return Ok(());
+        }
+
+        let address = self.pc;
+        match self.execute_instruction(state, rng) {
+            Ok(()) => Ok(()),
+            Err(error) => match self.fault_policy {
+                FaultPolicy::Halt => {
+                    self.pc = address;
+                    self.halted = true;
+                    Err(error)
+                }
+                FaultPolicy::Skip => {
+                    self.pc = address + 2;
+                    Err(error)
+                }
+                FaultPolicy::NoOp => {
+                    self.pc = address + 2;
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    fn execute_instruction(
+        &mut self,
+        state: &mut EmulatorState,
+        rng: &mut dyn Random,
+    ) -> Result<(), CpuError> {
+        let address = self.pc;
+        let (first, second) = (state.ram.get(self.pc)?, state.ram.get(self.pc + 1)?);
+        let unknown_opcode = CpuError::UnknownOpcode {
+            address,
+            opcode: u16::from_be_bytes([first, second]),
+        };
+        let op = Op::decode(first, second).map_err(|_| unknown_opcode)?;
+        if op.platform() > self.platform {
+            return Err(unknown_opcode);
+        }
 
         // Advance to the next instruction
         self.pc += 2;
 
-        let vx = self.get_register(instruction.x)?;
-        let vy = self.get_register(instruction.y)?;
-
-        match (
-            instruction.opcode,
-            instruction.x,
-            instruction.y,
-            instruction.n,
-        ) {
+        match op {
             // Clear screen
-            (0, 0, 0xE, 0) => state.frame_buffer = [[false; 32]; 64],
+            Op::Cls => state.frame_buffer.clear(),
             // Return
-            (0, 0, 0xE, 0xE) => {
+            Op::Ret => {
                 self.pc = self
                     .stack
                     .pop()
-                    .ok_or_else(|| anyhow::anyhow!("Popped from empty stack"))?
+                    .ok_or(CpuError::StackUnderflow { address })?
                     .into()
             }
+            // Scroll down N lines
+            Op::ScrollDown(n) => state.frame_buffer.scroll_down(n as usize),
+            // Scroll up N lines
+            Op::ScrollUp(n) => state.frame_buffer.scroll_up(n as usize),
+            // Scroll right 4 pixels
+            Op::ScrollRight => state.frame_buffer.scroll_right(4),
+            // Scroll left 4 pixels
+            Op::ScrollLeft => state.frame_buffer.scroll_left(4),
+            // Exit the interpreter
+            Op::Exit => {
+                self.pc -= 2;
+                self.halted = true;
+            }
+            // Switch to low resolution
+            Op::Low => state.frame_buffer.set_hires(false),
+            // Switch to high resolution
+            Op::High => state.frame_buffer.set_hires(true),
+            // Machine code routines can't be emulated
+            Op::Sys(_) => return Err(unknown_opcode),
             // Jump
-            (0x1, _, _, _) => {
-                self.pc = instruction.nnn as usize;
+            Op::Jp(addr) => {
+                self.pc = addr as usize;
             }
             // Call
-            (0x2, _, _, _) => {
+            Op::Call(addr) => {
+                if self.stack.len() >= self.stack_capacity {
+                    return Err(CpuError::StackOverflow { address });
+                }
                 self.stack.push(self.pc as u16);
-                self.pc = instruction.nnn.into();
+                self.pc = addr.into();
             }
             // Skip if VX equal to NN
-            (0x3, _, _, _) => {
-                if vx == instruction.nn {
-                    self.pc += 2
+            Op::SeVxByte { x, byte } => {
+                if self.get_register(x)? == byte {
+                    self.skip(state)?;
                 }
             }
             // Skip if VX NOT equal to NN
-            (0x4, _, _, _) => {
-                if vx != instruction.nn {
-                    self.pc += 2
+            Op::SneVxByte { x, byte } => {
+                if self.get_register(x)? != byte {
+                    self.skip(state)?;
                 }
             }
             // Skip if VX equal to VY
-            (0x5, _, _, 0x0) => {
-                if vx == vy {
-                    self.pc += 2
+            Op::SeVxVy { x, y } => {
+                if self.get_register(x)? == self.get_register(y)? {
+                    self.skip(state)?;
+                }
+            }
+            // Store VX to VY in memory starting at I
+            Op::SaveRange { x, y } => {
+                for (offset, register) in register_range(x, y).enumerate() {
+                    state
+                        .ram
+                        .set(self.i as usize + offset, self.get_register(register)?)?;
+                }
+            }
+            // Load VX to VY from memory starting at I
+            Op::LoadRange { x, y } => {
+                for (offset, register) in register_range(x, y).enumerate() {
+                    self.set_register(register, state.ram.get(self.i as usize + offset)?)?;
                 }
             }
             // Skip if VX NOT equal to VY
-            (0x9, _, _, 0x0) => {
-                if vx != vy {
-                    self.pc += 2
+            Op::SneVxVy { x, y } => {
+                if self.get_register(x)? != self.get_register(y)? {
+                    self.skip(state)?;
                 }
             }
             // Set register VX to NN
-            (0x6, _, _, _) => self.set_register(instruction.x, instruction.nn)?,
+            Op::LdVxByte { x, byte } => self.set_register(x, byte)?,
             // Add NN to VX
-            (0x7, _, _, _) => self.set_register(instruction.x, vx.wrapping_add(instruction.nn))?,
-
-            // TODO: Find out if I can write a macro for this to reduce code duplication
-
+            Op::AddVxByte { x, byte } => {
+                self.set_register(x, self.get_register(x)?.wrapping_add(byte))?
+            }
             // Set VX to VY
-            (0x8, _, _, 0x0) => self.set_register(instruction.x, vy)?,
+            Op::LdVxVy { x, y } => self.set_register(x, self.get_register(y)?)?,
             // Set VX to VX | VY
-            (0x8, _, _, 0x1) => self.set_register(instruction.x, vx | vy)?,
+            Op::Or { x, y } => {
+                self.set_register(x, self.get_register(x)? | self.get_register(y)?)?;
+                self.reset_flag_if_quirky();
+            }
             // Set VX to VX & VY
-            (0x8, _, _, 0x2) => self.set_register(instruction.x, vx & vy)?,
+            Op::And { x, y } => {
+                self.set_register(x, self.get_register(x)? & self.get_register(y)?)?;
+                self.reset_flag_if_quirky();
+            }
             // Set VX to VX ^ VY
-            (0x8, _, _, 0x3) => self.set_register(instruction.x, vx ^ vy)?,
+            Op::Xor { x, y } => {
+                self.set_register(x, self.get_register(x)? ^ self.get_register(y)?)?;
+                self.reset_flag_if_quirky();
+            }
             // Set VX to VX + VY (overflow -> carryflag)
-            (0x8, _, _, 0x4) => {
-                let (result, did_overflow) = vx.overflowing_add(vy);
-                self.set_register(instruction.x, result)?;
+            Op::AddVxVy { x, y } => {
+                let (result, did_overflow) =
+                    self.get_register(x)?.overflowing_add(self.get_register(y)?);
+                self.set_register(x, result)?;
                 self.set_carry_flag(did_overflow);
             }
             // Set VX to VX - VY (overflow -> carryflag)
-            (0x8, _, _, 0x5) => {
-                let (result, did_overflow) = vx.overflowing_sub(vy);
-                self.set_register(instruction.x, result)?;
+            Op::Sub { x, y } => {
+                let (result, did_overflow) =
+                    self.get_register(x)?.overflowing_sub(self.get_register(y)?);
+                self.set_register(x, result)?;
                 self.set_carry_flag(!did_overflow);
             }
             // Set VX to VY - VX (overflow -> carryflag)
-            (0x8, _, _, 0x7) => {
-                let (result, did_overflow) = vy.overflowing_sub(vx);
-                self.set_register(instruction.x, result)?;
+            Op::Subn { x, y } => {
+                let (result, did_overflow) =
+                    self.get_register(y)?.overflowing_sub(self.get_register(x)?);
+                self.set_register(x, result)?;
                 self.set_carry_flag(!did_overflow);
             }
-            // TODO: Make old vs. new behaviour configurable
-            // Here we are using the new behaviour
-
             // Shift right
-            (0x8, _, _, 0x6) => {
-                self.set_register(0xF, vy & 0b00000001)?;
-                self.set_register(instruction.x, vy >> 1)?;
+            Op::Shr { x, y } => {
+                let value = self.get_register(if self.quirks.shift_uses_vy { y } else { x })?;
+                self.set_register(x, value >> 1)?;
+                self.set_register(0xF, value & 0b00000001)?;
             }
             // Shift left
-            (0x8, _, _, 0xE) => {
-                self.set_register(0xF, vy & 0b10000000)?;
-                self.set_register(instruction.x, vy << 1)?;
+            Op::Shl { x, y } => {
+                let value = self.get_register(if self.quirks.shift_uses_vy { y } else { x })?;
+                self.set_register(x, value << 1)?;
+                self.set_register(0xF, value >> 7)?;
             }
 
             // Set I to NNN
-            (0xA, _, _, _) => self.i = instruction.nnn,
+            Op::LdI(addr) => self.i = addr,
 
-            // TODO: Make old vs. new behaviour configurable
-            // Here we are using the old behaviour
             // Jump with offset
-            (0xB, _, _, _) => self.pc = (instruction.nnn + self.get_register(0)? as u16) as usize,
+            Op::JpOffset(addr) => {
+                let register = if self.quirks.jump_uses_vx {
+                    (addr >> 8) as u8
+                } else {
+                    0
+                };
+                self.pc = (addr + self.get_register(register)? as u16) as usize;
+            }
 
             // RNG
-            (0xC, _, _, _) => self.set_register(instruction.x, rand::thread_rng().gen())?,
+            Op::Rnd { x, byte } => self.set_register(x, rng.next_byte() & byte)?,
 
             // Display
-            (0xD, _, _, _) => {
-                let pos_x = (vx % 64) as usize;
-                let pos_y = (vy % 32) as usize;
-
-                let mut did_change = false;
-
-                for row in 0..instruction.n {
-                    let y = pos_y + row as usize;
-                    if y >= 32 {
-                        break;
-                    }
-
-                    let sprite = state.ram.get(self.i as usize + row as usize)?;
-                    for col in 0..8 {
-                        let x = pos_x + col;
-                        if x >= 64 {
-                            break;
-                        }
-
-                        let screen_pixel = state.frame_buffer[x][y];
-                        let sprite_pixel = (sprite & (1 << (7 - col))) != 0;
-                        state.frame_buffer[x][y] = screen_pixel ^ sprite_pixel;
-                        did_change = did_change || (screen_pixel && sprite_pixel);
-                    }
-                }
-
-                self.set_register(0xF, if did_change { 1 } else { 0 })?;
+            Op::Drw { x, y, n } => {
+                self.draw(state, self.get_register(x)?, self.get_register(y)?, n)?
             }
 
             // Skip if pressed
-            (0xE, _, 0x9, 0xE) => {
-                if state.key_state[vx as usize] {
-                    self.pc += 2;
+            Op::Skp(x) => {
+                if state.key_state[self.get_register(x)? as usize & 0xF] {
+                    self.skip(state)?;
                 }
             }
             // Skip if NOT pressed
-            (0xE, _, 0xA, 0x1) => {
-                if !state.key_state[vx as usize] {
-                    self.pc += 2;
+            Op::Sknp(x) => {
+                if !state.key_state[self.get_register(x)? as usize & 0xF] {
+                    self.skip(state)?;
                 }
             }
 
+            // Set I to the 16 bit address following this instruction
+            Op::LdILong => {
+                self.i = u16::from(state.ram.get(self.pc)?) << 8
+                    | u16::from(state.ram.get(self.pc + 1)?);
+                self.pc += 2;
+            }
+            // Select the drawing planes
+            Op::Plane(n) => state.frame_buffer.select_planes(n),
+            // Load the audio pattern from I
+            Op::Audio => {
+                for (offset, byte) in state.audio_pattern.buffer.iter_mut().enumerate() {
+                    *byte = state.ram.get(self.i as usize + offset)?;
+                }
+            }
+            // Set the pitch register to VX
+            Op::Pitch(x) => state.audio_pattern.pitch = self.get_register(x)?,
+
             // Set VX to delay timer
-            (0xF, _, 0, 0x7) => self.set_register(instruction.x, state.delay_timer.get())?,
+            Op::LdVxDt(x) => self.set_register(x, state.delay_timer.get())?,
             // Set delay timer to VX
-            (0xF, _, 0x1, 0x5) => state.delay_timer.set(vx),
+            Op::LdDtVx(x) => state.delay_timer.set(self.get_register(x)?),
             // Set sound timer to VX
-            (0xF, _, 0x1, 0x8) => state.sound_timer.set(vx),
+            Op::LdStVx(x) => state.sound_timer.set(self.get_register(x)?),
 
             // Add VX to I
-            (0xF, _, 0x1, 0xE) => self.i = self.i.wrapping_add(vx.into()),
+            Op::AddIVx(x) => self.i = self.i.wrapping_add(self.get_register(x)?.into()),
 
             // Wait for key
-            (0xF, _, 0, 0xA) => {
-                if let Some((i, _)) = state.key_state.iter().enumerate().find(|(_, key)| **key) {
-                    self.set_register(instruction.x, i as u8)?;
-                } else {
-                    self.pc -= 2
-                }
+            Op::LdVxK(x) => {
+                is_valid_register(x)?;
+                self.key_wait = Some(KeyWait::new(x, &state.key_state));
             }
 
             // Get font character
-            (0xF, _, 0x2, 0x9) => self.i = FONT_OFFSET as u16 + (vx as u16 * 5),
+            Op::LdFVx(x) => self.i = FONT_OFFSET as u16 + (self.get_register(x)? as u16 * 5),
+            // Get big font character
+            Op::LdHfVx(x) => self.i = BIG_FONT_OFFSET as u16 + (self.get_register(x)? as u16 * 10),
 
             // Binary-coded decimal conversion
-            (0xF, _, 0x3, 0x3) => {
+            Op::LdBVx(x) => {
+                let vx = self.get_register(x)?;
                 state.ram.set(self.i as usize, vx / 100)?;
                 state.ram.set(self.i as usize + 1, (vx / 10) % 10)?;
                 state.ram.set(self.i as usize + 2, vx % 10)?;
             }
 
             // Store memory
-            (0xF, _, 0x5, 0x5) => {
-                for i in 0..instruction.x + 1 {
+            Op::StoreRegs(x) => {
+                for i in 0..x + 1 {
                     state
                         .ram
-                        .set((self.i + i as u16) as usize, self.get_register(i)?)?;
+                        .set(self.i as usize + i as usize, self.get_register(i)?)?;
+                }
+                if self.quirks.load_store_increments_i {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
                 }
             }
 
             // Load memory
-            (0xF, _, 0x6, 0x5) => {
-                for i in 0..instruction.x + 1 {
-                    self.set_register(i, state.ram.get((self.i + i as u16) as usize)?)?;
+            Op::LoadRegs(x) => {
+                for i in 0..x + 1 {
+                    self.set_register(i, state.ram.get(self.i as usize + i as usize)?)?;
+                }
+                if self.quirks.load_store_increments_i {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
                 }
             }
 
-            _ => panic!("Unknown instruction: {:?}", instruction),
+            // Store registers in the RPL user flags
+            Op::StoreFlags(x) => {
+                for i in 0..x + 1 {
+                    state.rpl_flags[i as usize] = self.get_register(i)?;
+                }
+            }
+
+            // Load registers from the RPL user flags
+            Op::LoadFlags(x) => {
+                for i in 0..x + 1 {
+                    self.set_register(i, state.rpl_flags[i as usize])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Skips the next instruction, taking the four byte long `F000 NNNN` into
+    /// account on XO-CHIP.
+    fn skip(&mut self, state: &EmulatorState) -> Result<(), CpuError> {
+        let is_long_load = self.platform.supports_xo_chip()
+            && state.ram.get(self.pc)? == 0xF0
+            && state.ram.get(self.pc + 1)? == 0x00;
+        self.pc += if is_long_load { 4 } else { 2 };
+        Ok(())
+    }
+
+    /// Draws the sprite at I to the position (`vx`, `vy`) in every selected
+    /// plane. `n` is the height of the sprite, or 0 for a 16x16 sprite on
+    /// SUPER-CHIP.
+    fn draw(&mut self, state: &mut EmulatorState, vx: u8, vy: u8, n: u8) -> Result<(), CpuError> {
+        let width = state.frame_buffer.width();
+        let height = state.frame_buffer.height();
+        let pos_x = vx as usize % width;
+        let pos_y = vy as usize % height;
+
+        // DXY0 draws a 16x16 sprite on SUPER-CHIP
+        let (sprite_width, sprite_height) = if n == 0 && self.platform.supports_super_chip() {
+            (16, 16)
+        } else {
+            (8, n as usize)
+        };
+
+        let mut did_change = false;
+        // The sprites for each selected plane are stored one after another
+        let mut address = self.i as usize;
+
+        for plane in [1, 2] {
+            if state.frame_buffer.planes() & plane == 0 {
+                continue;
+            }
+
+            for row in 0..sprite_height {
+                let mut y = pos_y + row;
+                if y >= height {
+                    if self.quirks.clip_sprites {
+                        break;
+                    }
+                    y %= height;
+                }
+
+                // The sprite row is left-aligned in 16 bits
+                let row_address = address + row * sprite_width / 8;
+                let mut sprite = u16::from(state.ram.get(row_address)?) << 8;
+                if sprite_width == 16 {
+                    sprite |= u16::from(state.ram.get(row_address + 1)?);
+                }
+
+                for col in 0..sprite_width {
+                    let mut x = pos_x + col;
+                    if x >= width {
+                        if self.quirks.clip_sprites {
+                            break;
+                        }
+                        x %= width;
+                    }
+
+                    if (sprite & (0x8000 >> col)) != 0 {
+                        did_change |= state.frame_buffer.toggle(x, y, plane);
+                    }
+                }
+            }
+
+            address += sprite_height * sprite_width / 8;
         }
+
+        self.set_register(0xF, if did_change { 1 } else { 0 })?;
+        self.waiting_for_vblank = self.quirks.display_wait;
         Ok(())
     }
 
     /// Sets the value of a cpu register
-    pub fn set_register(&mut self, register: u8, value: u8) -> Result<()> {
+    pub fn set_register(&mut self, register: u8, value: u8) -> Result<(), CpuError> {
         is_valid_register(register)?;
         self.registers[register as usize] = value;
         Ok(())
     }
 
     /// Gets the value of a cpu register
-    pub fn get_register(&self, register: u8) -> Result<u8> {
+    pub fn get_register(&self, register: u8) -> Result<u8, CpuError> {
         is_valid_register(register)?;
         Ok(self.registers[register as usize])
     }
@@ -256,18 +652,398 @@ impl Cpu {
     pub fn set_carry_flag(&mut self, flag: bool) {
         self.registers[0xF] = if flag { 1 } else { 0 };
     }
+
+    /// Resets VF after a logical operation if [`Quirks::vf_reset`] is set.
+    fn reset_flag_if_quirky(&mut self) {
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
+    }
 }
 
 impl Default for Cpu {
     fn default() -> Self {
-        Self::new(16)
+        Self::new(16, Platform::default(), Quirks::default())
+    }
+}
+
+impl Persist for FaultPolicy {
+    fn save(&self, out: &mut Writer) {
+        out.u8(*self as u8);
+    }
+
+    fn restore(input: &mut Reader) -> Result<Self, StateError> {
+        match input.u8()? {
+            0 => Ok(Self::Halt),
+            1 => Ok(Self::Skip),
+            2 => Ok(Self::NoOp),
+            _ => Err(StateError::Invalid("fault policy")),
+        }
+    }
+}
+
+impl Persist for KeyEdge {
+    fn save(&self, out: &mut Writer) {
+        out.u8(*self as u8);
+    }
+
+    fn restore(input: &mut Reader) -> Result<Self, StateError> {
+        match input.u8()? {
+            0 => Ok(Self::Release),
+            1 => Ok(Self::Press),
+            _ => Err(StateError::Invalid("key edge")),
+        }
+    }
+}
+
+/// The [`FaultPolicy`] is not part of a save state, it is configured by the
+/// front-end.
+impl Persist for Cpu {
+    fn save(&self, out: &mut Writer) {
+        self.platform.save(out);
+        self.quirks.save(out);
+        out.bytes(&self.registers);
+        out.u32(self.pc as u32);
+        out.u16(self.i);
+        out.u16(self.stack_capacity as u16);
+        out.u16(self.stack.len() as u16);
+        for address in &self.stack {
+            out.u16(*address);
+        }
+        out.bool(self.halted);
+        out.bool(self.waiting_for_vblank);
+        out.bool(self.key_wait.is_some());
+        if let Some(wait) = &self.key_wait {
+            out.u8(wait.register);
+            out.u16(wait.held);
+            out.u16(wait.pressed);
+        }
+    }
+
+    fn restore(input: &mut Reader) -> Result<Self, StateError> {
+        let platform = Platform::restore(input)?;
+        let quirks = Quirks::restore(input)?;
+        let registers = input.array()?;
+        let pc = input.u32()? as usize;
+        let i = input.u16()?;
+        let capacity = input.u16()?.into();
+        if capacity > MAX_STACK_DEPTH {
+            return Err(StateError::Invalid("stack capacity"));
+        }
+        let mut cpu = Self::new(capacity, platform, quirks);
+        cpu.registers = registers;
+        cpu.pc = pc;
+        cpu.i = i;
+
+        let depth = input.u16()?.into();
+        if depth > cpu.stack_capacity {
+            return Err(StateError::Invalid("stack depth"));
+        }
+        for _ in 0..depth {
+            cpu.stack.push(input.u16()?);
+        }
+        cpu.halted = input.bool()?;
+        cpu.waiting_for_vblank = input.bool()?;
+        if input.bool()? {
+            let register = input.u8()?;
+            if register >= 16 {
+                return Err(StateError::Invalid("register"));
+            }
+            cpu.key_wait = Some(KeyWait {
+                register,
+                held: input.u16()?,
+                pressed: input.u16()?,
+            });
+        }
+        Ok(cpu)
     }
 }
 
-fn is_valid_register(register: u8) -> Result<()> {
+/// Iterates from register `x` to register `y`, both inclusive, in either
+/// direction.
+fn register_range(x: u8, y: u8) -> impl Iterator<Item = u8> {
+    let count = x.abs_diff(y);
+    (0..=count).map(move |offset| if x <= y { x + offset } else { x - offset })
+}
+
+fn is_valid_register(register: u8) -> Result<(), CpuError> {
     if register >= 16 {
-        bail!("Invalid register: {}", register)
+        Err(CpuError::InvalidRegister(register))
     } else {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audio::AudioPattern, display::FrameBuffer, ram::Ram, random::SplitMix64, timer::Timer,
+    };
+
+    fn run(quirks: Quirks, program: &[u8], steps: usize) -> (Cpu, EmulatorState) {
+        run_on(Platform::Chip8, quirks, program, steps)
+    }
+
+    fn run_on(
+        platform: Platform,
+        quirks: Quirks,
+        program: &[u8],
+        steps: usize,
+    ) -> (Cpu, EmulatorState) {
+        let mut state = EmulatorState {
+            ram: Ram::new(platform.ram_size()),
+            sound_timer: Timer::default(),
+            delay_timer: Timer::default(),
+            frame_buffer: FrameBuffer::default(),
+            key_state: [false; 16],
+            rpl_flags: [0; 16],
+            audio_pattern: AudioPattern::default(),
+        };
+        for (i, byte) in program.iter().enumerate() {
+            state.ram.set(0x200 + i, *byte).unwrap();
+        }
+        let mut cpu = Cpu::new(16, platform, quirks);
+        cpu.pc = 0x200;
+        for _ in 0..steps {
+            cpu.execute(&mut state, &mut SplitMix64::new(0)).unwrap();
+        }
+        (cpu, state)
+    }
+
+    #[test]
+    fn test_shift_quirk() {
+        // V0 = 0x01, V1 = 0x80, V0 = V1 << 1
+        let program = [0x60, 0x01, 0x61, 0x80, 0x80, 0x1E];
+        let (cpu, _) = run(Quirks::COSMAC_VIP, &program, 3);
+        assert_eq!(cpu.get_register(0).unwrap(), 0x00);
+        assert_eq!(cpu.get_register(0xF).unwrap(), 1);
+
+        let (cpu, _) = run(Quirks::SUPER_CHIP, &program, 3);
+        assert_eq!(cpu.get_register(0).unwrap(), 0x02);
+        assert_eq!(cpu.get_register(0xF).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_jump_quirk() {
+        // V0 = 0x10, V3 = 0x20, jump to 0x300 + V0 or V3
+        let program = [0x60, 0x10, 0x63, 0x20, 0xB3, 0x00];
+        let (cpu, _) = run(Quirks::COSMAC_VIP, &program, 3);
+        assert_eq!(cpu.pc, 0x310);
+
+        let (cpu, _) = run(Quirks::CHIP_48, &program, 3);
+        assert_eq!(cpu.pc, 0x320);
+    }
+
+    #[test]
+    fn test_vf_reset_quirk() {
+        // VF = 0x05, V0 |= V1
+        let program = [0x6F, 0x05, 0x80, 0x11];
+        let (cpu, _) = run(Quirks::COSMAC_VIP, &program, 2);
+        assert_eq!(cpu.get_register(0xF).unwrap(), 0);
+
+        let (cpu, _) = run(Quirks::SUPER_CHIP, &program, 2);
+        assert_eq!(cpu.get_register(0xF).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_load_store_quirk() {
+        // I = 0x300, store V0..V2
+        let program = [0xA3, 0x00, 0xF2, 0x55];
+        let (cpu, _) = run(Quirks::COSMAC_VIP, &program, 2);
+        assert_eq!(cpu.i, 0x303);
+
+        let (cpu, _) = run(Quirks::SUPER_CHIP, &program, 2);
+        assert_eq!(cpu.i, 0x300);
+    }
+
+    #[test]
+    fn test_clip_quirk() {
+        // V0 = 31, V1 = 63, I = 0x20A, draw a 8x2 sprite at (V1, V0)
+        let program = [
+            0x60, 0x1F, 0x61, 0x3F, 0xA2, 0x0A, 0xD1, 0x02, 0x00, 0x00, 0xFF, 0xFF,
+        ];
+        let (_, state) = run(Quirks::COSMAC_VIP, &program, 4);
+        assert_eq!(state.frame_buffer.get(63, 31), 1);
+        assert_eq!(state.frame_buffer.get(0, 31), 0);
+
+        let (_, state) = run(Quirks::XO_CHIP, &program, 4);
+        assert_eq!(state.frame_buffer.get(0, 0), 1);
+        assert_eq!(state.frame_buffer.get(0, 31), 1);
+    }
+
+    #[test]
+    fn test_display_wait_quirk() {
+        let program = [0xD0, 0x01, 0x60, 0x01];
+        let (cpu, _) = run(Quirks::COSMAC_VIP, &program, 2);
+        assert_eq!(cpu.pc, 0x202);
+
+        let (cpu, _) = run(Quirks::XO_CHIP, &program, 2);
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn test_hires_sprite() {
+        // Switch to hires, V0 = 120, draw a 16x16 sprite at (V0, V0)
+        let mut program = vec![0x00, 0xFF, 0x60, 0x78, 0xA2, 0x08, 0xD0, 0x00];
+        program.extend([0xFF; 32]);
+        let (cpu, state) = run_on(Platform::SuperChip, Quirks::SUPER_CHIP, &program, 4);
+        assert!(state.frame_buffer.is_hires());
+        assert_eq!(state.frame_buffer.get(127, 56), 1);
+        assert_eq!(state.frame_buffer.get(120, 63), 1);
+        assert_eq!(state.frame_buffer.get(119, 56), 0);
+        assert_eq!(cpu.get_register(0xF).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_rpl_flags() {
+        // V0 = 1, V1 = 2, save V0..V1, clear V0..V1, load V0..V1
+        let program = [
+            0x60, 0x01, 0x61, 0x02, 0xF1, 0x75, 0x60, 0x00, 0x61, 0x00, 0xF1, 0x85,
+        ];
+        let (cpu, state) = run_on(Platform::SuperChip, Quirks::SUPER_CHIP, &program, 6);
+        assert_eq!(state.rpl_flags[..2], [1, 2]);
+        assert_eq!(cpu.get_register(0).unwrap(), 1);
+        assert_eq!(cpu.get_register(1).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_exit() {
+        let program = [0x00, 0xFD, 0x60, 0x01];
+        let (cpu, _) = run_on(Platform::SuperChip, Quirks::SUPER_CHIP, &program, 2);
+        assert!(cpu.halted);
+        assert_eq!(cpu.get_register(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_long_load() {
+        // Skip the long load, then perform it
+        let program = [0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0xF0, 0x00, 0xAB, 0xCD];
+        let (cpu, _) = run_on(Platform::XoChip, Quirks::XO_CHIP, &program, 2);
+        assert_eq!(cpu.i, 0xABCD);
+        assert_eq!(cpu.pc, 0x20A);
+    }
+
+    #[test]
+    fn test_register_range() {
+        // V1 = 1, V2 = 2, V3 = 3, I = 0x300, store V3..V1, load V1..V2 swapped
+        let program = [
+            0x61, 0x01, 0x62, 0x02, 0x63, 0x03, 0xA3, 0x00, 0x53, 0x12, 0x52, 0x13,
+        ];
+        let (cpu, state) = run_on(Platform::XoChip, Quirks::XO_CHIP, &program, 6);
+        assert_eq!(state.ram.get_slice(0x300, 3).unwrap(), [3, 2, 1]);
+        assert_eq!(cpu.i, 0x300);
+        assert_eq!(cpu.get_register(1).unwrap(), 2);
+        assert_eq!(cpu.get_register(2).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_draw_both_planes() {
+        // Select both planes, I = 0x208, draw 8x1 sprites to both planes
+        let program = [0xF3, 0x01, 0xA2, 0x08, 0xD0, 0x01, 0x00, 0x00, 0x80, 0xC0];
+        let (_, state) = run_on(Platform::XoChip, Quirks::XO_CHIP, &program, 3);
+        assert_eq!(state.frame_buffer.get(0, 0), 0b11);
+        assert_eq!(state.frame_buffer.get(1, 0), 0b10);
+    }
+
+    #[test]
+    fn test_audio_registers() {
+        // I = 0x208, load the pattern, V0 = 100, set the pitch
+        let mut program = vec![0xA2, 0x08, 0xF0, 0x02, 0x60, 0x64, 0xF0, 0x3A];
+        program.extend([0xAA; 16]);
+        let (_, state) = run_on(Platform::XoChip, Quirks::XO_CHIP, &program, 4);
+        assert_eq!(state.audio_pattern.buffer, [0xAA; 16]);
+        assert_eq!(state.audio_pattern.pitch, 100);
+    }
+
+    #[test]
+    fn test_random_numbers() {
+        // Every instruction gets a generator with the same seed
+        let (cpu, _) = run(Quirks::default(), &[0xC0, 0xFF, 0xC1, 0x0F], 2);
+        let byte = SplitMix64::new(0).next_byte();
+        assert_eq!(cpu.get_register(0).unwrap(), byte);
+        assert_eq!(cpu.get_register(1).unwrap(), byte & 0x0F);
+    }
+
+    #[test]
+    fn test_unknown_opcode_policies() {
+        let program = [0xFF, 0xFF, 0x60, 0x01];
+
+        let (mut cpu, mut state) = run(Quirks::default(), &program, 0);
+        assert_eq!(
+            cpu.execute(&mut state, &mut SplitMix64::new(0)),
+            Err(CpuError::UnknownOpcode {
+                address: 0x200,
+                opcode: 0xFFFF
+            })
+        );
+        assert!(cpu.halted);
+        assert_eq!(cpu.pc, 0x200);
+
+        let (mut cpu, mut state) = run(Quirks::default(), &program, 0);
+        cpu.fault_policy = FaultPolicy::Skip;
+        assert!(cpu.execute(&mut state, &mut SplitMix64::new(0)).is_err());
+        assert!(cpu.execute(&mut state, &mut SplitMix64::new(0)).is_ok());
+        assert_eq!(cpu.get_register(0).unwrap(), 1);
+
+        let (mut cpu, mut state) = run(Quirks::default(), &program, 0);
+        cpu.fault_policy = FaultPolicy::NoOp;
+        assert!(cpu.execute(&mut state, &mut SplitMix64::new(0)).is_ok());
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn test_wait_for_key() {
+        // Wait for a key in V3, V0 = 1
+        let program = [0xF3, 0x0A, 0x60, 0x01];
+        let (mut cpu, mut state) = run(Quirks::default(), &program, 0);
+        let mut step = |cpu: &mut Cpu, keys: &[usize]| {
+            state.key_state = core::array::from_fn(|key| keys.contains(&key));
+            cpu.execute(&mut state, &mut SplitMix64::new(0)).unwrap();
+        };
+
+        // Held from before, so it has to be released and pressed again
+        step(&mut cpu, &[7]);
+        assert!(cpu.waiting_for_key());
+        step(&mut cpu, &[]);
+        step(&mut cpu, &[7, 2]);
+        step(&mut cpu, &[7, 2]);
+        assert!(cpu.waiting_for_key());
+        step(&mut cpu, &[7]);
+        assert!(!cpu.waiting_for_key());
+        assert_eq!(cpu.get_register(3).unwrap(), 2);
+        assert_eq!(cpu.get_register(0).unwrap(), 0);
+        step(&mut cpu, &[7]);
+        assert_eq!(cpu.get_register(0).unwrap(), 1);
+
+        let (mut cpu, mut state) = run(Quirks::default(), &program, 0);
+        cpu.key_edge = KeyEdge::Press;
+        let mut step = |cpu: &mut Cpu, keys: &[usize]| {
+            state.key_state = core::array::from_fn(|key| keys.contains(&key));
+            cpu.execute(&mut state, &mut SplitMix64::new(0)).unwrap();
+        };
+        step(&mut cpu, &[4]);
+        step(&mut cpu, &[4]);
+        assert!(cpu.waiting_for_key());
+        step(&mut cpu, &[4, 0xB]);
+        assert!(!cpu.waiting_for_key());
+        assert_eq!(cpu.get_register(3).unwrap(), 0xB);
+    }
+
+    #[test]
+    fn test_stack_faults() {
+        let (mut cpu, mut state) = run(Quirks::default(), &[0x00, 0xEE], 0);
+        assert_eq!(
+            cpu.execute(&mut state, &mut SplitMix64::new(0)),
+            Err(CpuError::StackUnderflow { address: 0x200 })
+        );
+
+        // Calls itself until the stack is full
+        let (mut cpu, mut state) = run(Quirks::default(), &[0x22, 0x00], 16);
+        assert_eq!(cpu.stack.len(), 16);
+        assert_eq!(
+            cpu.execute(&mut state, &mut SplitMix64::new(0)),
+            Err(CpuError::StackOverflow { address: 0x200 })
+        );
+    }
+}
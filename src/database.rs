@@ -0,0 +1,381 @@
+//! Lookup of ROMs in a local copy of the
+//! [CHIP-8 database](https://github.com/chip-8/chip-8-database).
+//!
+//! The database consists of `programs.json`, a list of programs with the
+//! known versions of their ROMs, and `sha1-hashes.json`, which maps the SHA-1
+//! hash of every ROM to the index of its program. A ROM lists the platforms it
+//! runs on, best first, and may change the quirks of some of them, set the
+//! number of instructions per frame (its tickrate), the colours and which keys
+//! do what.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde_json::{Map, Value};
+
+use crate::{
+    config::{self, QuirkSettings, Settings},
+    display::Palette,
+    emulator::FRAME_RATE,
+    movie::rom_hash_hex,
+    platform::Platform,
+    quirks::Quirks,
+};
+
+/// What the database knows about a ROM.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RomInfo {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub release: Option<String>,
+    /// The identifiers of the platforms the ROM runs on as listed in the
+    /// database, best first.
+    pub platforms: Vec<String>,
+    /// The best of the platforms that can be emulated and its quirks.
+    pub platform: Option<(Platform, Quirks)>,
+    /// How many instructions are executed per frame.
+    pub tickrate: Option<u32>,
+    pub palette: Option<Palette>,
+    /// What the CHIP-8 keys do, e.g. `("left", 7)`.
+    pub keys: Vec<(String, u8)>,
+}
+
+impl RomInfo {
+    /// The settings to run the ROM with, see
+    /// [`Config::settings`](config::Config::settings).
+    pub fn settings(&self) -> Settings {
+        Settings {
+            platform: self.platform.map(|(platform, _)| platform),
+            quirks: QuirkSettings {
+                preset: self.platform.map(|(_, quirks)| quirks),
+                ..QuirkSettings::default()
+            },
+            // Parsing rejects tickrates for which this overflows
+            cycles: self.tickrate.map(|tickrate| tickrate * FRAME_RATE),
+            palette: self.palette,
+            ..Settings::default()
+        }
+    }
+
+    fn parse(program: &Value, rom: &Value) -> Result<Self, String> {
+        let strings = |value: Option<&Value>| -> Vec<String> {
+            value
+                .and_then(Value::as_array)
+                .map_or_else(Vec::new, |values| {
+                    values
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .map(String::from)
+                        .collect()
+                })
+        };
+        let string = |key| {
+            rom.get(key)
+                .or_else(|| program.get(key))
+                .and_then(Value::as_str)
+                .map(String::from)
+        };
+        let mut info = Self {
+            title: string("title").unwrap_or_default(),
+            authors: strings(rom.get("authors").or_else(|| program.get("authors"))),
+            release: string("release"),
+            platforms: strings(rom.get("platforms")),
+            ..Self::default()
+        };
+
+        info.platform = info.platforms.iter().find_map(|id| {
+            let (platform, mut quirks) = platform(id)?;
+            if let Some(changes) = rom.pointer(&format!("/quirkyPlatforms/{}", id)) {
+                change_quirks(&mut quirks, changes);
+            }
+            Some((platform, quirks))
+        });
+        if let Some(value) = rom.get("tickrate") {
+            info.tickrate = Some(tickrate(value)?);
+        }
+        if let Some(pixels) = rom.pointer("/colors/pixels") {
+            let colors = pixels
+                .as_array()
+                .and_then(|colors| colors.iter().map(Value::as_str).collect::<Option<Vec<_>>>())
+                .ok_or_else(|| format!("Invalid colours: {}", pixels))?;
+            info.palette = Some(config::palette(&colors)?);
+        }
+        if let Some(keys) = rom.get("keys").and_then(Value::as_object) {
+            info.keys = keys
+                .iter()
+                .filter_map(|(action, key)| {
+                    let key = key.as_u64().filter(|key| *key < 16)?;
+                    Some((action.clone(), key as u8))
+                })
+                .collect();
+        }
+        Ok(info)
+    }
+}
+
+impl fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Title:     {}", self.title)?;
+        if !self.authors.is_empty() {
+            writeln!(f, "Authors:   {}", self.authors.join(", "))?;
+        }
+        if let Some(release) = &self.release {
+            writeln!(f, "Release:   {}", release)?;
+        }
+        writeln!(f, "Platforms: {}", self.platforms.join(", "))?;
+        match self.platform {
+            Some((platform, quirks)) => {
+                writeln!(f, "Platform:  {}", platform)?;
+                writeln!(f, "Quirks:    {}", quirk_names(&quirks))?;
+            }
+            None => writeln!(f, "Platform:  none of them is supported")?,
+        }
+        if let Some(tickrate) = self.tickrate {
+            writeln!(f, "Tickrate:  {} instructions per frame", tickrate)?;
+        }
+        if let Some(palette) = self.palette {
+            let colors: Vec<_> = palette
+                .iter()
+                .map(|[r, g, b]| format!("#{:02X}{:02X}{:02X}", r, g, b))
+                .collect();
+            writeln!(f, "Colours:   {}", colors.join(" "))?;
+        }
+        if !self.keys.is_empty() {
+            let keys: Vec<_> = self
+                .keys
+                .iter()
+                .map(|(action, key)| format!("{} {:X}", action, key))
+                .collect();
+            writeln!(f, "Keys:      {}", keys.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// The quirks that are enabled, separated by commas.
+fn quirk_names(quirks: &Quirks) -> String {
+    let names: Vec<_> = [
+        ("shift_uses_vy", quirks.shift_uses_vy),
+        ("jump_uses_vx", quirks.jump_uses_vx),
+        ("load_store_increments_i", quirks.load_store_increments_i),
+        ("vf_reset", quirks.vf_reset),
+        ("clip_sprites", quirks.clip_sprites),
+        ("display_wait", quirks.display_wait),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(name, _)| name)
+    .collect();
+    if names.is_empty() {
+        "none".into()
+    } else {
+        names.join(", ")
+    }
+}
+
+/// The emulated platform and quirks matching a platform of the database.
+fn platform(id: &str) -> Option<(Platform, Quirks)> {
+    match id {
+        "originalChip8" | "hybridVIP" => Some((Platform::Chip8, Quirks::COSMAC_VIP)),
+        // CHIP-8 as most modern interpreters implement it
+        "modernChip8" => Some((
+            Platform::Chip8,
+            Quirks {
+                vf_reset: false,
+                display_wait: false,
+                ..Quirks::COSMAC_VIP
+            },
+        )),
+        "chip48" => Some((Platform::Chip8, Quirks::CHIP_48)),
+        // SUPER-CHIP 1.0 still increments I like CHIP-48
+        "superchip1" => Some((Platform::SuperChip, Quirks::CHIP_48)),
+        "superchip" => Some((Platform::SuperChip, Quirks::SUPER_CHIP)),
+        "xochip" => Some((Platform::XoChip, Quirks::XO_CHIP)),
+        _ => None,
+    }
+}
+
+/// Applies the quirks of the database in `changes` to `quirks`. Quirks that
+/// can't be emulated, like `memoryIncrementByX`, are ignored.
+fn change_quirks(quirks: &mut Quirks, changes: &Value) {
+    let Some(changes) = changes.as_object() else {
+        return;
+    };
+    for (name, enabled) in changes {
+        let Some(enabled) = enabled.as_bool() else {
+            continue;
+        };
+        match name.as_str() {
+            "shift" => quirks.shift_uses_vy = !enabled,
+            "memoryLeaveIUnchanged" => quirks.load_store_increments_i = !enabled,
+            "wrap" => quirks.clip_sprites = !enabled,
+            "jump" => quirks.jump_uses_vx = enabled,
+            "vblank" => quirks.display_wait = enabled,
+            "logic" => quirks.vf_reset = enabled,
+            _ => {}
+        }
+    }
+}
+
+/// Parses a tickrate, which has to be positive and small enough that the
+/// instructions per second fit into a `u32`.
+pub(crate) fn tickrate(value: &Value) -> Result<u32, String> {
+    value
+        .as_u64()
+        .and_then(|tickrate| u32::try_from(tickrate).ok())
+        .filter(|&tickrate| tickrate > 0 && tickrate.checked_mul(FRAME_RATE).is_some())
+        .ok_or_else(|| format!("Invalid tickrate: {}", value))
+}
+
+/// The contents of the database.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Database {
+    programs: Vec<Value>,
+    hashes: Map<String, Value>,
+}
+
+impl Database {
+    /// Where the database is looked up, `chip-8/database` in the data
+    /// directory of the user. `None` if the system has no such directory.
+    pub fn dir() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("chip-8").join("database"))
+    }
+
+    /// Reads `programs.json` and `sha1-hashes.json` in `dir`. A missing
+    /// database is the same as an empty one.
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let read = |name| {
+            let path = dir.join(name);
+            fs::read_to_string(&path).map_err(|error| (path, error))
+        };
+        match (read("programs.json"), read("sha1-hashes.json")) {
+            (Ok(programs), Ok(hashes)) => Self::from_json(&programs, &hashes)
+                .map_err(|error| format!("{}: {}", dir.display(), error)),
+            (Err((_, error)), _) | (_, Err((_, error)))
+                if error.kind() == io::ErrorKind::NotFound =>
+            {
+                Ok(Self::default())
+            }
+            (Err((path, error)), _) | (_, Err((path, error))) => {
+                Err(format!("{}: {}", path.display(), error))
+            }
+        }
+    }
+
+    /// Parses the contents of `programs.json` and `sha1-hashes.json`.
+    pub fn from_json(programs: &str, hashes: &str) -> Result<Self, String> {
+        let programs = serde_json::from_str::<Value>(programs)
+            .map_err(|error| format!("programs.json: {}", error))?;
+        let hashes = serde_json::from_str::<Value>(hashes)
+            .map_err(|error| format!("sha1-hashes.json: {}", error))?;
+        match (programs, hashes) {
+            (Value::Array(programs), Value::Object(hashes)) => Ok(Self { programs, hashes }),
+            _ => Err("Expected a list of programs and a map of hashes".into()),
+        }
+    }
+
+    /// Looks `rom` up by its hash.
+    ///
+    /// # Errors
+    /// Returns an error if the entry of the ROM is invalid.
+    pub fn lookup(&self, rom: &[u8]) -> Result<Option<RomInfo>, String> {
+        let hash = rom_hash_hex(rom);
+        let Some(index) = self.hashes.get(&hash) else {
+            return Ok(None);
+        };
+        let program = index
+            .as_u64()
+            .and_then(|index| self.programs.get(usize::try_from(index).ok()?))
+            .ok_or_else(|| format!("{}: No program {}", hash, index))?;
+        let rom = program
+            .pointer(&format!("/roms/{}", hash))
+            .ok_or_else(|| format!("{}: Missing in its program", hash))?;
+        RomInfo::parse(program, rom)
+            .map(Some)
+            .map_err(|error| format!("{}: {}", hash, error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The entry of the empty ROM
+    const PROGRAMS: &str = r##"[
+        {
+            "title": "Nothing",
+            "authors": ["Nobody"],
+            "release": "2024",
+            "roms": {
+                "da39a3ee5e6b4b0d3255bfef95601890afd80709": {
+                    "file": "nothing.ch8",
+                    "platforms": ["megachip8", "superchip", "xochip"],
+                    "quirkyPlatforms": {
+                        "superchip": {"wrap": true, "memoryLeaveIUnchanged": false}
+                    },
+                    "tickrate": 30,
+                    "colors": {"pixels": ["#102030", "#FFFFFF"], "buzzer": "#FF0000"},
+                    "keys": {"left": 7, "right": 9}
+                }
+            }
+        }
+    ]"##;
+    const HASHES: &str = r#"{"da39a3ee5e6b4b0d3255bfef95601890afd80709": 0}"#;
+
+    #[test]
+    fn test_lookup() {
+        let database = Database::from_json(PROGRAMS, HASHES).unwrap();
+        let info = database.lookup(&[]).unwrap().unwrap();
+        assert_eq!(info.title, "Nothing");
+        assert_eq!(info.authors, ["Nobody"]);
+        assert_eq!(info.platforms.len(), 3);
+        // MegaChip can't be emulated
+        let quirks = Quirks {
+            clip_sprites: false,
+            load_store_increments_i: true,
+            ..Quirks::SUPER_CHIP
+        };
+        assert_eq!(info.platform, Some((Platform::SuperChip, quirks)));
+        assert_eq!(info.palette.unwrap()[..2], [[0x10, 0x20, 0x30], [0xFF; 3]]);
+        assert_eq!(info.keys, [("left".into(), 7), ("right".into(), 9)]);
+
+        let settings = info.settings();
+        assert_eq!(settings.cycles, Some(1800));
+        assert_eq!(settings.quirks.resolve(Platform::Chip8), quirks);
+
+        assert_eq!(database.lookup(&[0x12, 0x00]), Ok(None));
+    }
+
+    #[test]
+    fn test_invalid_entries() {
+        let database = Database::from_json(
+            r#"[{"roms": {"da39a3ee5e6b4b0d3255bfef95601890afd80709": {"tickrate": 0}}}]"#,
+            r#"{"da39a3ee5e6b4b0d3255bfef95601890afd80709": 0}"#,
+        )
+        .unwrap();
+        assert!(database
+            .lookup(&[])
+            .unwrap_err()
+            .ends_with("Invalid tickrate: 0"));
+        let database = Database::from_json(
+            r#"[{"roms": {"da39a3ee5e6b4b0d3255bfef95601890afd80709": {"tickrate": 100000000}}}]"#,
+            r#"{"da39a3ee5e6b4b0d3255bfef95601890afd80709": 0}"#,
+        )
+        .unwrap();
+        assert!(database
+            .lookup(&[])
+            .unwrap_err()
+            .ends_with("Invalid tickrate: 100000000"));
+        let database =
+            Database::from_json("[]", r#"{"da39a3ee5e6b4b0d3255bfef95601890afd80709": 3}"#)
+                .unwrap();
+        assert!(database.lookup(&[]).is_err());
+        assert!(Database::from_json("{}", "{}").is_err());
+        assert_eq!(
+            Database::load(Path::new("/nonexistent")),
+            Ok(Database::default())
+        );
+    }
+}
@@ -0,0 +1,862 @@
+//! An interactive debugger that controls how an [`Emulator`] is stepped.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Write},
+    ops::RangeInclusive,
+    str::FromStr,
+};
+
+use crate::{
+    cpu::Cpu,
+    disasm::{format_op, Syntax},
+    display::Render,
+    emulator::{Emulator, EmulatorError, EmulatorState},
+    instruction::Op,
+    ram::WatchHit,
+};
+
+/// How many instructions are shown before and after the pc.
+const CONTEXT: usize = 4;
+
+/// A command given to the [`Debugger`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Run until a breakpoint is hit.
+    Continue,
+    /// Stop running.
+    Pause,
+    /// Execute a single instruction.
+    Step,
+    /// Execute a single instruction, running called subroutines to
+    /// completion.
+    StepOver,
+    /// Run until the current subroutine returns.
+    StepOut,
+    /// Stop when the pc reaches an address.
+    Break(usize),
+    /// Remove the breakpoint at an address.
+    Delete(usize),
+    /// Stop when an address in the range is written.
+    Watch(RangeInclusive<usize>),
+    /// Remove a watched range.
+    Unwatch(RangeInclusive<usize>),
+    /// Stop when the condition becomes true.
+    BreakIf(Condition),
+    /// Remove all conditions.
+    ClearConditions,
+    /// Show the state of the emulator.
+    Info,
+    /// Show the available commands.
+    Help,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    /// Parses a command typed into a console, e.g. `s` or `b 0x2A0`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let parse_address = |word: &str| {
+            let digits = word.trim_start_matches("0x").trim_start_matches("0X");
+            usize::from_str_radix(digits, 16).map_err(|_| format!("Invalid address: {}", word))
+        };
+        let mut address = || {
+            let word = words
+                .next()
+                .ok_or_else(|| format!("{} needs an address", command))?;
+            parse_address(word)
+        };
+        let range = |words: &mut std::str::SplitWhitespace| {
+            let word = words
+                .next()
+                .ok_or_else(|| format!("{} needs an address", command))?;
+            let (start, end) = word.split_once('-').unwrap_or((word, word));
+            Ok::<_, String>(parse_address(start)?..=parse_address(end)?)
+        };
+
+        match command {
+            "c" | "continue" => Ok(Command::Continue),
+            "p" | "pause" => Ok(Command::Pause),
+            "s" | "step" => Ok(Command::Step),
+            "n" | "next" => Ok(Command::StepOver),
+            "f" | "finish" => Ok(Command::StepOut),
+            "b" | "break" => Ok(Command::Break(address()?)),
+            "d" | "delete" => Ok(Command::Delete(address()?)),
+            "w" | "watch" => Ok(Command::Watch(range(&mut words)?)),
+            "uw" | "unwatch" => Ok(Command::Unwatch(range(&mut words)?)),
+            "cond" => {
+                let expression = s.trim_start()[command.len()..].trim();
+                Ok(Command::BreakIf(expression.parse()?))
+            }
+            "uncond" => Ok(Command::ClearConditions),
+            "i" | "info" => Ok(Command::Info),
+            "h" | "help" => Ok(Command::Help),
+            _ => Err(format!("Unknown command: {}", s.trim())),
+        }
+    }
+}
+
+pub const HELP: &str = "\
+c, continue    run until a breakpoint is hit
+p, pause       stop running
+s, step        execute one instruction
+n, next        execute one instruction, stepping over calls
+f, finish      run until the current subroutine returns
+b, break ADDR  stop when the pc reaches ADDR (hexadecimal)
+d, delete ADDR remove the breakpoint at ADDR
+w, watch ADDR[-END]
+               stop when an address in ADDR..=END is written
+uw, unwatch ADDR[-END]
+               remove a watched range
+cond EXPR      stop when EXPR becomes true, e.g. V3 == 0x10 && I > 0x300
+               EXPR may use V0-VF, I, PC, SP (stack depth), DT, ST, [ADDR]
+               (a byte of memory), numbers, ( ), ! and the operators
+               || && == != < <= > >= | ^ & + -
+uncond         remove all conditions
+i, info        show registers, stack, timers and disassembly
+h, help        show this help";
+
+/// An expression over the state of the emulator, e.g.
+/// `V3 == 0x10 && I > 0x300`. Comparisons and logical operators evaluate to
+/// 1 or 0 and any value other than 0 is true.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    text: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Value(i64),
+    Register(u8),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+impl Condition {
+    pub fn is_true(&self, cpu: &Cpu, state: &EmulatorState) -> bool {
+        self.expr.eval(cpu, state) != 0
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = ConditionParser {
+            text: s.as_bytes(),
+            position: 0,
+        };
+        let expr = parser.binary(0)?;
+        parser.skip_whitespace();
+        if parser.position < parser.text.len() {
+            return Err(parser.error("Unexpected character"));
+        }
+        Ok(Self {
+            text: s.trim().to_string(),
+            expr,
+        })
+    }
+}
+
+impl Expr {
+    fn eval(&self, cpu: &Cpu, state: &EmulatorState) -> i64 {
+        let truth = |value: bool| i64::from(value);
+        match self {
+            Expr::Value(value) => *value,
+            Expr::Register(register) => cpu.get_register(*register).unwrap_or_default().into(),
+            Expr::I => cpu.i.into(),
+            Expr::Pc => cpu.pc as i64,
+            Expr::Sp => cpu.stack.len() as i64,
+            Expr::Dt => state.delay_timer.get().into(),
+            Expr::St => state.sound_timer.get().into(),
+            Expr::Memory(address) => usize::try_from(address.eval(cpu, state))
+                .ok()
+                .and_then(|address| state.ram.get(address).ok())
+                .unwrap_or_default()
+                .into(),
+            Expr::Not(value) => truth(value.eval(cpu, state) == 0),
+            Expr::Binary(operator, left, right) => {
+                let left = left.eval(cpu, state);
+                // Short-circuit like the operators of most languages
+                match *operator {
+                    "||" if left != 0 => return 1,
+                    "&&" if left == 0 => return 0,
+                    _ => {}
+                }
+                let right = right.eval(cpu, state);
+                match *operator {
+                    "||" | "&&" => truth(right != 0),
+                    "==" => truth(left == right),
+                    "!=" => truth(left != right),
+                    "<=" => truth(left <= right),
+                    ">=" => truth(left >= right),
+                    "<" => truth(left < right),
+                    ">" => truth(left > right),
+                    "|" => left | right,
+                    "^" => left ^ right,
+                    "&" => left & right,
+                    "+" => left.wrapping_add(right),
+                    _ => left.wrapping_sub(right),
+                }
+            }
+        }
+    }
+}
+
+/// A precedence climbing parser for conditions.
+struct ConditionParser<'a> {
+    text: &'a [u8],
+    position: usize,
+}
+
+impl ConditionParser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("{} at column {}", message, self.position + 1)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .text
+            .get(self.position)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.position += 1;
+        }
+    }
+
+    /// Consumes the next binary operator if it binds at least as tightly as
+    /// `min_precedence`.
+    fn operator(&mut self, min_precedence: u8) -> Option<(&'static str, u8)> {
+        // Longer operators come first so `<=` isn't read as `<`
+        const OPERATORS: [(&str, u8); 13] = [
+            ("||", 0),
+            ("&&", 1),
+            ("==", 2),
+            ("!=", 2),
+            ("<=", 3),
+            (">=", 3),
+            ("<", 3),
+            (">", 3),
+            ("|", 4),
+            ("^", 5),
+            ("&", 6),
+            ("+", 7),
+            ("-", 7),
+        ];
+        self.skip_whitespace();
+        let rest = &self.text[self.position..];
+        let (operator, precedence) = OPERATORS
+            .iter()
+            .find(|(operator, _)| rest.starts_with(operator.as_bytes()))?;
+        if *precedence < min_precedence {
+            return None;
+        }
+        self.position += operator.len();
+        Some((operator, *precedence))
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        while let Some((operator, precedence)) = self.operator(min_precedence) {
+            let right = self.binary(precedence + 1)?;
+            left = Expr::Binary(operator, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        self.skip_whitespace();
+        match self.text.get(self.position) {
+            Some(b'!') => {
+                self.position += 1;
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            Some(b'(') => {
+                self.position += 1;
+                let expr = self.binary(0)?;
+                self.expect(b')')?;
+                Ok(expr)
+            }
+            Some(b'[') => {
+                self.position += 1;
+                let address = self.binary(0)?;
+                self.expect(b']')?;
+                Ok(Expr::Memory(Box::new(address)))
+            }
+            Some(char) if char.is_ascii_alphanumeric() => self.word(),
+            Some(_) => Err(self.error("Unexpected character")),
+            None => Err(self.error("Expected a value")),
+        }
+    }
+
+    fn expect(&mut self, char: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.text.get(self.position) != Some(&char) {
+            return Err(self.error(&format!("Expected {}", char as char)));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    /// Parses a number or the name of a register.
+    fn word(&mut self) -> Result<Expr, String> {
+        let start = self.position;
+        while self
+            .text
+            .get(self.position)
+            .is_some_and(u8::is_ascii_alphanumeric)
+        {
+            self.position += 1;
+        }
+        // Only ASCII characters were consumed
+        let word = std::str::from_utf8(&self.text[start..self.position])
+            .unwrap()
+            .to_ascii_uppercase();
+
+        let number = if let Some(digits) = word.strip_prefix("0X") {
+            i64::from_str_radix(digits, 16)
+        } else if let Some(digits) = word.strip_prefix("0B") {
+            i64::from_str_radix(digits, 2)
+        } else {
+            word.parse()
+        };
+        if let Ok(number) = number {
+            return Ok(Expr::Value(number));
+        }
+        let expr = match word.as_str() {
+            "I" => Expr::I,
+            "PC" => Expr::Pc,
+            "SP" => Expr::Sp,
+            "DT" => Expr::Dt,
+            "ST" => Expr::St,
+            _ => match word
+                .strip_prefix('V')
+                .map(|digit| u8::from_str_radix(digit, 16))
+            {
+                Some(Ok(register)) if word.len() == 2 => Expr::Register(register),
+                _ => {
+                    self.position = start;
+                    return Err(self.error(&format!("Unknown value {}", word)));
+                }
+            },
+        };
+        Ok(expr)
+    }
+}
+
+/// Why the [`Debugger`] stopped execution.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    /// A step completed.
+    Step,
+    /// The pc reached a breakpoint.
+    Breakpoint(usize),
+    /// A watched address was written.
+    Watchpoint(WatchHit),
+    /// A condition became true.
+    Condition(Condition),
+    /// The cpu halted.
+    Halted,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stop::Step => write!(f, "Stepped"),
+            Stop::Breakpoint(address) => write!(f, "Breakpoint at {:#05X}", address),
+            Stop::Watchpoint(hit) => write!(
+                f,
+                "{:#05X} changed from {:#04X} to {:#04X}",
+                hit.address, hit.old, hit.new
+            ),
+            Stop::Condition(condition) => write!(f, "{} is true", condition),
+            Stop::Halted => write!(f, "The cpu halted"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Paused,
+    Running,
+    Step,
+    /// Run until the pc is at `pc` with a stack of `depth` entries.
+    RunUntil {
+        pc: usize,
+        depth: usize,
+    },
+    /// Run until the stack has less than `depth` entries.
+    RunUntilReturn {
+        depth: usize,
+    },
+}
+
+/// Decides when the [`Emulator`] executes instructions.
+pub struct Debugger {
+    mode: Mode,
+    breakpoints: BTreeSet<usize>,
+    // Each condition with whether it was true after the last step, so it only
+    // stops execution when it becomes true
+    conditions: Vec<(Condition, bool)>,
+    // The breakpoint at this address is ignored once, so execution can
+    // continue after hitting it
+    resumed_at: Option<usize>,
+}
+
+impl Debugger {
+    /// Creates a new [`Debugger`] which is either paused or running.
+    pub fn new(paused: bool) -> Self {
+        Self {
+            mode: if paused { Mode::Paused } else { Mode::Running },
+            breakpoints: BTreeSet::new(),
+            conditions: Vec::new(),
+            resumed_at: None,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.mode == Mode::Paused
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<usize> {
+        &self.breakpoints
+    }
+
+    pub fn conditions(&self) -> impl Iterator<Item = &Condition> {
+        self.conditions.iter().map(|(condition, _)| condition)
+    }
+
+    /// Carries out a [`Command`]. Returns the text to show to the user.
+    pub fn handle<R: Render>(&mut self, command: Command, emulator: &mut Emulator<R>) -> String {
+        let pc = emulator.cpu.pc;
+        match command {
+            Command::Continue => self.resume(Mode::Running, pc),
+            Command::Pause => self.mode = Mode::Paused,
+            Command::Step => self.resume(Mode::Step, pc),
+            Command::StepOver => {
+                let is_call = emulator
+                    .state
+                    .ram
+                    .get(pc)
+                    .and_then(|first| Ok((first, emulator.state.ram.get(pc + 1)?)))
+                    .is_ok_and(|(first, second)| {
+                        matches!(Op::decode(first, second), Ok(Op::Call(_)))
+                    });
+                let mode = if is_call {
+                    Mode::RunUntil {
+                        pc: pc + 2,
+                        depth: emulator.cpu.stack.len(),
+                    }
+                } else {
+                    Mode::Step
+                };
+                self.resume(mode, pc);
+            }
+            Command::StepOut => {
+                if emulator.cpu.stack.is_empty() {
+                    return "Not inside a subroutine".to_string();
+                }
+                self.resume(
+                    Mode::RunUntilReturn {
+                        depth: emulator.cpu.stack.len(),
+                    },
+                    pc,
+                );
+            }
+            Command::Break(address) => {
+                self.breakpoints.insert(address);
+                return format!("Breakpoint at {:#05X}", address);
+            }
+            Command::Delete(address) => {
+                return if self.breakpoints.remove(&address) {
+                    format!("Deleted breakpoint at {:#05X}", address)
+                } else {
+                    format!("No breakpoint at {:#05X}", address)
+                };
+            }
+            Command::Watch(range) => {
+                let text = format!("Watching {}", format_range(&range));
+                emulator.state.ram.watch(range);
+                return text;
+            }
+            Command::Unwatch(range) => {
+                return if emulator.state.ram.unwatch(&range) {
+                    format!("Stopped watching {}", format_range(&range))
+                } else {
+                    format!("Not watching {}", format_range(&range))
+                };
+            }
+            Command::BreakIf(condition) => {
+                let is_true = condition.is_true(&emulator.cpu, &emulator.state);
+                let text = format!("Stopping when {} becomes true", condition);
+                self.conditions.push((condition, is_true));
+                return text;
+            }
+            Command::ClearConditions => self.conditions.clear(),
+            Command::Info => return self.view(emulator),
+            Command::Help => return HELP.to_string(),
+        }
+        String::new()
+    }
+
+    fn resume(&mut self, mode: Mode, pc: usize) {
+        self.mode = mode;
+        self.resumed_at = Some(pc);
+    }
+
+    /// Steps the [`Emulator`] unless the debugger is paused. Returns why
+    /// execution stopped if it did during this call.
+    pub fn step<R: Render>(
+        &mut self,
+        emulator: &mut Emulator<R>,
+    ) -> Result<Option<Stop>, EmulatorError> {
+        let pc = emulator.cpu.pc;
+        if self.mode == Mode::Paused {
+            return Ok(None);
+        }
+        if self.breakpoints.contains(&pc) && self.resumed_at != Some(pc) {
+            self.mode = Mode::Paused;
+            return Ok(Some(Stop::Breakpoint(pc)));
+        }
+
+        // The cpu does nothing while it waits for the display, so a step is
+        // not complete until it executes something. FX0A is complete once it
+        // got a key.
+        let executes = !emulator.cpu.waiting_for_vblank && !emulator.cpu.halted;
+        let result = emulator.step();
+        let executes = executes && !emulator.cpu.waiting_for_key();
+
+        let cpu = &emulator.cpu;
+        if cpu.pc != pc {
+            self.resumed_at = None;
+        }
+        let mut stop = emulator.state.ram.take_watch_hit().map(Stop::Watchpoint);
+        for (condition, was_true) in &mut self.conditions {
+            let is_true = condition.is_true(cpu, &emulator.state);
+            if is_true && !*was_true && stop.is_none() {
+                stop = Some(Stop::Condition(condition.clone()));
+            }
+            *was_true = is_true;
+        }
+        if stop.is_none() {
+            let done = match self.mode {
+                Mode::Step => executes,
+                Mode::RunUntil { pc, depth } => {
+                    (cpu.pc == pc && cpu.stack.len() == depth) || cpu.stack.len() < depth
+                }
+                Mode::RunUntilReturn { depth } => cpu.stack.len() < depth,
+                Mode::Paused | Mode::Running => false,
+            };
+            if cpu.halted {
+                stop = Some(Stop::Halted);
+            } else if done {
+                stop = Some(Stop::Step);
+            }
+        }
+        if stop.is_some() {
+            self.mode = Mode::Paused;
+        }
+        result.map(|()| stop)
+    }
+
+    /// Steps the [`Emulator`] until the end of the current frame unless the
+    /// debugger is paused or stops during the frame, like
+    /// [`Emulator::run_frame`].
+    pub fn run_frame<R: Render>(
+        &mut self,
+        emulator: &mut Emulator<R>,
+    ) -> Result<Option<Stop>, EmulatorError> {
+        let frame = emulator.frame_count();
+        while !self.is_paused() && emulator.frame_count() == frame {
+            if let Some(stop) = self.step(emulator)? {
+                return Ok(Some(stop));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Shows the registers, stack, timers and the disassembly around the pc.
+    pub fn view<R: Render>(&self, emulator: &Emulator<R>) -> String {
+        let cpu = &emulator.cpu;
+        let state = &emulator.state;
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "PC {:#05X}  I {:#05X}  DT {:3}  ST {:3}{}",
+            cpu.pc,
+            cpu.i,
+            state.delay_timer.get(),
+            state.sound_timer.get(),
+            if cpu.halted {
+                "  (halted)"
+            } else if cpu.waiting_for_key() {
+                "  (waiting for key)"
+            } else {
+                ""
+            }
+        );
+        for row in 0..2 {
+            for register in row * 8..row * 8 + 8 {
+                let value = cpu.get_register(register).unwrap_or_default();
+                let _ = write!(out, "V{:X} {:02X}  ", register, value);
+            }
+            out.truncate(out.trim_end().len());
+            out.push('\n');
+        }
+        let _ = write!(out, "Stack:");
+        for address in &cpu.stack {
+            let _ = write!(out, " {:#05X}", address);
+        }
+        out.push('\n');
+
+        let labels = BTreeMap::new();
+        let start = cpu.pc.saturating_sub(CONTEXT * 2);
+        for address in (start..=cpu.pc + CONTEXT * 2).step_by(2) {
+            let bytes = (state.ram.get(address), state.ram.get(address + 1));
+            let (first, second) = match bytes {
+                (Ok(first), Ok(second)) => (first, second),
+                _ => break,
+            };
+            let text = match Op::decode(first, second) {
+                Ok(op) => {
+                    let long = state
+                        .ram
+                        .get_slice(address + 2, 2)
+                        .map_or(0, |bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+                    format_op(&op, long, Syntax::Cowgod, &labels)
+                }
+                Err(_) => format!("DB {:#04X}, {:#04X}", first, second),
+            };
+            let marker = if address == cpu.pc { "->" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&address) {
+                '*'
+            } else {
+                ' '
+            };
+            let _ = writeln!(
+                out,
+                "{}{} {:03X}  {:02X}{:02X}  {}",
+                marker, breakpoint, address, first, second, text
+            );
+        }
+
+        let watched = emulator.state.ram.watched();
+        if !watched.is_empty() {
+            let _ = write!(out, "Watching:");
+            for range in watched {
+                let _ = write!(out, " {}", format_range(range));
+            }
+            out.push('\n');
+        }
+        for condition in self.conditions() {
+            let _ = writeln!(out, "Stopping when {}", condition);
+        }
+        out.truncate(out.trim_end().len());
+        out
+    }
+}
+
+fn format_range(range: &RangeInclusive<usize>) -> String {
+    if range.start() == range.end() {
+        format!("{:#05X}", range.start())
+    } else {
+        format!("{:#05X}-{:#05X}", range.start(), range.end())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{display::NullRenderer, platform::Platform, quirks::Quirks};
+
+    // 200: CALL 0x206; 202: LD V1, 1; 204: JP 0x204; 206: LD V0, 1; 208: RET
+    fn emulator() -> Emulator<NullRenderer> {
+        let mut emulator = Emulator::new(NullRenderer, 600, Platform::Chip8, Quirks::CHIP_48);
+        emulator
+            .load_rom(&[0x22, 0x06, 0x61, 0x01, 0x12, 0x04, 0x60, 0x01, 0x00, 0xEE])
+            .unwrap();
+        emulator
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!("s".parse(), Ok(Command::Step));
+        assert_eq!("b 0x2a0".parse(), Ok(Command::Break(0x2A0)));
+        assert_eq!("delete 300".parse(), Ok(Command::Delete(0x300)));
+        assert!("b".parse::<Command>().is_err());
+        assert!("jump".parse::<Command>().is_err());
+        assert_eq!("w 300-302".parse(), Ok(Command::Watch(0x300..=0x302)));
+        assert_eq!("uw 0x300".parse(), Ok(Command::Unwatch(0x300..=0x300)));
+        assert!(matches!(
+            "cond V3 == 1".parse(),
+            Ok(Command::BreakIf(condition)) if condition.to_string() == "V3 == 1"
+        ));
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut emulator = emulator();
+        emulator.cpu.set_register(3, 0x10).unwrap();
+        emulator.cpu.i = 0x301;
+        emulator.state.ram.set(0x301, 7).unwrap();
+        let (cpu, state) = (&emulator.cpu, &emulator.state);
+        let is_true = |text: &str| text.parse::<Condition>().unwrap().is_true(cpu, state);
+
+        assert!(is_true("V3 == 0x10 && I > 0x300"));
+        assert!(is_true("v3 == 16 || [0] == 5"));
+        assert!(is_true("[I] == 7 && PC == 0x200 && SP == 0"));
+        assert!(is_true("!(V3 != 0x10) && V3 - 1 == 0xF"));
+        assert!(is_true("1 + 2 == 3 && 0b110 & 3 == 2"));
+        assert!(!is_true("V3 < 0x10 || I <= 0x300"));
+
+        assert!("V3 ==".parse::<Condition>().is_err());
+        assert!("VG == 1".parse::<Condition>().is_err());
+        assert!("(V3 == 1".parse::<Condition>().is_err());
+        assert!("V3 = 1".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn test_break_if() {
+        let mut emulator = emulator();
+        let mut debugger = Debugger::new(false);
+        let condition: Condition = "V0 == 1".parse().unwrap();
+        debugger.handle(Command::BreakIf(condition.clone()), &mut emulator);
+
+        let stop = loop {
+            if let Some(stop) = debugger.step(&mut emulator).unwrap() {
+                break stop;
+            }
+        };
+        assert_eq!(stop, Stop::Condition(condition));
+        assert_eq!(emulator.cpu.pc, 0x208);
+
+        // The condition stays true, so it doesn't stop again
+        debugger.handle(Command::Continue, &mut emulator);
+        for _ in 0..10 {
+            assert_eq!(debugger.step(&mut emulator).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // 200: LD I, 0x300; 202: LD V0, 9; 204: LD B, V0; 206: JP 0x206
+        let mut emulator = emulator();
+        emulator
+            .load_rom(&[0xA3, 0x00, 0x60, 0x09, 0xF0, 0x33, 0x12, 0x06])
+            .unwrap();
+        let mut debugger = Debugger::new(false);
+        debugger.handle(Command::Watch(0x302..=0x302), &mut emulator);
+
+        let stop = loop {
+            if let Some(stop) = debugger.step(&mut emulator).unwrap() {
+                break stop;
+            }
+        };
+        let hit = WatchHit {
+            address: 0x302,
+            old: 0,
+            new: 9,
+        };
+        assert_eq!(stop, Stop::Watchpoint(hit));
+        assert_eq!(emulator.cpu.pc, 0x206);
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut emulator = emulator();
+        let mut debugger = Debugger::new(false);
+        assert_eq!(debugger.run_frame(&mut emulator).unwrap(), None);
+        assert_eq!(emulator.frame_count(), 1);
+
+        debugger.handle(Command::Break(0x204), &mut emulator);
+        emulator
+            .load_rom(&[0x22, 0x06, 0x61, 0x01, 0x12, 0x04, 0x60, 0x01, 0x00, 0xEE])
+            .unwrap();
+        assert_eq!(
+            debugger.run_frame(&mut emulator).unwrap(),
+            Some(Stop::Breakpoint(0x204))
+        );
+        assert_eq!(emulator.frame_count(), 1);
+        assert_eq!(debugger.run_frame(&mut emulator).unwrap(), None);
+        assert_eq!(emulator.frame_count(), 1);
+    }
+
+    #[test]
+    fn test_paused_does_nothing() {
+        let mut emulator = emulator();
+        let mut debugger = Debugger::new(true);
+        assert_eq!(debugger.step(&mut emulator).unwrap(), None);
+        assert_eq!(emulator.cpu.pc, 0x200);
+    }
+
+    #[test]
+    fn test_step_and_step_over() {
+        let mut emulator = emulator();
+        let mut debugger = Debugger::new(true);
+
+        debugger.handle(Command::StepOver, &mut emulator);
+        while debugger.step(&mut emulator).unwrap().is_none() {}
+        assert_eq!(emulator.cpu.pc, 0x202);
+        assert_eq!(emulator.cpu.get_register(0).unwrap(), 1);
+
+        debugger.handle(Command::Step, &mut emulator);
+        assert_eq!(debugger.step(&mut emulator).unwrap(), Some(Stop::Step));
+        assert_eq!(emulator.cpu.pc, 0x204);
+        assert!(debugger.is_paused());
+    }
+
+    #[test]
+    fn test_step_out() {
+        let mut emulator = emulator();
+        let mut debugger = Debugger::new(true);
+        debugger.handle(Command::Step, &mut emulator);
+        debugger.step(&mut emulator).unwrap();
+        assert_eq!(emulator.cpu.pc, 0x206);
+
+        debugger.handle(Command::StepOut, &mut emulator);
+        while debugger.step(&mut emulator).unwrap().is_none() {}
+        assert_eq!(emulator.cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut emulator = emulator();
+        let mut debugger = Debugger::new(false);
+        debugger.handle(Command::Break(0x208), &mut emulator);
+
+        while debugger.step(&mut emulator).unwrap().is_none() {}
+        assert_eq!(emulator.cpu.pc, 0x208);
+
+        // Continuing executes the instruction at the breakpoint
+        debugger.handle(Command::Continue, &mut emulator);
+        debugger.step(&mut emulator).unwrap();
+        assert_eq!(emulator.cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn test_view() {
+        let mut emulator = emulator();
+        let mut debugger = Debugger::new(true);
+        debugger.handle(Command::Break(0x202), &mut emulator);
+        let view = debugger.view(&emulator);
+        assert!(view.starts_with("PC 0x200  I 0x000  DT   0  ST   0\n"));
+        assert!(view.contains("->  200  2206  CALL 0x206"));
+        assert!(view.contains("  * 202  6101  LD V1, 0x01"));
+    }
+}
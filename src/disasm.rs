@@ -0,0 +1,481 @@
+//! Turns ROMs back into readable assembly.
+//!
+//! Code is told apart from data by following every path of execution starting
+//! at [`ROM_OFFSET`]. Bytes that are never reached are treated as data.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    str::FromStr,
+};
+
+use crate::{
+    emulator::ROM_OFFSET,
+    instruction::{Op, U12},
+    platform::Platform,
+};
+
+/// How many data bytes are put on a single line.
+const DATA_PER_LINE: usize = 8;
+
+/// The assembly dialect to produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Syntax {
+    /// The mnemonics of Cowgod's CHIP-8 technical reference.
+    #[default]
+    Cowgod,
+    /// The high level syntax of Octo.
+    Octo,
+}
+
+impl fmt::Display for Syntax {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Cowgod => "cowgod",
+            Self::Octo => "octo",
+        })
+    }
+}
+
+impl FromStr for Syntax {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cowgod" => Ok(Self::Cowgod),
+            "octo" => Ok(Self::Octo),
+            _ => Err(format!("Unknown syntax: {}", s)),
+        }
+    }
+}
+
+/// A part of a disassembled ROM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    /// A reachable instruction. For [`Op::LdILong`] `bytes` includes the
+    /// address following the opcode.
+    Instruction {
+        address: usize,
+        op: Op,
+        bytes: Vec<u8>,
+    },
+    /// Bytes that are never executed.
+    Data { address: usize, bytes: Vec<u8> },
+}
+
+impl Item {
+    /// The address of the first byte.
+    pub fn address(&self) -> usize {
+        match self {
+            Item::Instruction { address, .. } | Item::Data { address, .. } => *address,
+        }
+    }
+
+    /// The raw bytes from the ROM.
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            Item::Instruction { bytes, .. } | Item::Data { bytes, .. } => bytes,
+        }
+    }
+}
+
+/// A ROM split into instructions and data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disassembly {
+    pub items: Vec<Item>,
+    /// The names of the jump and call targets, keyed by their address.
+    pub labels: BTreeMap<usize, String>,
+}
+
+impl Disassembly {
+    /// Formats the whole ROM in the given [`Syntax`]. Every line is followed
+    /// by a comment containing its address and raw bytes, so the output can
+    /// be assembled again.
+    pub fn format(&self, syntax: Syntax) -> String {
+        let comment = match syntax {
+            Syntax::Cowgod => ';',
+            Syntax::Octo => '#',
+        };
+
+        let mut out = String::new();
+        if syntax == Syntax::Octo {
+            // Octo starts executing at the label `main`
+            out.push_str(": main\n");
+        }
+
+        for item in &self.items {
+            if let Some(label) = self.labels.get(&item.address()) {
+                match syntax {
+                    Syntax::Cowgod => out.push_str(&format!("{}:\n", label)),
+                    Syntax::Octo => out.push_str(&format!(": {}\n", label)),
+                }
+            }
+
+            let text = match item {
+                Item::Instruction { op, bytes, .. } => {
+                    format_op(op, long_address(bytes), syntax, &self.labels)
+                }
+                Item::Data { bytes, .. } => {
+                    let bytes = bytes.iter().map(|byte| format!("{:#04X}", byte));
+                    match syntax {
+                        Syntax::Cowgod => format!("DB {}", bytes.collect::<Vec<_>>().join(", ")),
+                        Syntax::Octo => bytes.collect::<Vec<_>>().join(" "),
+                    }
+                }
+            };
+            let raw = item
+                .bytes()
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<Vec<_>>()
+                .join(" ");
+            out.push_str(&format!(
+                "    {:<24}{} {:03X}: {}\n",
+                text,
+                comment,
+                item.address(),
+                raw
+            ));
+        }
+        out
+    }
+}
+
+/// Disassembles a ROM that is loaded at [`ROM_OFFSET`].
+pub fn disassemble(rom: &[u8], platform: Platform) -> Disassembly {
+    let (code, targets) = find_code(rom, platform);
+    let end = ROM_OFFSET + rom.len();
+
+    let mut items = Vec::new();
+    let mut address = ROM_OFFSET;
+    while address < end {
+        if let Some(op) = code.get(&address) {
+            let offset = address - ROM_OFFSET;
+            items.push(Item::Instruction {
+                address,
+                op: *op,
+                bytes: rom[offset..offset + op.size()].to_vec(),
+            });
+            address += op.size();
+            continue;
+        }
+
+        // Data runs until the next instruction or label
+        let start = address;
+        while address < end
+            && address - start < DATA_PER_LINE
+            && !code.contains_key(&address)
+            && (address == start || !targets.contains(&address))
+        {
+            address += 1;
+        }
+        items.push(Item::Data {
+            address: start,
+            bytes: rom[start - ROM_OFFSET..address - ROM_OFFSET].to_vec(),
+        });
+    }
+
+    // Targets in the middle of an instruction or outside of the ROM can't be
+    // labeled
+    let labels = items
+        .iter()
+        .map(Item::address)
+        .filter(|address| targets.contains(address))
+        .map(|address| (address, format!("L{:03X}", address)))
+        .collect();
+
+    Disassembly { items, labels }
+}
+
+/// Formats a single [`Op`] in the given [`Syntax`].
+///
+/// `long_address` is only used for [`Op::LdILong`]. Addresses that have a
+/// name in `labels` are replaced by it.
+pub fn format_op(
+    op: &Op,
+    long_address: u16,
+    syntax: Syntax,
+    labels: &BTreeMap<usize, String>,
+) -> String {
+    let addr = |address: U12| {
+        labels
+            .get(&(address as usize))
+            .cloned()
+            .unwrap_or_else(|| format!("{:#05X}", address))
+    };
+    let long = labels
+        .get(&(long_address as usize))
+        .cloned()
+        .unwrap_or_else(|| format!("{:#06X}", long_address));
+
+    match syntax {
+        Syntax::Cowgod => match *op {
+            Op::Sys(a) => format!("SYS {}", addr(a)),
+            Op::Cls => "CLS".to_string(),
+            Op::Ret => "RET".to_string(),
+            Op::ScrollDown(n) => format!("SCD {}", n),
+            Op::ScrollUp(n) => format!("SCU {}", n),
+            Op::ScrollRight => "SCR".to_string(),
+            Op::ScrollLeft => "SCL".to_string(),
+            Op::Exit => "EXIT".to_string(),
+            Op::Low => "LOW".to_string(),
+            Op::High => "HIGH".to_string(),
+            Op::Jp(a) => format!("JP {}", addr(a)),
+            Op::Call(a) => format!("CALL {}", addr(a)),
+            Op::SeVxByte { x, byte } => format!("SE V{:X}, {:#04X}", x, byte),
+            Op::SneVxByte { x, byte } => format!("SNE V{:X}, {:#04X}", x, byte),
+            Op::SeVxVy { x, y } => format!("SE V{:X}, V{:X}", x, y),
+            Op::SaveRange { x, y } => format!("LD [I], V{:X}-V{:X}", x, y),
+            Op::LoadRange { x, y } => format!("LD V{:X}-V{:X}, [I]", x, y),
+            Op::LdVxByte { x, byte } => format!("LD V{:X}, {:#04X}", x, byte),
+            Op::AddVxByte { x, byte } => format!("ADD V{:X}, {:#04X}", x, byte),
+            Op::LdVxVy { x, y } => format!("LD V{:X}, V{:X}", x, y),
+            Op::Or { x, y } => format!("OR V{:X}, V{:X}", x, y),
+            Op::And { x, y } => format!("AND V{:X}, V{:X}", x, y),
+            Op::Xor { x, y } => format!("XOR V{:X}, V{:X}", x, y),
+            Op::AddVxVy { x, y } => format!("ADD V{:X}, V{:X}", x, y),
+            Op::Sub { x, y } => format!("SUB V{:X}, V{:X}", x, y),
+            Op::Shr { x, y } => format!("SHR V{:X}, V{:X}", x, y),
+            Op::Subn { x, y } => format!("SUBN V{:X}, V{:X}", x, y),
+            Op::Shl { x, y } => format!("SHL V{:X}, V{:X}", x, y),
+            Op::SneVxVy { x, y } => format!("SNE V{:X}, V{:X}", x, y),
+            Op::LdI(a) => format!("LD I, {}", addr(a)),
+            Op::JpOffset(a) => format!("JP V0, {}", addr(a)),
+            Op::Rnd { x, byte } => format!("RND V{:X}, {:#04X}", x, byte),
+            Op::Drw { x, y, n } => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+            Op::Skp(x) => format!("SKP V{:X}", x),
+            Op::Sknp(x) => format!("SKNP V{:X}", x),
+            Op::LdILong => format!("LD I, LONG {}", long),
+            Op::Plane(n) => format!("PLANE {}", n),
+            Op::Audio => "AUDIO".to_string(),
+            Op::LdVxDt(x) => format!("LD V{:X}, DT", x),
+            Op::LdVxK(x) => format!("LD V{:X}, K", x),
+            Op::LdDtVx(x) => format!("LD DT, V{:X}", x),
+            Op::LdStVx(x) => format!("LD ST, V{:X}", x),
+            Op::AddIVx(x) => format!("ADD I, V{:X}", x),
+            Op::LdFVx(x) => format!("LD F, V{:X}", x),
+            Op::LdHfVx(x) => format!("LD HF, V{:X}", x),
+            Op::LdBVx(x) => format!("LD B, V{:X}", x),
+            Op::Pitch(x) => format!("LD PITCH, V{:X}", x),
+            Op::StoreRegs(x) => format!("LD [I], V{:X}", x),
+            Op::LoadRegs(x) => format!("LD V{:X}, [I]", x),
+            Op::StoreFlags(x) => format!("LD R, V{:X}", x),
+            Op::LoadFlags(x) => format!("LD V{:X}, R", x),
+        },
+        Syntax::Octo => match *op {
+            // Octo has no mnemonic for machine code routines
+            Op::Sys(_) => {
+                let [first, second] = op.encode();
+                format!("{:#04X} {:#04X}", first, second)
+            }
+            Op::Cls => "clear".to_string(),
+            Op::Ret => "return".to_string(),
+            Op::ScrollDown(n) => format!("scroll-down {}", n),
+            Op::ScrollUp(n) => format!("scroll-up {}", n),
+            Op::ScrollRight => "scroll-right".to_string(),
+            Op::ScrollLeft => "scroll-left".to_string(),
+            Op::Exit => "exit".to_string(),
+            Op::Low => "lores".to_string(),
+            Op::High => "hires".to_string(),
+            Op::Jp(a) => format!("jump {}", addr(a)),
+            // A label on its own calls the subroutine
+            Op::Call(a) => match labels.get(&(a as usize)) {
+                Some(label) => label.clone(),
+                None => format!(":call {}", addr(a)),
+            },
+            // Octo's conditions describe when the next instruction is executed
+            Op::SeVxByte { x, byte } => format!("if v{:x} != {:#04X} then", x, byte),
+            Op::SneVxByte { x, byte } => format!("if v{:x} == {:#04X} then", x, byte),
+            Op::SeVxVy { x, y } => format!("if v{:x} != v{:x} then", x, y),
+            Op::SaveRange { x, y } => format!("save v{:x} - v{:x}", x, y),
+            Op::LoadRange { x, y } => format!("load v{:x} - v{:x}", x, y),
+            Op::LdVxByte { x, byte } => format!("v{:x} := {:#04X}", x, byte),
+            Op::AddVxByte { x, byte } => format!("v{:x} += {:#04X}", x, byte),
+            Op::LdVxVy { x, y } => format!("v{:x} := v{:x}", x, y),
+            Op::Or { x, y } => format!("v{:x} |= v{:x}", x, y),
+            Op::And { x, y } => format!("v{:x} &= v{:x}", x, y),
+            Op::Xor { x, y } => format!("v{:x} ^= v{:x}", x, y),
+            Op::AddVxVy { x, y } => format!("v{:x} += v{:x}", x, y),
+            Op::Sub { x, y } => format!("v{:x} -= v{:x}", x, y),
+            Op::Shr { x, y } => format!("v{:x} >>= v{:x}", x, y),
+            Op::Subn { x, y } => format!("v{:x} =- v{:x}", x, y),
+            Op::Shl { x, y } => format!("v{:x} <<= v{:x}", x, y),
+            Op::SneVxVy { x, y } => format!("if v{:x} == v{:x} then", x, y),
+            Op::LdI(a) => format!("i := {}", addr(a)),
+            Op::JpOffset(a) => format!("jump0 {}", addr(a)),
+            Op::Rnd { x, byte } => format!("v{:x} := random {:#04X}", x, byte),
+            Op::Drw { x, y, n } => format!("sprite v{:x} v{:x} {}", x, y, n),
+            Op::Skp(x) => format!("if v{:x} -key then", x),
+            Op::Sknp(x) => format!("if v{:x} key then", x),
+            Op::LdILong => format!("i := long {}", long),
+            Op::Plane(n) => format!("plane {}", n),
+            Op::Audio => "audio".to_string(),
+            Op::LdVxDt(x) => format!("v{:x} := delay", x),
+            Op::LdVxK(x) => format!("v{:x} := key", x),
+            Op::LdDtVx(x) => format!("delay := v{:x}", x),
+            Op::LdStVx(x) => format!("buzzer := v{:x}", x),
+            Op::AddIVx(x) => format!("i += v{:x}", x),
+            Op::LdFVx(x) => format!("i := hex v{:x}", x),
+            Op::LdHfVx(x) => format!("i := bighex v{:x}", x),
+            Op::LdBVx(x) => format!("bcd v{:x}", x),
+            Op::Pitch(x) => format!("pitch := v{:x}", x),
+            Op::StoreRegs(x) => format!("save v{:x}", x),
+            Op::LoadRegs(x) => format!("load v{:x}", x),
+            Op::StoreFlags(x) => format!("saveflags v{:x}", x),
+            Op::LoadFlags(x) => format!("loadflags v{:x}", x),
+        },
+    }
+}
+
+/// Gets the address following [`Op::LdILong`] from the bytes of an
+/// instruction, or 0 if there is none.
+fn long_address(bytes: &[u8]) -> u16 {
+    match bytes {
+        [_, _, high, low] => u16::from_be_bytes([*high, *low]),
+        _ => 0,
+    }
+}
+
+/// Decodes the instruction at `address` if it is fully inside the ROM and
+/// can be executed on `platform`.
+fn decode_at(rom: &[u8], address: usize, platform: Platform) -> Option<Op> {
+    let offset = address.checked_sub(ROM_OFFSET)?;
+    let op = Op::decode(*rom.get(offset)?, *rom.get(offset + 1)?).ok()?;
+    // Machine code routines are never used by real programs, so this is
+    // almost certainly data
+    if op.platform() > platform || offset + op.size() > rom.len() || matches!(op, Op::Sys(_)) {
+        None
+    } else {
+        Some(op)
+    }
+}
+
+/// Follows every path of execution from [`ROM_OFFSET`]. Returns the reachable
+/// instructions and the targets of jumps and calls.
+fn find_code(rom: &[u8], platform: Platform) -> (BTreeMap<usize, Op>, BTreeSet<usize>) {
+    let mut code = BTreeMap::new();
+    let mut targets = BTreeSet::new();
+    let mut pending = vec![ROM_OFFSET];
+
+    while let Some(address) = pending.pop() {
+        if code.contains_key(&address) {
+            continue;
+        }
+        let op = match decode_at(rom, address, platform) {
+            Some(op) => op,
+            None => continue,
+        };
+        code.insert(address, op);
+
+        let next = address + op.size();
+        match op {
+            Op::Ret | Op::Exit => {}
+            Op::Jp(target) => {
+                targets.insert(target as usize);
+                pending.push(target as usize);
+            }
+            // The offset is unknown, but it's usually the start of a jump table
+            Op::JpOffset(target) => {
+                targets.insert(target as usize);
+                pending.push(target as usize);
+            }
+            Op::Call(target) => {
+                targets.insert(target as usize);
+                pending.push(target as usize);
+                pending.push(next);
+            }
+            Op::SeVxByte { .. }
+            | Op::SneVxByte { .. }
+            | Op::SeVxVy { .. }
+            | Op::SneVxVy { .. }
+            | Op::Skp(_)
+            | Op::Sknp(_) => {
+                let skipped = decode_at(rom, next, platform).map_or(2, |op| op.size());
+                pending.push(next);
+                pending.push(next + skipped);
+            }
+            _ => pending.push(next),
+        }
+    }
+
+    (code, targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // CLS; CALL 0x208; JP 0x204; data; RET
+    const ROM: [u8; 10] = [0x00, 0xE0, 0x22, 0x08, 0x12, 0x04, 0xAB, 0xCD, 0x00, 0xEE];
+
+    #[test]
+    fn test_data_detection() {
+        let disassembly = disassemble(&ROM, Platform::Chip8);
+        assert_eq!(
+            disassembly.items[3],
+            Item::Data {
+                address: 0x206,
+                bytes: vec![0xAB, 0xCD]
+            }
+        );
+        assert!(matches!(
+            disassembly.items[4],
+            Item::Instruction { op: Op::Ret, .. }
+        ));
+    }
+
+    #[test]
+    fn test_labels() {
+        let disassembly = disassemble(&ROM, Platform::Chip8);
+        assert_eq!(
+            disassembly.labels.keys().copied().collect::<Vec<_>>(),
+            [0x204, 0x208]
+        );
+    }
+
+    #[test]
+    fn test_skips_long_load() {
+        // SE V0, 0; LD I, LONG 0x1234; RET
+        let rom = [0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x00, 0xEE];
+        let disassembly = disassemble(&rom, Platform::XoChip);
+        assert_eq!(disassembly.items.len(), 3);
+        assert!(disassembly
+            .format(Syntax::Cowgod)
+            .contains("LD I, LONG 0x1234"));
+    }
+
+    #[test]
+    fn test_cowgod_syntax() {
+        let text = disassemble(&ROM, Platform::Chip8).format(Syntax::Cowgod);
+        assert_eq!(
+            text.lines().collect::<Vec<_>>(),
+            [
+                "    CLS                     ; 200: 00 E0",
+                "    CALL L208               ; 202: 22 08",
+                "L204:",
+                "    JP L204                 ; 204: 12 04",
+                "    DB 0xAB, 0xCD           ; 206: AB CD",
+                "L208:",
+                "    RET                     ; 208: 00 EE",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_octo_syntax() {
+        let text = disassemble(&ROM, Platform::Chip8).format(Syntax::Octo);
+        assert_eq!(
+            text.lines().collect::<Vec<_>>(),
+            [
+                ": main",
+                "    clear                   # 200: 00 E0",
+                "    L208                    # 202: 22 08",
+                ": L204",
+                "    jump L204               # 204: 12 04",
+                "    0xAB 0xCD               # 206: AB CD",
+                ": L208",
+                "    return                  # 208: 00 EE",
+            ]
+        );
+    }
+}
@@ -1,53 +1,449 @@
-use sdl2::{pixels::Color, rect::Rect, render::Canvas, video::Window};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::fmt;
 
-const SCALE: u32 = 20;
+use crate::savestate::{Persist, Reader, StateError, Writer};
 
-pub type FrameBuffer = [[bool; 32]; 64];
+#[cfg(feature = "sdl")]
+pub use sdl::SDLRenderer;
+
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+/// The bitmask of both XO-CHIP planes.
+pub const ALL_PLANES: u8 = 0b11;
+
+/// The RGB colours of the four combinations of the two XO-CHIP planes,
+/// indexed by the bitmask stored in the [`FrameBuffer`].
+pub type Palette = [[u8; 3]; 4];
+
+/// Black background, white plane 1, with plane 2 and the overlap in between.
+pub const DEFAULT_PALETTE: Palette = [
+    [0x00, 0x00, 0x00],
+    [0xFF, 0xFF, 0xFF],
+    [0xAA, 0xAA, 0xAA],
+    [0x55, 0x55, 0x55],
+];
+
+/// The screen of the emulator.
+///
+/// It is either in low resolution mode (64x32) or, on SUPER-CHIP and
+/// XO-CHIP, in high resolution mode (128x64). Pixels are addressed by their
+/// column `x` and row `y` within the active resolution.
+///
+/// XO-CHIP adds a second bitplane, so every pixel is stored as a bitmask of
+/// the planes it is set in. This mask doubles as index into a [`Palette`].
+/// Drawing, clearing and scrolling only affect the currently selected planes.
+///
+/// Every change marks the frame buffer as dirty, so it is only drawn when it
+/// changed. The flag is not part of comparisons and save states.
+#[derive(Debug, Clone)]
+pub struct FrameBuffer {
+    hires: bool,
+    planes: u8,
+    pixels: [[u8; HIRES_HEIGHT]; HIRES_WIDTH],
+    dirty: bool,
+}
+
+impl PartialEq for FrameBuffer {
+    fn eq(&self, other: &Self) -> bool {
+        (self.hires, self.planes, &self.pixels) == (other.hires, other.planes, &other.pixels)
+    }
+}
+
+impl Eq for FrameBuffer {}
+
+impl FrameBuffer {
+    /// The width of the active resolution.
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_WIDTH
+        } else {
+            LORES_WIDTH
+        }
+    }
+
+    /// The height of the active resolution.
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_HEIGHT
+        } else {
+            LORES_HEIGHT
+        }
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    /// Switches between low and high resolution mode and clears all planes.
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.pixels = [[0; HIRES_HEIGHT]; HIRES_WIDTH];
+        self.dirty = true;
+    }
+
+    /// Whether the pixels or the resolution changed since the last call of
+    /// [`FrameBuffer::mark_clean`].
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Marks the current contents as drawn.
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+    }
+
+    /// The bitmask of the selected planes.
+    pub fn planes(&self) -> u8 {
+        self.planes
+    }
+
+    /// Selects the planes affected by drawing, clearing and scrolling.
+    pub fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & ALL_PLANES;
+    }
+
+    /// Gets the bitmask of the planes the pixel at the given position is set
+    /// in.
+    ///
+    /// # Panics
+    /// Panics if the position is outside of [`HIRES_WIDTH`]x[`HIRES_HEIGHT`].
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.pixels[x][y]
+    }
+
+    /// Sets the bitmask of the planes the pixel at the given position is set
+    /// in.
+    ///
+    /// # Panics
+    /// Panics if the position is outside of [`HIRES_WIDTH`]x[`HIRES_HEIGHT`].
+    pub fn set(&mut self, x: usize, y: usize, value: u8) {
+        self.pixels[x][y] = value & ALL_PLANES;
+        self.dirty = true;
+    }
+
+    /// Flips the pixel at the given position in the given plane. Returns
+    /// whether the pixel was turned off.
+    ///
+    /// # Panics
+    /// Panics if the position is outside of [`HIRES_WIDTH`]x[`HIRES_HEIGHT`].
+    pub fn toggle(&mut self, x: usize, y: usize, plane: u8) -> bool {
+        let was_set = self.pixels[x][y] & plane != 0;
+        self.pixels[x][y] ^= plane;
+        self.dirty = true;
+        was_set
+    }
+
+    /// Turns all pixels in the selected planes off.
+    pub fn clear(&mut self) {
+        for col in self.pixels.iter_mut() {
+            for pixel in col.iter_mut() {
+                *pixel &= !self.planes;
+            }
+        }
+        self.dirty = true;
+    }
+
+    /// Moves the contents of the screen `n` rows down. The rows at the top
+    /// are cleared.
+    pub fn scroll_down(&mut self, n: usize) {
+        self.scroll(0, n as isize);
+    }
+
+    /// Moves the contents of the screen `n` rows up. The rows at the bottom
+    /// are cleared.
+    pub fn scroll_up(&mut self, n: usize) {
+        self.scroll(0, -(n as isize));
+    }
+
+    /// Moves the contents of the screen `n` columns to the right. The
+    /// columns on the left are cleared.
+    pub fn scroll_right(&mut self, n: usize) {
+        self.scroll(n as isize, 0);
+    }
+
+    /// Moves the contents of the screen `n` columns to the left. The
+    /// columns on the right are cleared.
+    pub fn scroll_left(&mut self, n: usize) {
+        self.scroll(-(n as isize), 0);
+    }
+
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let old = self.pixels;
+        let (width, height) = (self.width() as isize, self.height() as isize);
+        for x in 0..width {
+            for y in 0..height {
+                let (from_x, from_y) = (x - dx, y - dy);
+                let moved = if (0..width).contains(&from_x) && (0..height).contains(&from_y) {
+                    old[from_x as usize][from_y as usize] & self.planes
+                } else {
+                    0
+                };
+                let pixel = &mut self.pixels[x as usize][y as usize];
+                *pixel = (*pixel & !self.planes) | moved;
+            }
+        }
+        self.dirty = true;
+    }
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        Self {
+            hires: false,
+            planes: 1,
+            pixels: [[0; HIRES_HEIGHT]; HIRES_WIDTH],
+            dirty: true,
+        }
+    }
+}
+
+impl FrameBuffer {
+    /// Converts the active resolution to RGB pixels, row by row.
+    pub fn to_rgb(&self, palette: &Palette) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(self.width() * self.height() * 3);
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                rgb.extend_from_slice(&palette[self.get(x, y) as usize]);
+            }
+        }
+        rgb
+    }
+}
+
+/// Draws the active resolution as text, one line per row. Unset pixels are
+/// `.`, pixels in plane 1 `#`, in plane 2 `+` and in both planes `@`.
+impl fmt::Display for FrameBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const PIXELS: [char; 4] = ['.', '#', '+', '@'];
+        for y in 0..self.height() {
+            let row: String = (0..self.width())
+                .map(|x| PIXELS[self.get(x, y) as usize])
+                .collect();
+            writeln!(f, "{}", row)?;
+        }
+        Ok(())
+    }
+}
+
+impl Persist for FrameBuffer {
+    fn save(&self, out: &mut Writer) {
+        out.bool(self.hires);
+        out.u8(self.planes);
+        for column in &self.pixels {
+            out.bytes(column);
+        }
+    }
+
+    fn restore(input: &mut Reader) -> Result<Self, StateError> {
+        let mut frame_buffer = Self {
+            hires: input.bool()?,
+            planes: input.u8()?,
+            pixels: [[0; HIRES_HEIGHT]; HIRES_WIDTH],
+            dirty: true,
+        };
+        if frame_buffer.planes & !ALL_PLANES != 0 {
+            return Err(StateError::Invalid("planes"));
+        }
+        for column in &mut frame_buffer.pixels {
+            *column = input.array()?;
+            if column.iter().any(|pixel| pixel & !ALL_PLANES != 0) {
+                return Err(StateError::Invalid("pixel"));
+            }
+        }
+        Ok(frame_buffer)
+    }
+}
+
+/// An error raised by a [`Render`].
+pub type RenderError = Box<dyn core::error::Error + Send + Sync>;
 
 /// A trait to render a [`FrameBuffer`].
 pub trait Render {
-    fn draw(&mut self, frame_buffer: FrameBuffer) -> anyhow::Result<()>;
-}
-
-/// The built-in renderer using SDL as graphics library.
-pub struct SDLRenderer(Canvas<Window>);
-
-impl SDLRenderer {
-    /// Creates a new [`SDLRenderer`] from a [`sdl2::Sdl`] as context.
-    pub fn new(ctx: &sdl2::Sdl) -> Self {
-        let video_subsystem = ctx.video().unwrap();
-        let window = video_subsystem
-            .window("CHIP-8 Emulator", 64 * SCALE, 32 * SCALE)
-            .position_centered()
-            .build()
-            .unwrap();
-        Self(window.into_canvas().build().unwrap())
-    }
-}
-
-impl Render for SDLRenderer {
-    fn draw(&mut self, frame_buffer: FrameBuffer) -> anyhow::Result<()> {
-        self.0.set_draw_color(Color::BLACK);
-        self.0.clear();
-
-        self.0.set_draw_color(Color::WHITE);
-        for (x, col) in frame_buffer.iter().enumerate() {
-            for (y, state) in col.iter().enumerate() {
-                // If this is true we shall render the pixel white
-                if *state {
-                    self.0
-                        .fill_rect(Rect::new(
-                            i32::try_from(x)? * SCALE as i32,
-                            i32::try_from(y)? * SCALE as i32,
-                            SCALE,
-                            SCALE,
-                        ))
-                        .map_err(anyhow::Error::msg)?;
+    fn draw(&mut self, frame_buffer: &FrameBuffer) -> Result<(), RenderError>;
+}
+
+/// A renderer which draws nothing, e.g. to run ROMs without a display. The
+/// frame buffer can still be read from the
+/// [`EmulatorState`](crate::emulator::EmulatorState).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NullRenderer;
+
+impl Render for NullRenderer {
+    fn draw(&mut self, _: &FrameBuffer) -> Result<(), RenderError> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sdl")]
+mod sdl {
+    use sdl2::{pixels::Color, rect::Rect, render::Canvas, video::Window};
+
+    use super::{
+        FrameBuffer, Palette, Render, RenderError, DEFAULT_PALETTE, LORES_HEIGHT, LORES_WIDTH,
+    };
+
+    const SCALE: u32 = 20;
+    const TITLE: &str = "CHIP-8 Emulator";
+
+    /// The built-in renderer using SDL as graphics library.
+    pub struct SDLRenderer {
+        canvas: Canvas<Window>,
+        palette: Palette,
+    }
+
+    impl SDLRenderer {
+        /// Creates a new [`SDLRenderer`] from a [`sdl2::Sdl`] as context.
+        pub fn new(ctx: &sdl2::Sdl) -> Self {
+            Self::with_scale(ctx, SCALE)
+        }
+
+        /// Creates a renderer whose window shows every pixel of the low
+        /// resolution mode as `scale` by `scale` pixels.
+        pub fn with_scale(ctx: &sdl2::Sdl, scale: u32) -> Self {
+            let video_subsystem = ctx.video().unwrap();
+            let window = video_subsystem
+                .window(
+                    TITLE,
+                    LORES_WIDTH as u32 * scale,
+                    LORES_HEIGHT as u32 * scale,
+                )
+                .position_centered()
+                .build()
+                .unwrap();
+            Self {
+                canvas: window.into_canvas().build().unwrap(),
+                palette: DEFAULT_PALETTE,
+            }
+        }
+
+        /// Changes the colours used to draw the planes.
+        pub fn set_palette(&mut self, palette: Palette) {
+            self.palette = palette;
+        }
+
+        /// Shows `status` after the title of the window, or only the title
+        /// if it is `None`.
+        pub fn set_status(&mut self, status: Option<&str>) -> Result<(), RenderError> {
+            let title = match status {
+                Some(status) => format!("{} - {}", TITLE, status),
+                None => TITLE.to_string(),
+            };
+            self.canvas.window_mut().set_title(&title)?;
+            Ok(())
+        }
+    }
+
+    fn to_color([r, g, b]: [u8; 3]) -> Color {
+        Color::RGB(r, g, b)
+    }
+
+    impl Render for SDLRenderer {
+        fn draw(&mut self, frame_buffer: &FrameBuffer) -> Result<(), RenderError> {
+            self.canvas.set_draw_color(to_color(self.palette[0]));
+            self.canvas.clear();
+
+            // The window keeps its size, so pixels shrink in high resolution mode
+            let (window_width, _) = self.canvas.output_size()?;
+            let scale = window_width / frame_buffer.width() as u32;
+
+            for x in 0..frame_buffer.width() {
+                for y in 0..frame_buffer.height() {
+                    // The background has already been drawn
+                    let pixel = frame_buffer.get(x, y);
+                    if pixel != 0 {
+                        self.canvas
+                            .set_draw_color(to_color(self.palette[pixel as usize]));
+                        self.canvas.fill_rect(Rect::new(
+                            i32::try_from(x)? * scale as i32,
+                            i32::try_from(y)? * scale as i32,
+                            scale,
+                            scale,
+                        ))?;
+                    }
                 }
             }
+
+            self.canvas.present();
+            Ok(())
         }
+    }
+}
 
-        self.0.present();
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scroll_down() {
+        let mut frame_buffer = FrameBuffer::default();
+        frame_buffer.set(3, 0, 1);
+        frame_buffer.set(3, LORES_HEIGHT - 1, 1);
+        frame_buffer.scroll_down(2);
+        assert_eq!(frame_buffer.get(3, 0), 0);
+        assert_eq!(frame_buffer.get(3, 2), 1);
+        assert_eq!(frame_buffer.get(3, LORES_HEIGHT + 1), 0);
+    }
+
+    #[test]
+    fn test_scroll_sideways() {
+        let mut frame_buffer = FrameBuffer::default();
+        frame_buffer.set_hires(true);
+        frame_buffer.set(HIRES_WIDTH - 1, 5, 1);
+        frame_buffer.scroll_left(4);
+        assert_eq!(frame_buffer.get(HIRES_WIDTH - 5, 5), 1);
+        assert_eq!(frame_buffer.get(HIRES_WIDTH - 1, 5), 0);
+        frame_buffer.scroll_right(4);
+        assert_eq!(frame_buffer.get(HIRES_WIDTH - 1, 5), 1);
+        frame_buffer.scroll_right(4);
+        assert_eq!(frame_buffer.get(HIRES_WIDTH - 1, 5), 0);
+    }
+
+    #[test]
+    fn test_text_and_rgb() {
+        let mut frame_buffer = FrameBuffer::default();
+        frame_buffer.set(0, 0, 1);
+        frame_buffer.set(2, 0, 2);
+        frame_buffer.set(3, 1, ALL_PLANES);
+        let text = frame_buffer.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), LORES_HEIGHT);
+        assert!(lines[0].starts_with("#.+."));
+        assert!(lines[1].starts_with("...@"));
+
+        let rgb = frame_buffer.to_rgb(&DEFAULT_PALETTE);
+        assert_eq!(rgb.len(), LORES_WIDTH * LORES_HEIGHT * 3);
+        assert_eq!(rgb[..6], [0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00]);
+        assert_eq!(rgb[6..9], DEFAULT_PALETTE[2]);
+    }
+
+    #[test]
+    fn test_resolution_switch_clears() {
+        let mut frame_buffer = FrameBuffer::default();
+        frame_buffer.set(0, 0, 1);
+        frame_buffer.set_hires(true);
+        assert_eq!(frame_buffer.width(), HIRES_WIDTH);
+        assert_eq!(frame_buffer.get(0, 0), 0);
+    }
+
+    #[test]
+    fn test_planes() {
+        let mut frame_buffer = FrameBuffer::default();
+        frame_buffer.set(0, 0, ALL_PLANES);
+        frame_buffer.set(1, 0, ALL_PLANES);
+
+        frame_buffer.select_planes(2);
+        assert!(frame_buffer.toggle(0, 0, 2));
+        assert_eq!(frame_buffer.get(0, 0), 1);
+
+        frame_buffer.scroll_down(1);
+        assert_eq!(frame_buffer.get(1, 0), 1);
+        assert_eq!(frame_buffer.get(1, 1), 2);
+
+        frame_buffer.select_planes(1);
+        frame_buffer.clear();
+        assert_eq!(frame_buffer.get(0, 0), 0);
+        assert_eq!(frame_buffer.get(1, 1), 2);
     }
 }
@@ -1,13 +1,67 @@
 use crate::{
-    cpu::{Cpu, KeyState},
-    display::{FrameBuffer, Render},
+    audio::{Audio, AudioError, AudioPattern, NullAudio},
+    cpu::{Cpu, CpuError, KeyState},
+    display::{FrameBuffer, Render, RenderError},
+    platform::Platform,
+    quirks::Quirks,
     ram::Ram,
+    random::{Random, SplitMix64},
+    rewind::RewindBuffer,
+    savestate::{Persist, Reader, StateError, Writer},
     timer::Timer,
 };
-use anyhow::Result;
+use alloc::{boxed::Box, vec::Vec};
+use core::fmt;
 
+/// ROMs are loaded at this address and start executing there.
+pub const ROM_OFFSET: usize = 0x200;
+/// How often per second the timers are decremented and the screen is shown.
+pub const FRAME_RATE: u32 = 60;
 pub const FONT_OFFSET: usize = 0x50;
 pub type Font = [u8; 80];
+/// The big font used by SUPER-CHIP is stored right after the regular font.
+pub const BIG_FONT_OFFSET: usize = FONT_OFFSET + 80;
+pub type BigFont = [u8; 160];
+
+/// The hexadecimal digits 0 to F, 4x5 pixels each.
+pub const FONT: Font = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+/// The SUPER-CHIP digits 0 to F, 8x10 pixels each.
+pub const BIG_FONT: BigFont = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
 
 /// Represents the current state of an [`Emulator`].
 pub struct EmulatorState {
@@ -16,6 +70,81 @@ pub struct EmulatorState {
     pub delay_timer: Timer,
     pub frame_buffer: FrameBuffer,
     pub key_state: KeyState,
+    /// The RPL user flags of the HP-48 which SUPER-CHIP programs can use as
+    /// persistent storage.
+    pub rpl_flags: [u8; 16],
+    /// The XO-CHIP audio pattern played while the sound timer is active.
+    pub audio_pattern: AudioPattern,
+}
+
+impl Persist for EmulatorState {
+    fn save(&self, out: &mut Writer) {
+        self.ram.save(out);
+        self.sound_timer.save(out);
+        self.delay_timer.save(out);
+        self.frame_buffer.save(out);
+        out.keys(&self.key_state);
+        out.bytes(&self.rpl_flags);
+        self.audio_pattern.save(out);
+    }
+
+    fn restore(input: &mut Reader) -> Result<Self, StateError> {
+        let ram = Ram::restore(input)?;
+        let sound_timer = Timer::restore(input)?;
+        let delay_timer = Timer::restore(input)?;
+        let frame_buffer = FrameBuffer::restore(input)?;
+        let key_state = input.keys()?;
+        Ok(Self {
+            ram,
+            sound_timer,
+            delay_timer,
+            frame_buffer,
+            key_state,
+            rpl_flags: input.array()?,
+            audio_pattern: AudioPattern::restore(input)?,
+        })
+    }
+}
+
+/// An error that occurred while stepping the [`Emulator`].
+#[derive(Debug)]
+pub enum EmulatorError {
+    /// The cpu faulted. What happens afterwards is decided by the cpu's
+    /// [`FaultPolicy`](crate::cpu::FaultPolicy).
+    Cpu(CpuError),
+    /// The [`Render`] failed to draw the frame buffer.
+    Display(RenderError),
+    /// The [`Audio`] failed to play the sound.
+    Audio(AudioError),
+    /// A snapshot taken for [`Emulator::rewind`] could not be restored.
+    Rewind(StateError),
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cpu(error) => write!(f, "Cpu fault: {}", error),
+            Self::Display(error) => write!(f, "Display error: {}", error),
+            Self::Audio(error) => write!(f, "Audio error: {}", error),
+            Self::Rewind(error) => write!(f, "Rewind error: {}", error),
+        }
+    }
+}
+
+impl core::error::Error for EmulatorError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            Self::Cpu(error) => Some(error),
+            Self::Display(error) | Self::Audio(error) => Some(error.as_ref()),
+            Self::Rewind(error) => Some(error),
+        }
+    }
+}
+
+impl From<CpuError> for EmulatorError {
+    fn from(error: CpuError) -> Self {
+        Self::Cpu(error)
+    }
 }
 
 /// A CHIP-8 emulator as a struct bundling all the components required.
@@ -25,62 +154,384 @@ pub struct Emulator<R: Render> {
     display: R,
     // The amount of cpu cycles since the last timer decrement
     ticks: u32,
-    // How many cpu cycles there should be between every timer decrement
-    timer_freq: u32,
+    // The amount of timer ticks since the emulator was created
+    frames: u64,
+    // How many cpu cycles there are in every frame, i.e. between every timer
+    // decrement
+    cycles_per_frame: u32,
+    // A snapshot is taken on every timer tick, i.e. once per frame
+    rewind: Option<RewindBuffer>,
+    rng: Box<dyn Random>,
+    audio: Box<dyn Audio>,
 }
 
 impl<R: Render> Emulator<R> {
     /// Creates a new [`Emulator`] with the given [`Render`].
-    /// `cycles` should be how often the step function is invoked per second.
-    pub fn new(display: R, cycles: u32) -> Emulator<R> {
+    /// `cycles` is how many instructions are executed per second, which is
+    /// rounded to a whole number of at least one per frame.
+    /// `platform` and `quirks` are handed to the [`Cpu`].
+    pub fn new(display: R, cycles: u32, platform: Platform, quirks: Quirks) -> Emulator<R> {
         Self {
             state: EmulatorState {
-                ram: Ram::default(),
+                ram: Ram::new(platform.ram_size()),
                 delay_timer: Timer::default(),
                 sound_timer: Timer::default(),
-                frame_buffer: [[false; 32]; 64],
+                frame_buffer: FrameBuffer::default(),
                 key_state: [false; 16],
+                rpl_flags: [0; 16],
+                audio_pattern: AudioPattern::default(),
             },
-            cpu: Cpu::default(),
+            cpu: Cpu::new(16, platform, quirks),
             display,
             ticks: 0,
-            timer_freq: cycles / 60,
+            frames: 0,
+            cycles_per_frame: (cycles.saturating_add(FRAME_RATE / 2) / FRAME_RATE).max(1),
+            rewind: None,
+            rng: Box::new(SplitMix64::new(default_seed())),
+            audio: Box::new(NullAudio),
         }
     }
 
     /// Loads the given font in the emulated RAM at the offset of 0x50 bytes.
-    pub fn load_font(&mut self, font: &Font) -> Result<()> {
-        self.load(FONT_OFFSET, font)?;
-        Ok(())
+    pub fn load_font(&mut self, font: &Font) -> Result<(), CpuError> {
+        self.load(FONT_OFFSET, font)
+    }
+
+    /// Loads the given SUPER-CHIP big font in the emulated RAM right after the
+    /// regular font.
+    pub fn load_big_font(&mut self, font: &BigFont) -> Result<(), CpuError> {
+        self.load(BIG_FONT_OFFSET, font)
     }
 
     /// Loads a ROM into the emulated RAM and jumps the pc to it.
-    pub fn load_rom(&mut self, rom: &[u8]) -> Result<()> {
-        self.load(0x200, rom)?;
-        self.cpu.pc = 0x200;
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), CpuError> {
+        self.load(ROM_OFFSET, rom)?;
+        self.cpu.pc = ROM_OFFSET;
         Ok(())
     }
 
     /// Copies the data that into the emulated RAM at a given offset.
-    pub fn load(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+    pub fn load(&mut self, offset: usize, data: &[u8]) -> Result<(), CpuError> {
         for (i, byte) in data.iter().enumerate() {
             self.state.ram.set(offset + i, *byte)?;
         }
         Ok(())
     }
 
-    /// Executes the next instruction and redraws the screen.
-    /// An internal counter is kept that decrements the timers every 8th call
-    /// of this function.
-    pub fn step(&mut self) -> Result<()> {
-        self.cpu.execute(&mut self.state)?;
-        self.display.draw(self.state.frame_buffer)?;
+    /// The amount of frames, i.e. timer ticks, since the emulator was
+    /// created.
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    /// The [`Render`] drawing the screen.
+    pub fn display_mut(&mut self) -> &mut R {
+        &mut self.display
+    }
+
+    /// How many instructions are executed per frame.
+    pub fn cycles_per_frame(&self) -> u32 {
+        self.cycles_per_frame
+    }
+
+    /// Changes how many instructions are executed per frame, at least one.
+    /// The current frame ends early if it already executed more.
+    pub fn set_cycles_per_frame(&mut self, cycles: u32) {
+        self.cycles_per_frame = cycles.max(1);
+    }
+
+    /// Replaces the random number generator used by `CXNN`. By default a
+    /// [`SplitMix64`] with a random seed is used, or with the seed 0 without
+    /// the `std` feature.
+    pub fn set_rng(&mut self, rng: Box<dyn Random>) {
+        self.rng = rng;
+    }
+
+    /// Replaces the [`Audio`] which plays the sound timer. By default nothing
+    /// is played.
+    pub fn set_audio(&mut self, audio: Box<dyn Audio>) {
+        self.audio = audio;
+    }
+
+    /// Makes `CXNN` produce the same numbers on every run with this seed.
+    pub fn seed(&mut self, seed: u64) {
+        self.set_rng(Box::new(SplitMix64::new(seed)));
+    }
+
+    /// Serializes the cpu, the [`EmulatorState`], the cycle counter and the
+    /// state of the random number generator. See
+    /// [`savestate`](crate::savestate) for the format.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = Writer::new();
+        self.cpu.save(&mut out);
+        self.state.save(&mut out);
+        out.u32(self.ticks);
+        out.u64(self.rng.state());
+        out.finish()
+    }
+
+    /// Restores a state created by [`Emulator::save_state`]. The fault
+    /// policy and key edge of the cpu and debugger watchpoints are kept.
+    ///
+    /// # Errors
+    /// The emulator is left untouched if the state can't be restored.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut input = Reader::new(data)?;
+        let mut cpu = Cpu::restore(&mut input)?;
+        let mut state = EmulatorState::restore(&mut input)?;
+        let ticks = input.u32()?;
+        let rng_state = input.u64()?;
+        input.finish()?;
+
+        cpu.fault_policy = self.cpu.fault_policy;
+        cpu.key_edge = self.cpu.key_edge;
+        state.ram.take_watchpoints(&mut self.state.ram);
+        self.cpu = cpu;
+        self.state = state;
+        self.ticks = ticks;
+        self.rng.set_state(rng_state);
+        Ok(())
+    }
+
+    /// Keeps a snapshot of every frame for [`Emulator::rewind`] while they
+    /// fit into `budget` bytes. A budget of 0 disables rewinding.
+    pub fn set_rewind_budget(&mut self, budget: usize) {
+        self.rewind = (budget > 0).then(|| RewindBuffer::new(budget));
+    }
+
+    /// Restores the state of `frames` frames ago and redraws the screen.
+    /// Returns how many frames were rewound, which is less than `frames` if
+    /// not enough snapshots are available.
+    ///
+    /// # Errors
+    /// Fails if the [`Render`] fails or a snapshot can't be restored.
+    pub fn rewind(&mut self, frames: usize) -> Result<usize, EmulatorError> {
+        let Some(buffer) = &self.rewind else {
+            return Ok(0);
+        };
+        // The newest snapshot is the start of the current frame
+        let rewound = frames.min(buffer.len().saturating_sub(1));
+        if rewound == 0 {
+            return Ok(0);
+        }
+        let index = buffer.len() - 1 - rewound;
+        let Some(state) = buffer.get(index) else {
+            return Ok(0);
+        };
+        // The newer snapshots are kept until the state was restored
+        self.load_state(&state).map_err(EmulatorError::Rewind)?;
+        // The restored frame becomes the current frame
+        if let Some(buffer) = &mut self.rewind {
+            buffer.truncate(index + 1);
+        }
+        self.present()?;
+        Ok(rewound)
+    }
+
+    /// Draws the frame buffer if it changed since it was last drawn. This
+    /// happens at the end of every frame, so front-ends only need to call it
+    /// to show changes within a frame, e.g. while debugging.
+    ///
+    /// # Errors
+    /// Only the [`Render`] can fail.
+    pub fn present(&mut self) -> Result<(), EmulatorError> {
+        if self.state.frame_buffer.is_dirty() {
+            self.display
+                .draw(&self.state.frame_buffer)
+                .map_err(EmulatorError::Display)?;
+            self.state.frame_buffer.mark_clean();
+        }
+        Ok(())
+    }
+
+    /// Executes the instructions until the end of the current frame, which
+    /// ticks the timers once. Front-ends should call this [`FRAME_RATE`]
+    /// times per second.
+    ///
+    /// # Errors
+    /// Stops at the first error. Calling this again finishes the frame.
+    pub fn run_frame(&mut self) -> Result<(), EmulatorError> {
+        let frame = self.frames;
+        while self.frames == frame {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Executes the next instruction.
+    /// Every [`cycles_per_frame`](Emulator::cycles_per_frame) calls of this
+    /// function a frame ends, which decrements the timers and draws the
+    /// screen if it changed.
+    ///
+    /// # Errors
+    /// Cpu faults are returned after the frame was finished, so stepping can
+    /// continue if the cpu did not halt.
+    pub fn step(&mut self) -> Result<(), EmulatorError> {
+        let result = self.cpu.execute(&mut self.state, self.rng.as_mut());
         self.ticks += 1;
-        if self.ticks >= self.timer_freq {
+        if self.ticks >= self.cycles_per_frame {
             self.state.sound_timer.decrement();
             self.state.delay_timer.decrement();
+            self.cpu.waiting_for_vblank = false;
             self.ticks = 0;
+            self.frames += 1;
+            if let Some(mut buffer) = self.rewind.take() {
+                buffer.push(self.save_state());
+                self.rewind = Some(buffer);
+            }
+            self.present()?;
+            let sound = (self.state.sound_timer.get() > 0).then_some(&self.state.audio_pattern);
+            self.audio.frame(sound).map_err(EmulatorError::Audio)?;
         }
-        Ok(())
+        result.map_err(EmulatorError::Cpu)
+    }
+}
+
+#[cfg(feature = "std")]
+fn default_seed() -> u64 {
+    rand::random()
+}
+
+// There is no portable source of entropy without std
+#[cfg(not(feature = "std"))]
+fn default_seed() -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+    use crate::display::NullRenderer;
+
+    // 200: ADD V0, 1; 202: JP 0x200
+    fn emulator(cycles: u32) -> Emulator<NullRenderer> {
+        let mut emulator = Emulator::new(NullRenderer, cycles, Platform::Chip8, Quirks::COSMAC_VIP);
+        emulator.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        emulator
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut emulator = emulator(600);
+        emulator.state.delay_timer.set(10);
+        emulator.run_frame().unwrap();
+        assert_eq!(emulator.frame_count(), 1);
+        assert_eq!(emulator.state.delay_timer.get(), 9);
+        assert_eq!(emulator.cpu.get_register(0), Ok(5));
+
+        emulator.step().unwrap();
+        emulator.run_frame().unwrap();
+        assert_eq!(emulator.frame_count(), 2);
+        assert_eq!(emulator.cpu.get_register(0), Ok(10));
+
+        emulator.set_cycles_per_frame(4);
+        emulator.run_frame().unwrap();
+        assert_eq!(emulator.cpu.get_register(0), Ok(12));
+        assert_eq!(emulator.state.delay_timer.get(), 7);
+    }
+
+    // Counts how often the screen was drawn
+    struct Counter(Rc<Cell<u32>>);
+
+    impl Render for Counter {
+        fn draw(&mut self, _: &FrameBuffer) -> Result<(), RenderError> {
+            self.0.set(self.0.get() + 1);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_draws_once_per_frame() {
+        // 200: CLS; 202: JP 0x200
+        let draws = Rc::new(Cell::new(0));
+        let mut emulator = Emulator::new(
+            Counter(Rc::clone(&draws)),
+            600,
+            Platform::Chip8,
+            Quirks::COSMAC_VIP,
+        );
+        emulator.load_rom(&[0x00, 0xE0, 0x12, 0x00]).unwrap();
+        for _ in 0..9 {
+            emulator.step().unwrap();
+        }
+        assert_eq!(draws.get(), 0);
+        emulator.step().unwrap();
+        assert_eq!(draws.get(), 1);
+        emulator.run_frame().unwrap();
+        assert_eq!(draws.get(), 2);
+
+        // Nothing changes
+        emulator.load_rom(&[0x12, 0x00]).unwrap();
+        emulator.run_frame().unwrap();
+        emulator.present().unwrap();
+        assert_eq!(draws.get(), 2);
+        emulator.state.frame_buffer.set(1, 2, 1);
+        emulator.present().unwrap();
+        assert_eq!(draws.get(), 3);
+    }
+
+    #[test]
+    fn test_cycles_per_frame() {
+        assert_eq!(emulator(700).cycles_per_frame(), 12);
+        // Fewer cycles than frames per second still tick the timers once per
+        // frame
+        let mut emulator = emulator(50);
+        assert_eq!(emulator.cycles_per_frame(), 1);
+        emulator.state.delay_timer.set(10);
+        emulator.step().unwrap();
+        assert_eq!(emulator.state.delay_timer.get(), 9);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut emulator = emulator(600);
+        assert_eq!(emulator.rewind(1).unwrap(), 0);
+        emulator.set_rewind_budget(1 << 20);
+
+        // A snapshot is taken every 10 steps at 600 cycles per second
+        for _ in 0..50 {
+            emulator.step().unwrap();
+        }
+        let saved = emulator.save_state();
+        for _ in 0..50 {
+            emulator.step().unwrap();
+        }
+        assert_eq!(emulator.rewind(5).unwrap(), 5);
+        assert_eq!(emulator.save_state(), saved);
+        assert_eq!(emulator.rewind(100).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_rewind_nothing() {
+        let mut emulator = emulator(600);
+        emulator.set_rewind_budget(1 << 20);
+        for _ in 0..20 {
+            emulator.step().unwrap();
+        }
+        let saved = emulator.save_state();
+        for _ in 0..10 {
+            emulator.step().unwrap();
+        }
+        assert_eq!(emulator.rewind(0).unwrap(), 0);
+        assert_eq!(emulator.rewind(1).unwrap(), 1);
+        assert_eq!(emulator.save_state(), saved);
+    }
+
+    #[test]
+    fn test_rewind_invalid_snapshot() {
+        let mut emulator = emulator(600);
+        emulator.set_rewind_budget(1 << 20);
+        for _ in 0..20 {
+            emulator.step().unwrap();
+        }
+        let state = emulator.save_state();
+        let buffer = emulator.rewind.as_mut().unwrap();
+        buffer.push(vec![0; 4]);
+        buffer.push(state);
+        assert!(matches!(emulator.rewind(1), Err(EmulatorError::Rewind(_))));
+        // The snapshots are kept
+        assert_eq!(emulator.rewind.as_ref().unwrap().len(), 4);
     }
 }
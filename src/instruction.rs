@@ -1,3 +1,7 @@
+use core::fmt;
+
+use crate::platform::Platform;
+
 // Rust doesn't support these types so we use the next smallest representation
 // for these types instead.
 pub type U4 = u8;
@@ -28,6 +32,303 @@ impl Instruction {
     }
 }
 
+/// A decoded instruction of any of the supported [`Platform`]s.
+///
+/// The variants are named after the mnemonics of Cowgod's CHIP-8 technical
+/// reference. `x` and `y` are register indices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// `0NNN`: Call a machine code routine
+    Sys(U12),
+    /// `00E0`: Clear the screen
+    Cls,
+    /// `00EE`: Return from a subroutine
+    Ret,
+    /// `00CN`: Scroll down N lines
+    ScrollDown(U4),
+    /// `00DN`: Scroll up N lines
+    ScrollUp(U4),
+    /// `00FB`: Scroll right 4 pixels
+    ScrollRight,
+    /// `00FC`: Scroll left 4 pixels
+    ScrollLeft,
+    /// `00FD`: Exit the interpreter
+    Exit,
+    /// `00FE`: Switch to low resolution
+    Low,
+    /// `00FF`: Switch to high resolution
+    High,
+    /// `1NNN`: Jump to NNN
+    Jp(U12),
+    /// `2NNN`: Call the subroutine at NNN
+    Call(U12),
+    /// `3XNN`: Skip if VX equals NN
+    SeVxByte { x: U4, byte: u8 },
+    /// `4XNN`: Skip if VX does not equal NN
+    SneVxByte { x: U4, byte: u8 },
+    /// `5XY0`: Skip if VX equals VY
+    SeVxVy { x: U4, y: U4 },
+    /// `5XY2`: Store VX to VY in memory starting at I
+    SaveRange { x: U4, y: U4 },
+    /// `5XY3`: Load VX to VY from memory starting at I
+    LoadRange { x: U4, y: U4 },
+    /// `6XNN`: Set VX to NN
+    LdVxByte { x: U4, byte: u8 },
+    /// `7XNN`: Add NN to VX
+    AddVxByte { x: U4, byte: u8 },
+    /// `8XY0`: Set VX to VY
+    LdVxVy { x: U4, y: U4 },
+    /// `8XY1`: Set VX to VX | VY
+    Or { x: U4, y: U4 },
+    /// `8XY2`: Set VX to VX & VY
+    And { x: U4, y: U4 },
+    /// `8XY3`: Set VX to VX ^ VY
+    Xor { x: U4, y: U4 },
+    /// `8XY4`: Set VX to VX + VY
+    AddVxVy { x: U4, y: U4 },
+    /// `8XY5`: Set VX to VX - VY
+    Sub { x: U4, y: U4 },
+    /// `8XY6`: Shift right
+    Shr { x: U4, y: U4 },
+    /// `8XY7`: Set VX to VY - VX
+    Subn { x: U4, y: U4 },
+    /// `8XYE`: Shift left
+    Shl { x: U4, y: U4 },
+    /// `9XY0`: Skip if VX does not equal VY
+    SneVxVy { x: U4, y: U4 },
+    /// `ANNN`: Set I to NNN
+    LdI(U12),
+    /// `BNNN`: Jump to NNN plus an offset, see [`Quirks::jump_uses_vx`]
+    ///
+    /// [`Quirks::jump_uses_vx`]: crate::quirks::Quirks::jump_uses_vx
+    JpOffset(U12),
+    /// `CXNN`: Set VX to a random number masked by NN
+    Rnd { x: U4, byte: u8 },
+    /// `DXYN`: Draw a sprite of height N at (VX, VY)
+    Drw { x: U4, y: U4, n: U4 },
+    /// `EX9E`: Skip if the key VX is pressed
+    Skp(U4),
+    /// `EXA1`: Skip if the key VX is not pressed
+    Sknp(U4),
+    /// `F000`: Set I to the 16 bit address stored in the next two bytes
+    LdILong,
+    /// `FN01`: Select the drawing planes N
+    Plane(U4),
+    /// `F002`: Load the audio pattern from I
+    Audio,
+    /// `FX07`: Set VX to the delay timer
+    LdVxDt(U4),
+    /// `FX0A`: Wait for a key and store it in VX
+    LdVxK(U4),
+    /// `FX15`: Set the delay timer to VX
+    LdDtVx(U4),
+    /// `FX18`: Set the sound timer to VX
+    LdStVx(U4),
+    /// `FX1E`: Add VX to I
+    AddIVx(U4),
+    /// `FX29`: Set I to the font character VX
+    LdFVx(U4),
+    /// `FX30`: Set I to the big font character VX
+    LdHfVx(U4),
+    /// `FX33`: Store the binary-coded decimal of VX at I
+    LdBVx(U4),
+    /// `FX3A`: Set the pitch register to VX
+    Pitch(U4),
+    /// `FX55`: Store V0 to VX in memory starting at I
+    StoreRegs(U4),
+    /// `FX65`: Load V0 to VX from memory starting at I
+    LoadRegs(U4),
+    /// `FX75`: Store V0 to VX in the RPL user flags
+    StoreFlags(U4),
+    /// `FX85`: Load V0 to VX from the RPL user flags
+    LoadFlags(U4),
+}
+
+/// The opcode does not belong to any [`Op`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError(pub u16);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown instruction: {:04X}", self.0)
+    }
+}
+
+impl core::error::Error for DecodeError {}
+
+impl Op {
+    /// Decodes an [`Op`] from two bytes.
+    ///
+    /// # Errors
+    /// An error is returned if the bytes do not form a valid instruction on
+    /// any platform.
+    pub fn decode(first: u8, second: u8) -> Result<Self, DecodeError> {
+        let Instruction {
+            opcode,
+            x,
+            y,
+            n,
+            nn,
+            nnn,
+        } = Instruction::parse(first, second);
+
+        Ok(match (opcode, x, y, n) {
+            (0x0, 0x0, 0xE, 0x0) => Op::Cls,
+            (0x0, 0x0, 0xE, 0xE) => Op::Ret,
+            (0x0, 0x0, 0xC, _) => Op::ScrollDown(n),
+            (0x0, 0x0, 0xD, _) => Op::ScrollUp(n),
+            (0x0, 0x0, 0xF, 0xB) => Op::ScrollRight,
+            (0x0, 0x0, 0xF, 0xC) => Op::ScrollLeft,
+            (0x0, 0x0, 0xF, 0xD) => Op::Exit,
+            (0x0, 0x0, 0xF, 0xE) => Op::Low,
+            (0x0, 0x0, 0xF, 0xF) => Op::High,
+            (0x0, _, _, _) => Op::Sys(nnn),
+            (0x1, _, _, _) => Op::Jp(nnn),
+            (0x2, _, _, _) => Op::Call(nnn),
+            (0x3, _, _, _) => Op::SeVxByte { x, byte: nn },
+            (0x4, _, _, _) => Op::SneVxByte { x, byte: nn },
+            (0x5, _, _, 0x0) => Op::SeVxVy { x, y },
+            (0x5, _, _, 0x2) => Op::SaveRange { x, y },
+            (0x5, _, _, 0x3) => Op::LoadRange { x, y },
+            (0x6, _, _, _) => Op::LdVxByte { x, byte: nn },
+            (0x7, _, _, _) => Op::AddVxByte { x, byte: nn },
+            (0x8, _, _, 0x0) => Op::LdVxVy { x, y },
+            (0x8, _, _, 0x1) => Op::Or { x, y },
+            (0x8, _, _, 0x2) => Op::And { x, y },
+            (0x8, _, _, 0x3) => Op::Xor { x, y },
+            (0x8, _, _, 0x4) => Op::AddVxVy { x, y },
+            (0x8, _, _, 0x5) => Op::Sub { x, y },
+            (0x8, _, _, 0x6) => Op::Shr { x, y },
+            (0x8, _, _, 0x7) => Op::Subn { x, y },
+            (0x8, _, _, 0xE) => Op::Shl { x, y },
+            (0x9, _, _, 0x0) => Op::SneVxVy { x, y },
+            (0xA, _, _, _) => Op::LdI(nnn),
+            (0xB, _, _, _) => Op::JpOffset(nnn),
+            (0xC, _, _, _) => Op::Rnd { x, byte: nn },
+            (0xD, _, _, _) => Op::Drw { x, y, n },
+            (0xE, _, 0x9, 0xE) => Op::Skp(x),
+            (0xE, _, 0xA, 0x1) => Op::Sknp(x),
+            (0xF, 0x0, 0x0, 0x0) => Op::LdILong,
+            (0xF, _, 0x0, 0x1) => Op::Plane(x),
+            (0xF, 0x0, 0x0, 0x2) => Op::Audio,
+            (0xF, _, 0x0, 0x7) => Op::LdVxDt(x),
+            (0xF, _, 0x0, 0xA) => Op::LdVxK(x),
+            (0xF, _, 0x1, 0x5) => Op::LdDtVx(x),
+            (0xF, _, 0x1, 0x8) => Op::LdStVx(x),
+            (0xF, _, 0x1, 0xE) => Op::AddIVx(x),
+            (0xF, _, 0x2, 0x9) => Op::LdFVx(x),
+            (0xF, _, 0x3, 0x0) => Op::LdHfVx(x),
+            (0xF, _, 0x3, 0x3) => Op::LdBVx(x),
+            (0xF, _, 0x3, 0xA) => Op::Pitch(x),
+            (0xF, _, 0x5, 0x5) => Op::StoreRegs(x),
+            (0xF, _, 0x6, 0x5) => Op::LoadRegs(x),
+            (0xF, _, 0x7, 0x5) => Op::StoreFlags(x),
+            (0xF, _, 0x8, 0x5) => Op::LoadFlags(x),
+            _ => return Err(DecodeError(u16::from_be_bytes([first, second]))),
+        })
+    }
+
+    /// Encodes the [`Op`] back into two bytes.
+    ///
+    /// Operands are truncated to the size of their field.
+    pub fn encode(&self) -> [u8; 2] {
+        let xy = |high: u16, x: U4, y: U4, low: u16| {
+            high << 12 | u16::from(x & 0xF) << 8 | u16::from(y & 0xF) << 4 | low
+        };
+        let xnn = |high: u16, x: U4, nn: u8| high << 12 | u16::from(x & 0xF) << 8 | u16::from(nn);
+        let nnn = |high: u16, nnn: U12| high << 12 | (nnn & 0xFFF);
+        let fx = |x: U4, low: u16| 0xF000 | u16::from(x & 0xF) << 8 | low;
+
+        let opcode = match *self {
+            Op::Sys(addr) => nnn(0x0, addr),
+            Op::Cls => 0x00E0,
+            Op::Ret => 0x00EE,
+            Op::ScrollDown(n) => 0x00C0 | u16::from(n & 0xF),
+            Op::ScrollUp(n) => 0x00D0 | u16::from(n & 0xF),
+            Op::ScrollRight => 0x00FB,
+            Op::ScrollLeft => 0x00FC,
+            Op::Exit => 0x00FD,
+            Op::Low => 0x00FE,
+            Op::High => 0x00FF,
+            Op::Jp(addr) => nnn(0x1, addr),
+            Op::Call(addr) => nnn(0x2, addr),
+            Op::SeVxByte { x, byte } => xnn(0x3, x, byte),
+            Op::SneVxByte { x, byte } => xnn(0x4, x, byte),
+            Op::SeVxVy { x, y } => xy(0x5, x, y, 0x0),
+            Op::SaveRange { x, y } => xy(0x5, x, y, 0x2),
+            Op::LoadRange { x, y } => xy(0x5, x, y, 0x3),
+            Op::LdVxByte { x, byte } => xnn(0x6, x, byte),
+            Op::AddVxByte { x, byte } => xnn(0x7, x, byte),
+            Op::LdVxVy { x, y } => xy(0x8, x, y, 0x0),
+            Op::Or { x, y } => xy(0x8, x, y, 0x1),
+            Op::And { x, y } => xy(0x8, x, y, 0x2),
+            Op::Xor { x, y } => xy(0x8, x, y, 0x3),
+            Op::AddVxVy { x, y } => xy(0x8, x, y, 0x4),
+            Op::Sub { x, y } => xy(0x8, x, y, 0x5),
+            Op::Shr { x, y } => xy(0x8, x, y, 0x6),
+            Op::Subn { x, y } => xy(0x8, x, y, 0x7),
+            Op::Shl { x, y } => xy(0x8, x, y, 0xE),
+            Op::SneVxVy { x, y } => xy(0x9, x, y, 0x0),
+            Op::LdI(addr) => nnn(0xA, addr),
+            Op::JpOffset(addr) => nnn(0xB, addr),
+            Op::Rnd { x, byte } => xnn(0xC, x, byte),
+            Op::Drw { x, y, n } => xy(0xD, x, y, u16::from(n & 0xF)),
+            Op::Skp(x) => xnn(0xE, x, 0x9E),
+            Op::Sknp(x) => xnn(0xE, x, 0xA1),
+            Op::LdILong => 0xF000,
+            Op::Plane(x) => fx(x, 0x01),
+            Op::Audio => 0xF002,
+            Op::LdVxDt(x) => fx(x, 0x07),
+            Op::LdVxK(x) => fx(x, 0x0A),
+            Op::LdDtVx(x) => fx(x, 0x15),
+            Op::LdStVx(x) => fx(x, 0x18),
+            Op::AddIVx(x) => fx(x, 0x1E),
+            Op::LdFVx(x) => fx(x, 0x29),
+            Op::LdHfVx(x) => fx(x, 0x30),
+            Op::LdBVx(x) => fx(x, 0x33),
+            Op::Pitch(x) => fx(x, 0x3A),
+            Op::StoreRegs(x) => fx(x, 0x55),
+            Op::LoadRegs(x) => fx(x, 0x65),
+            Op::StoreFlags(x) => fx(x, 0x75),
+            Op::LoadFlags(x) => fx(x, 0x85),
+        };
+        opcode.to_be_bytes()
+    }
+
+    /// The first [`Platform`] that supports this [`Op`].
+    pub fn platform(&self) -> Platform {
+        match self {
+            Op::ScrollDown(_)
+            | Op::ScrollRight
+            | Op::ScrollLeft
+            | Op::Exit
+            | Op::Low
+            | Op::High
+            | Op::LdHfVx(_)
+            | Op::StoreFlags(_)
+            | Op::LoadFlags(_) => Platform::SuperChip,
+            Op::ScrollUp(_)
+            | Op::SaveRange { .. }
+            | Op::LoadRange { .. }
+            | Op::LdILong
+            | Op::Plane(_)
+            | Op::Audio
+            | Op::Pitch(_) => Platform::XoChip,
+            _ => Platform::Chip8,
+        }
+    }
+
+    /// The size of the instruction in bytes. Only [`Op::LdILong`] is followed
+    /// by an additional address.
+    pub fn size(&self) -> usize {
+        if *self == Op::LdILong {
+            4
+        } else {
+            2
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -46,4 +347,27 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_decoding() {
+        assert_eq!(Op::decode(0x00, 0xE0), Ok(Op::Cls));
+        assert_eq!(Op::decode(0x12, 0x34), Ok(Op::Jp(0x234)));
+        assert_eq!(Op::decode(0xD1, 0x2F), Ok(Op::Drw { x: 1, y: 2, n: 0xF }));
+        assert_eq!(Op::decode(0xFA, 0x65), Ok(Op::LoadRegs(0xA)));
+        assert_eq!(Op::decode(0x51, 0x21), Err(DecodeError(0x5121)));
+        assert_eq!(Op::decode(0xE0, 0x00), Err(DecodeError(0xE000)));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut count = 0;
+        for opcode in 0..=u16::MAX {
+            let [first, second] = opcode.to_be_bytes();
+            if let Ok(op) = Op::decode(first, second) {
+                assert_eq!(op.encode(), [first, second], "{:?}", op);
+                count += 1;
+            }
+        }
+        assert!(count > 0);
+    }
 }
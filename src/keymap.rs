@@ -0,0 +1,323 @@
+//! Maps the keys of a keyboard to the 16 keys of the CHIP-8 keypad.
+//!
+//! The keypad of the COSMAC VIP is laid out as
+//!
+//! ```text
+//! 1 2 3 C
+//! 4 5 6 D
+//! 7 8 9 E
+//! A 0 B F
+//! ```
+//!
+//! and the [`Layout`] presets map it to the same block of keys on the
+//! keyboard, e.g. to `1234`, `QWER`, `ASDF` and `ZXCV` on a QWERTY keyboard.
+
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use core::{fmt, str::FromStr};
+
+/// The CHIP-8 keys row by row as they are laid out on the keypad.
+const KEYPAD: [u8; 16] = [
+    0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF,
+];
+
+/// A key of the keyboard, identified by what it types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Key {
+    /// A key of the main block typing this character without modifiers.
+    /// Letters are lowercase.
+    Char(char),
+    /// A key of the numeric keypad typing this character. Enter is `'\n'`.
+    Numpad(char),
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Char(' ') => write!(f, "space"),
+            Self::Char(',') => write!(f, "comma"),
+            Self::Char(c) => write!(f, "{}", c),
+            Self::Numpad('\n') => write!(f, "numenter"),
+            Self::Numpad(c) => write!(f, "num{}", c),
+        }
+    }
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    /// Parses the names produced by the [`Display`](fmt::Display)
+    /// implementation. Letters may be uppercase.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let single = |s: &str| {
+            let mut chars = s.chars();
+            chars.next().filter(|_| chars.next().is_none())
+        };
+        match s {
+            "space" => Ok(Self::Char(' ')),
+            "comma" => Ok(Self::Char(',')),
+            "numenter" => Ok(Self::Numpad('\n')),
+            _ => match (single(s), s.strip_prefix("num").and_then(single)) {
+                (Some(c), _) => Ok(Self::Char(c.to_ascii_lowercase())),
+                (None, Some(c @ ('0'..='9' | '/' | '*' | '-' | '+' | '.'))) => Ok(Self::Numpad(c)),
+                _ => Err(format!("Unknown key: {}", s)),
+            },
+        }
+    }
+}
+
+/// The keyboard layouts with a preset [`Keymap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Layout {
+    #[default]
+    Qwerty,
+    Qwertz,
+    Azerty,
+    /// The digits on the same digits of the numeric keypad and A to F on
+    /// `/`, `*`, `-`, `+`, Enter and `.`.
+    Numpad,
+}
+
+impl Layout {
+    /// The keys mapped to the keys of [`KEYPAD`].
+    fn keys(self) -> [Key; 16] {
+        let rows = match self {
+            Self::Qwerty => "1234qwerasdfzxcv",
+            Self::Qwertz => "1234qwerasdfyxcv",
+            Self::Azerty => "&é\"'azerqsdfwxcv",
+            Self::Numpad => "123-456+789\n/0*.",
+        };
+        let mut chars = rows.chars();
+        core::array::from_fn(|_| {
+            let c = chars.next().unwrap();
+            if self == Self::Numpad {
+                Key::Numpad(c)
+            } else {
+                Key::Char(c)
+            }
+        })
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Qwerty => "qwerty",
+            Self::Qwertz => "qwertz",
+            Self::Azerty => "azerty",
+            Self::Numpad => "numpad",
+        })
+    }
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    /// Parses the names produced by the [`Display`](fmt::Display)
+    /// implementation.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "qwerty" => Ok(Self::Qwerty),
+            "qwertz" => Ok(Self::Qwertz),
+            "azerty" => Ok(Self::Azerty),
+            "numpad" => Ok(Self::Numpad),
+            _ => Err(format!("Unknown layout: {}", s)),
+        }
+    }
+}
+
+/// Which CHIP-8 key every key of the keyboard presses. Several keys can press
+/// the same CHIP-8 key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    keys: BTreeMap<Key, u8>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self::new(Layout::default())
+    }
+}
+
+impl Keymap {
+    /// The preset for `layout`.
+    pub fn new(layout: Layout) -> Self {
+        Self {
+            keys: layout.keys().into_iter().zip(KEYPAD).collect(),
+        }
+    }
+
+    /// The CHIP-8 key pressed by `key`, as index into a
+    /// [`KeyState`](crate::cpu::KeyState).
+    pub fn get(&self, key: Key) -> Option<usize> {
+        self.keys.get(&key).map(|&chip8| chip8.into())
+    }
+
+    /// The keys pressing the CHIP-8 key `chip8`.
+    pub fn keys(&self, chip8: u8) -> impl Iterator<Item = Key> + '_ {
+        self.keys
+            .iter()
+            .filter(move |(_, &bound)| bound == chip8)
+            .map(|(&key, _)| key)
+    }
+
+    /// Makes `key` press the CHIP-8 key `chip8` instead of the one it pressed
+    /// before.
+    ///
+    /// # Panics
+    /// Panics if `chip8` is not a hexadecimal digit.
+    pub fn bind(&mut self, key: Key, chip8: u8) {
+        assert!(chip8 < 16, "There is no CHIP-8 key {:#X}", chip8);
+        self.keys.insert(key, chip8);
+    }
+
+    /// Removes all keys pressing the CHIP-8 key `chip8`.
+    pub fn unbind(&mut self, chip8: u8) {
+        self.keys.retain(|_, bound| *bound != chip8);
+    }
+
+    /// Changes the key map as described by `config`, e.g. the overrides of a
+    /// ROM. Every line either replaces the map with a preset or lists all
+    /// keys for a CHIP-8 key, other CHIP-8 keys keep their keys:
+    ///
+    /// ```text
+    /// # Start from the AZERTY preset
+    /// layout = azerty
+    /// # Space and W press 5, nothing presses F
+    /// 5 = space, w
+    /// F =
+    /// ```
+    ///
+    /// Keys are named by the character they type, `space`, `comma`, `num0`
+    /// to `num9`, `num/`, `num*`, `num-`, `num+`, `num.` or `numenter`.
+    ///
+    /// # Errors
+    /// Returns the first invalid line. The key map is left untouched then.
+    pub fn apply(&mut self, config: &str) -> Result<(), String> {
+        let mut keymap = self.clone();
+        for (number, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: String| format!("Line {}: {}", number + 1, message);
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| error(format!("Expected NAME = VALUE, got {}", line)))?;
+            let (name, value) = (name.trim(), value.trim());
+            if name == "layout" {
+                keymap = Self::new(value.parse().map_err(error)?);
+                continue;
+            }
+            let chip8 = u8::from_str_radix(name, 16)
+                .ok()
+                .filter(|chip8| *chip8 < 16)
+                .ok_or_else(|| error(format!("Unknown CHIP-8 key: {}", name)))?;
+            let keys = value
+                .split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(str::parse)
+                .collect::<Result<Vec<Key>, _>>()
+                .map_err(error)?;
+            keymap.unbind(chip8);
+            for key in keys {
+                keymap.bind(key, chip8);
+            }
+        }
+        *self = keymap;
+        Ok(())
+    }
+}
+
+impl FromStr for Keymap {
+    type Err = String;
+
+    /// Applies `config` to the default key map, see [`Keymap::apply`].
+    fn from_str(config: &str) -> Result<Self, Self::Err> {
+        let mut keymap = Self::default();
+        keymap.apply(config)?;
+        Ok(keymap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        let keymap = Keymap::new(Layout::Qwerty);
+        let get = |c| keymap.get(Key::Char(c));
+        assert_eq!(
+            (get('1'), get('4'), get('q')),
+            (Some(0x1), Some(0xC), Some(0x4))
+        );
+        assert_eq!(
+            (get('r'), get('x'), get('v')),
+            (Some(0xD), Some(0x0), Some(0xF))
+        );
+        assert_eq!(get('y'), None);
+
+        let keymap = Keymap::new(Layout::Qwertz);
+        assert_eq!(keymap.get(Key::Char('y')), Some(0xA));
+        assert_eq!(keymap.get(Key::Char('z')), None);
+        let keymap = Keymap::new(Layout::Azerty);
+        assert_eq!(keymap.get(Key::Char('é')), Some(0x2));
+        assert_eq!(keymap.get(Key::Char('w')), Some(0xA));
+        let keymap = Keymap::new(Layout::Numpad);
+        for digit in 0..10 {
+            let key = Key::Numpad(char::from_digit(digit, 10).unwrap());
+            assert_eq!(keymap.get(key), Some(digit as usize));
+        }
+        assert_eq!(keymap.get(Key::Numpad('\n')), Some(0xE));
+
+        // Every CHIP-8 key is pressed by exactly one key
+        for layout in [
+            Layout::Qwerty,
+            Layout::Qwertz,
+            Layout::Azerty,
+            Layout::Numpad,
+        ] {
+            let keymap = Keymap::new(layout);
+            for chip8 in 0..16 {
+                assert_eq!(keymap.keys(chip8).count(), 1, "{} {:X}", layout, chip8);
+            }
+        }
+    }
+
+    #[test]
+    fn test_key_names() {
+        for name in ["q", "1", "é", "space", "comma", "num7", "num+", "numenter"] {
+            assert_eq!(name.parse::<Key>().unwrap().to_string(), name);
+        }
+        assert_eq!("Q".parse(), Ok(Key::Char('q')));
+        assert!("enter".parse::<Key>().is_err());
+        assert!("numa".parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut keymap: Keymap = "layout = qwertz\n# Comment\n5 = space, W\n f="
+            .parse()
+            .unwrap();
+        assert_eq!(keymap.get(Key::Char(' ')), Some(0x5));
+        assert_eq!(keymap.get(Key::Char('w')), Some(0x5));
+        assert_eq!(keymap.get(Key::Char('y')), Some(0xA));
+        assert_eq!(keymap.keys(0xF).count(), 0);
+
+        // Keys move to the CHIP-8 key they are listed for
+        keymap.apply("6 = w").unwrap();
+        assert_eq!(keymap.get(Key::Char('w')), Some(0x6));
+        assert_eq!(keymap.keys(0x5).collect::<Vec<_>>(), [Key::Char(' ')]);
+
+        let unchanged = keymap.clone();
+        assert_eq!(
+            keymap.apply("1 = q\nG = w"),
+            Err("Line 2: Unknown CHIP-8 key: G".into())
+        );
+        assert!(keymap.apply("1 = numx").is_err());
+        assert!(keymap.apply("layout = dvorak").is_err());
+        assert!(keymap.apply("1 q").is_err());
+        assert_eq!(keymap, unchanged);
+    }
+}
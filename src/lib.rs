@@ -1,9 +1,47 @@
 //! This crate provides all the components required to run a CHIP-8
 //! emulator/interpreter.
+//!
+//! Without the `std` feature (enabled by the default `sdl` feature) the crate
+//! is `no_std`, so the emulator core can run on microcontrollers. It still
+//! needs `alloc`: the memory is allocated in the size of the platform, 4 KiB
+//! for CHIP-8, and the random number generator and the audio are a
+//! `Box<dyn Random>` and a `Box<dyn Audio>`. The tools (assembler,
+//! disassembler, debugger, movies and Octo cartridges) require `std`.
 
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod asm;
+pub mod audio;
+#[cfg(feature = "std")]
+pub mod cartridge;
+#[cfg(feature = "std")]
+pub mod clock;
+#[cfg(feature = "std")]
+pub mod config;
 pub mod cpu;
+#[cfg(feature = "std")]
+pub mod database;
+#[cfg(feature = "std")]
+pub mod debugger;
+#[cfg(feature = "std")]
+pub mod disasm;
 pub mod display;
 pub mod emulator;
 pub mod instruction;
+pub mod keymap;
+#[cfg(feature = "std")]
+pub mod movie;
+#[cfg(feature = "std")]
+pub mod octo;
+pub mod platform;
+pub mod quirks;
 pub mod ram;
+pub mod random;
+pub mod rewind;
+pub mod savestate;
+#[cfg(feature = "std")]
+pub mod terminal;
 pub mod timer;
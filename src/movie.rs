@@ -0,0 +1,418 @@
+//! Recordings of the input of a game which replay identically.
+//!
+//! A movie stores the key state whenever it changes at the start of a frame,
+//! together with everything else that decides how the emulator behaves: the
+//! hash of the ROM, the platform, the quirks, the speed, the seed of the
+//! random number generator, the [`FaultPolicy`] and the [`KeyEdge`] of
+//! `FX0A`. Keys only change at the start of frames while recording as well,
+//! so replaying a movie produces the same frame buffers.
+
+use std::fmt;
+
+use crate::{
+    cpu::{FaultPolicy, KeyEdge, KeyState},
+    display::Render,
+    emulator::{Emulator, EmulatorError},
+    platform::Platform,
+    quirks::Quirks,
+    savestate::{Persist, Reader, StateError, Writer},
+};
+
+/// Identifies a movie.
+pub const MAGIC: [u8; 4] = *b"C8MV";
+/// The version of the format written by this crate.
+pub const VERSION: u16 = 1;
+
+/// An error that occurred while loading or replaying a movie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovieError {
+    /// The data does not start with [`MAGIC`].
+    NotAMovie,
+    /// The movie was written by an incompatible version of the format.
+    UnsupportedVersion(u16),
+    /// The data ended before the movie was complete.
+    Truncated,
+    /// The movie contains a value that can't be restored.
+    Invalid(&'static str),
+    /// The movie was recorded with a different ROM.
+    RomMismatch,
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAMovie => write!(f, "Not a movie"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported movie version: {}", version)
+            }
+            Self::Truncated => write!(f, "Movie is truncated"),
+            Self::Invalid(what) => write!(f, "Invalid {} in movie", what),
+            Self::RomMismatch => write!(f, "The movie was recorded with a different ROM"),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<StateError> for MovieError {
+    fn from(error: StateError) -> Self {
+        match error {
+            StateError::NotAState => Self::NotAMovie,
+            StateError::UnsupportedVersion(version) => Self::UnsupportedVersion(version),
+            StateError::Truncated => Self::Truncated,
+            StateError::Invalid(what) => Self::Invalid(what),
+        }
+    }
+}
+
+/// The SHA-1 hash identifying a ROM.
+pub fn rom_hash(rom: &[u8]) -> [u8; 20] {
+    sha1_smol::Sha1::from(rom).digest().bytes()
+}
+
+/// The SHA-1 hash of a ROM in lowercase hexadecimal, as ROMs are listed in
+/// the config file and the ROM database.
+pub fn rom_hash_hex(rom: &[u8]) -> String {
+    sha1_smol::Sha1::from(rom).digest().to_string()
+}
+
+/// A recording of the keys pressed while running a ROM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: [u8; 20],
+    pub platform: Platform,
+    pub quirks: Quirks,
+    /// How many instructions are executed per second.
+    pub cycles: u32,
+    /// The seed for [`Emulator::seed`].
+    pub seed: u64,
+    /// What the cpu does on faults.
+    pub fault_policy: FaultPolicy,
+    /// When `FX0A` gets its key.
+    pub key_edge: KeyEdge,
+    // The key state from a frame on, ordered by frame. Frames are counted
+    // from the start of the recording.
+    inputs: Vec<(u64, KeyState)>,
+}
+
+impl Movie {
+    /// Creates an empty movie for the given ROM and settings, with the
+    /// default [`FaultPolicy`] and [`KeyEdge`].
+    pub fn new(rom: &[u8], platform: Platform, quirks: Quirks, cycles: u32, seed: u64) -> Self {
+        Self {
+            rom_hash: rom_hash(rom),
+            platform,
+            quirks,
+            cycles,
+            seed,
+            fault_policy: FaultPolicy::default(),
+            key_edge: KeyEdge::default(),
+            inputs: Vec::new(),
+        }
+    }
+
+    /// Checks that the movie was recorded with `rom`.
+    pub fn check_rom(&self, rom: &[u8]) -> Result<(), MovieError> {
+        if rom_hash(rom) == self.rom_hash {
+            Ok(())
+        } else {
+            Err(MovieError::RomMismatch)
+        }
+    }
+
+    /// The frame the keys last changed in.
+    pub fn length(&self) -> u64 {
+        self.inputs.last().map_or(0, |(frame, _)| *frame)
+    }
+
+    /// The keys held during `frame`.
+    pub fn keys_at(&self, frame: u64) -> KeyState {
+        let index = self.inputs.partition_point(|(start, _)| *start <= frame);
+        index
+            .checked_sub(1)
+            .map_or([false; 16], |index| self.inputs[index].1)
+    }
+
+    /// Holds `keys` from `frame` on until the next change, e.g. to script the
+    /// input of a run.
+    pub fn set_keys(&mut self, frame: u64, keys: KeyState) {
+        match self
+            .inputs
+            .binary_search_by_key(&frame, |(start, _)| *start)
+        {
+            Ok(index) => self.inputs[index].1 = keys,
+            Err(index) => self.inputs.insert(index, (frame, keys)),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Writer::with_header(&MAGIC, VERSION);
+        out.bytes(&self.rom_hash);
+        self.platform.save(&mut out);
+        self.quirks.save(&mut out);
+        out.u32(self.cycles);
+        out.u64(self.seed);
+        self.fault_policy.save(&mut out);
+        self.key_edge.save(&mut out);
+        out.u32(self.inputs.len() as u32);
+        for (frame, keys) in &self.inputs {
+            out.u64(*frame);
+            out.keys(keys);
+        }
+        out.finish()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MovieError> {
+        let mut input = Reader::with_header(data, &MAGIC, VERSION)?;
+        let mut movie = Self {
+            rom_hash: input.array()?,
+            platform: Platform::restore(&mut input)?,
+            quirks: Quirks::restore(&mut input)?,
+            cycles: input.u32()?,
+            seed: input.u64()?,
+            fault_policy: FaultPolicy::restore(&mut input)?,
+            key_edge: KeyEdge::restore(&mut input)?,
+            inputs: Vec::new(),
+        };
+        for _ in 0..input.u32()? {
+            let frame = input.u64()?;
+            if movie.inputs.last().is_some_and(|(last, _)| *last >= frame) {
+                return Err(MovieError::Invalid("frame order"));
+            }
+            movie.inputs.push((frame, input.keys()?));
+        }
+        input.finish()?;
+        Ok(movie)
+    }
+}
+
+/// Records the keys handed to an [`Emulator`].
+pub struct Recorder {
+    movie: Movie,
+    start: Option<u64>,
+    // The last frame keys were handed to the emulator in
+    applied: Option<u64>,
+}
+
+impl Recorder {
+    pub fn new(movie: Movie) -> Self {
+        Self {
+            movie,
+            start: None,
+            applied: None,
+        }
+    }
+
+    /// Hands `keys` to the emulator if a new frame started and records them
+    /// if they changed. Must be called before every step.
+    pub fn apply<R: Render>(&mut self, emulator: &mut Emulator<R>, keys: &KeyState) {
+        let frame = emulator.frame_count();
+        let start = *self.start.get_or_insert(frame);
+        if self.applied != Some(frame) {
+            self.applied = Some(frame);
+            if self
+                .movie
+                .inputs
+                .last()
+                .is_none_or(|(_, last)| last != keys)
+            {
+                self.movie.inputs.push((frame - start, *keys));
+            }
+        }
+        // Changes within the frame are only applied in the next one
+        emulator.state.key_state = self.movie.inputs.last().unwrap().1;
+    }
+
+    /// Rewinds the emulator by up to `frames` frames like
+    /// [`Emulator::rewind`] and drops the keys recorded since, so the movie
+    /// continues from the restored frame. Frames before the start of the
+    /// recording are never restored.
+    ///
+    /// # Errors
+    /// Fails if the emulator fails to rewind.
+    pub fn rewind<R: Render>(
+        &mut self,
+        emulator: &mut Emulator<R>,
+        frames: usize,
+    ) -> Result<usize, EmulatorError> {
+        let Some(start) = self.start else {
+            return Ok(0);
+        };
+        let recorded = (emulator.frame_count() - start) as usize;
+        if recorded == 0 {
+            return Ok(0);
+        }
+        let rewound = emulator.rewind(frames.min(recorded))?;
+        // The frame counter of the emulator keeps counting
+        let start = start + rewound as u64;
+        let frame = emulator.frame_count() - start;
+        self.start = Some(start);
+        self.movie.inputs.retain(|(start, _)| *start < frame);
+        self.applied = None;
+        Ok(rewound)
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+/// Drives an [`Emulator`] with the keys of a [`Movie`].
+pub struct Replay {
+    movie: Movie,
+    start: Option<u64>,
+}
+
+impl Replay {
+    pub fn new(movie: Movie) -> Self {
+        Self { movie, start: None }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    /// Whether all recorded key changes were replayed.
+    pub fn is_finished<R: Render>(&self, emulator: &Emulator<R>) -> bool {
+        self.start
+            .is_some_and(|start| emulator.frame_count() - start >= self.movie.length())
+    }
+
+    /// Hands the recorded keys of the current frame to the emulator. Must be
+    /// called before every step.
+    pub fn apply<R: Render>(&mut self, emulator: &mut Emulator<R>) {
+        let frame = emulator.frame_count();
+        let start = *self.start.get_or_insert(frame);
+        emulator.state.key_state = self.movie.keys_at(frame - start);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::{FrameBuffer, NullRenderer};
+
+    // Draws a pixel at a random position while key 5 is held:
+    // 200: RND V0, 0x3F; 202: RND V1, 0x1F; 204: LD I, 0x210; 206: LD V2, 5
+    // 208: SKNP V2; 20A: DRW V0, V1, 1; 20C: JP 0x200; 210: DB 0x80
+    const ROM: [u8; 17] = [
+        0xC0, 0x3F, 0xC1, 0x1F, 0xA2, 0x10, 0x62, 0x05, 0xE2, 0xA1, 0xD0, 0x11, 0x12, 0x00, 0x00,
+        0x00, 0x80,
+    ];
+
+    fn emulator(movie: &Movie) -> Emulator<NullRenderer> {
+        let mut emulator = Emulator::new(NullRenderer, movie.cycles, movie.platform, movie.quirks);
+        emulator.seed(movie.seed);
+        emulator.load_rom(&ROM).unwrap();
+        emulator
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let movie = Movie::new(&ROM, Platform::Chip8, Quirks::CHIP_48, 600, 1234);
+        let mut recorder = Recorder::new(movie.clone());
+        let mut recorded = emulator(&movie);
+        let mut keys = [false; 16];
+        for step in 0..2000 {
+            // Keys change in the middle of frames
+            keys[5] = (95..613).contains(&step) || (1201..1502).contains(&step);
+            recorder.apply(&mut recorded, &keys);
+            recorded.step().unwrap();
+        }
+        let movie = recorder.finish();
+        assert_eq!(movie.length(), 151);
+
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        movie.check_rom(&ROM).unwrap();
+        let mut replay = Replay::new(movie.clone());
+        let mut replayed = emulator(&movie);
+        for _ in 0..2000 {
+            replay.apply(&mut replayed);
+            replayed.step().unwrap();
+        }
+        assert!(replay.is_finished(&replayed));
+        assert_eq!(replayed.state.frame_buffer, recorded.state.frame_buffer);
+        assert_ne!(replayed.state.frame_buffer, FrameBuffer::default());
+    }
+
+    #[test]
+    fn test_rewind_while_recording() {
+        let movie = Movie::new(&ROM, Platform::Chip8, Quirks::CHIP_48, 600, 99);
+        let mut recorder = Recorder::new(movie.clone());
+        let mut recorded = emulator(&movie);
+        recorded.set_rewind_budget(1 << 20);
+        let mut keys = [false; 16];
+        for step in 0..1000 {
+            keys[5] = (300..900).contains(&step);
+            recorder.apply(&mut recorded, &keys);
+            recorded.step().unwrap();
+        }
+        // Takes back the last 40 frames and plays them differently
+        assert_eq!(recorder.rewind(&mut recorded, 40).unwrap(), 40);
+        for step in 0..1000 {
+            keys[5] = (200..500).contains(&step);
+            recorder.apply(&mut recorded, &keys);
+            recorded.step().unwrap();
+        }
+        let movie = recorder.finish();
+        assert_eq!(movie.length(), 110);
+
+        let mut replay = Replay::new(movie.clone());
+        let mut replayed = emulator(&movie);
+        for _ in 0..1600 {
+            replay.apply(&mut replayed);
+            replayed.step().unwrap();
+        }
+        assert_eq!(replayed.state.frame_buffer, recorded.state.frame_buffer);
+        assert_eq!(replayed.save_state(), recorded.save_state());
+
+        // Frames before the start of the recording are never restored
+        let mut recorder = Recorder::new(movie.clone());
+        let mut late = emulator(&movie);
+        late.set_rewind_budget(1 << 20);
+        for _ in 0..100 {
+            late.step().unwrap();
+        }
+        recorder.apply(&mut late, &keys);
+        assert_eq!(recorder.rewind(&mut late, 5).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_keys_at() {
+        let mut movie = Movie::new(&ROM, Platform::Chip8, Quirks::CHIP_48, 600, 0);
+        let mut pressed = [false; 16];
+        pressed[1] = true;
+        movie.set_keys(20, [false; 16]);
+        movie.set_keys(10, [true; 16]);
+        movie.set_keys(10, pressed);
+        assert_eq!(movie.keys_at(9), [false; 16]);
+        assert_eq!(movie.keys_at(10), pressed);
+        assert_eq!(movie.keys_at(19), pressed);
+        assert_eq!(movie.keys_at(500), [false; 16]);
+    }
+
+    #[test]
+    fn test_invalid_movies() {
+        let movie = Movie::new(&ROM, Platform::Chip8, Quirks::CHIP_48, 600, 0);
+        assert_eq!(movie.check_rom(&[0x00]), Err(MovieError::RomMismatch));
+
+        let data = movie.to_bytes();
+        assert_eq!(
+            Movie::from_bytes(&data[..data.len() - 1]),
+            Err(MovieError::Truncated)
+        );
+        assert_eq!(
+            Movie::from_bytes(&Writer::new().finish()),
+            Err(MovieError::NotAMovie)
+        );
+    }
+
+    #[test]
+    fn test_settings() {
+        let mut movie = Movie::new(&ROM, Platform::XoChip, Quirks::XO_CHIP, 600, 7);
+        movie.fault_policy = FaultPolicy::Skip;
+        movie.key_edge = KeyEdge::Press;
+        movie.set_keys(3, [true; 16]);
+        assert_eq!(Movie::from_bytes(&movie.to_bytes()), Ok(movie));
+    }
+}
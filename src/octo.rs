@@ -0,0 +1,1243 @@
+//! Compiles programs written in Octo's language into ROMs.
+//!
+//! Octo is the most popular CHIP-8 IDE and its cartridges contain the source
+//! of the program rather than a ROM. Besides the statements produced by
+//! [`disasm`](crate::disasm) in [`Syntax::Octo`](crate::disasm::Syntax::Octo)
+//! the compiler understands:
+//! * structured control flow: `if ... then`, `if ... begin ... else ... end`,
+//!   `loop ... while ... again` and the comparisons `<`, `>`, `<=` and `>=`,
+//!   which use VF as temporary register
+//! * the directives `:alias`, `:const`, `:unpack`, `:next`, `:org`, `:byte`,
+//!   `:pointer`, `:call`, `:macro`, `:calc`, `:stringmode` and `:assert`
+//!
+//! Execution starts at the label `main`. Unless the code starts with it, a jump
+//! to `main` is put in front of the program. Comments start with `#`.
+//! Expressions of `:calc` are evaluated from right to left without operator
+//! precedence, like in Octo.
+
+use std::{collections::HashMap, f64::consts};
+
+use crate::{asm::AsmError, emulator::ROM_OFFSET, instruction::Op, ram::XO_CHIP_RAM_SIZE};
+
+/// How many macros may be expanded before a recursion is assumed.
+const MAX_EXPANSIONS: usize = 100_000;
+
+/// Compiles `source` into a ROM that can be loaded at [`ROM_OFFSET`].
+pub fn compile(source: &str) -> Result<Vec<u8>, AsmError> {
+    Compiler::new(tokenize(source)).run()
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error(&self, message: impl Into<String>) -> AsmError {
+        AsmError {
+            file: "<source>".to_string(),
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        }
+    }
+
+    fn is(&self, text: &str) -> bool {
+        self.text == text
+    }
+
+    /// The contents of a string literal.
+    fn string(&self) -> Option<&str> {
+        self.text.strip_prefix('"')?.strip_suffix('"')
+    }
+}
+
+/// Splits `source` at whitespace, dropping comments. Strings in double quotes
+/// are single tokens including the quotes.
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut start = 0;
+        while start < chars.len() {
+            if chars[start].is_whitespace() {
+                start += 1;
+                continue;
+            }
+            if chars[start] == '#' {
+                break;
+            }
+            let mut end = start + 1;
+            if chars[start] == '"' {
+                while end < chars.len() && chars[end] != '"' {
+                    end += 1;
+                }
+                end = (end + 1).min(chars.len());
+            } else {
+                while end < chars.len() && !chars[end].is_whitespace() && chars[end] != '#' {
+                    end += 1;
+                }
+            }
+            tokens.push(Token {
+                text: chars[start..end].iter().collect(),
+                line: index + 1,
+                column: start + 1,
+            });
+            start = end;
+        }
+    }
+    tokens
+}
+
+/// Parses a number in decimal, hexadecimal (`0x`) or binary (`0b`), with an
+/// optional minus sign.
+fn number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|char: char| char.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// Parses `v0` to `vf`.
+fn register(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix(['v', 'V'])?;
+    if digit.len() == 1 {
+        u8::from_str_radix(digit, 16).ok()
+    } else {
+        None
+    }
+}
+
+/// Words that can't be used as names.
+const KEYWORDS: [&str; 48] = [
+    ":", ":=", "+=", "-=", "=-", "|=", "&=", "^=", ">>=", "<<=", "==", "!=", "<", ">", "<=", ">=",
+    ";", "return", "clear", "bcd", "save", "load", "sprite", "jump", "jump0", "native", "delay",
+    "buzzer", "pitch", "random", "key", "-key", "hex", "bighex", "long", "i", "if", "then",
+    "begin", "else", "end", "loop", "while", "again", "hires", "lores", "exit", "audio",
+];
+
+/// Directives that may come before `main` as they don't produce any code by
+/// themselves.
+const DECLARATIONS: [&str; 8] = [
+    ":alias",
+    ":const",
+    ":calc",
+    ":macro",
+    ":stringmode",
+    ":assert",
+    ":breakpoint",
+    ":monitor",
+];
+
+fn is_name(text: &str) -> bool {
+    !text.is_empty()
+        && !text.starts_with([':', '"', '{', '}', '('])
+        && number(text).is_none()
+        && register(text).is_none()
+        && !KEYWORDS.contains(&text)
+}
+
+/// A place that refers to a label before it is defined.
+enum Use {
+    /// The low 12 bits of the instruction at the address.
+    Addr(usize),
+    /// The 16 bit address at the address.
+    Long(usize),
+    /// The values of the two instructions at the address loading VX and VY,
+    /// with the nibble in front of a 12 bit address or `None` for `long`.
+    Unpack(usize, Option<u8>),
+}
+
+/// A block of structured control flow.
+enum Block {
+    /// The jump to patch with the end of the `if` or `else` branch.
+    Branch { token: Token, jump: usize },
+    /// The start of a `loop` and the jumps of its `while`s.
+    Loop {
+        token: Token,
+        start: usize,
+        breaks: Vec<usize>,
+    },
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+    calls: usize,
+}
+
+struct Compiler {
+    tokens: Vec<Token>,
+    position: usize,
+    memory: Vec<u8>,
+    here: usize,
+    end: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    uses: HashMap<String, Vec<(Token, Use)>>,
+    blocks: Vec<Block>,
+    macros: HashMap<String, Macro>,
+    // The body and value of every character of a string mode
+    string_modes: HashMap<String, HashMap<char, (usize, Vec<Token>)>>,
+    expansions: usize,
+    /// Whether it is still open if the program starts with a jump to `main`.
+    entry_pending: bool,
+}
+
+impl Compiler {
+    fn new(tokens: Vec<Token>) -> Self {
+        Self {
+            tokens,
+            position: 0,
+            memory: vec![0; XO_CHIP_RAM_SIZE],
+            here: ROM_OFFSET,
+            end: ROM_OFFSET,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            uses: HashMap::new(),
+            blocks: Vec::new(),
+            macros: HashMap::new(),
+            string_modes: HashMap::new(),
+            expansions: 0,
+            entry_pending: true,
+        }
+    }
+
+    fn run(mut self) -> Result<Vec<u8>, AsmError> {
+        while let Some(token) = self.next() {
+            self.statement(token)?;
+        }
+        if self.entry_pending {
+            let token = Token {
+                text: String::new(),
+                line: 1,
+                column: 1,
+            };
+            self.entry(&token)?;
+        }
+
+        if let Some(block) = self.blocks.first() {
+            return Err(match block {
+                Block::Branch { token, .. } => token.error("This if has no end"),
+                Block::Loop { token, .. } => token.error("This loop has no again"),
+            });
+        }
+        if let Some((name, uses)) = self.uses.iter().min_by_key(|(_, uses)| {
+            let (token, _) = &uses[0];
+            (token.line, token.column)
+        }) {
+            return Err(uses[0].0.error(format!("Undefined name: {}", name)));
+        }
+        Ok(self.memory[ROM_OFFSET..self.end].to_vec())
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    /// The next token. `after` is reported if there is none.
+    fn expect(&mut self, after: &Token) -> Result<Token, AsmError> {
+        self.next()
+            .ok_or_else(|| after.error(format!("Unexpected end after {}", after.text)))
+    }
+
+    /// Consumes the next token, which has to be `text`.
+    fn keyword(&mut self, after: &Token, text: &str) -> Result<Token, AsmError> {
+        let token = self.expect(after)?;
+        if token.is(text) {
+            Ok(token)
+        } else {
+            Err(token.error(format!("Expected {}, got {}", text, token.text)))
+        }
+    }
+
+    fn name(&mut self, after: &Token) -> Result<Token, AsmError> {
+        let token = self.expect(after)?;
+        if is_name(&token.text) {
+            Ok(token)
+        } else {
+            Err(token.error(format!("Invalid name: {}", token.text)))
+        }
+    }
+
+    fn register(&mut self, after: &Token) -> Result<u8, AsmError> {
+        let token = self.expect(after)?;
+        self.register_of(&token)
+            .ok_or_else(|| token.error(format!("Expected a register, got {}", token.text)))
+    }
+
+    fn register_of(&self, token: &Token) -> Option<u8> {
+        self.aliases
+            .get(&token.text)
+            .copied()
+            .or_else(|| register(&token.text))
+    }
+
+    /// The value of a number, constant or label.
+    fn lookup(&self, text: &str) -> Option<f64> {
+        number(text)
+            .or_else(|| self.constants.get(text).copied())
+            .or_else(|| self.labels.get(text).map(|&address| address as f64))
+    }
+
+    /// A value that has to be known already, either a single token or a
+    /// `:calc` expression in braces.
+    fn value(&mut self, after: &Token, min: i64, max: i64) -> Result<i64, AsmError> {
+        let token = self.expect(after)?;
+        let value = if token.is("{") {
+            self.position -= 1;
+            self.calc(after)?
+        } else {
+            self.lookup(&token.text)
+                .ok_or_else(|| token.error(format!("Undefined name: {}", token.text)))?
+        };
+        let value = value.floor() as i64;
+        if value < min || value > max {
+            return Err(token.error(format!("Value {} out of range {}..={}", value, min, max)));
+        }
+        Ok(value)
+    }
+
+    /// Negative bytes are stored as two's complement.
+    fn byte(&mut self, after: &Token) -> Result<u8, AsmError> {
+        Ok(self.value(after, -0x80, 0xFF)? as u8)
+    }
+
+    fn nibble(&mut self, after: &Token) -> Result<u8, AsmError> {
+        Ok(self.value(after, 0, 0xF)? as u8)
+    }
+
+    /// An address that may refer to a label defined later, which is recorded
+    /// as `usage`. Returns 0 for those.
+    fn address(&mut self, after: &Token, max: usize, usage: Use) -> Result<u16, AsmError> {
+        let token = self.expect(after)?;
+        self.address_of(&token, max, usage)
+    }
+
+    fn address_of(&mut self, token: &Token, max: usize, usage: Use) -> Result<u16, AsmError> {
+        match self.lookup(&token.text) {
+            Some(value) if (0.0..=max as f64).contains(&value) => Ok(value as u16),
+            Some(value) => Err(token.error(format!("Address {} out of range", value))),
+            None if is_name(&token.text) => {
+                self.uses
+                    .entry(token.text.clone())
+                    .or_default()
+                    .push((token.clone(), usage));
+                Ok(0)
+            }
+            None => Err(token.error(format!("Expected an address, got {}", token.text))),
+        }
+    }
+
+    fn emit(&mut self, token: &Token, bytes: &[u8]) -> Result<(), AsmError> {
+        if self.here + bytes.len() > self.memory.len() {
+            return Err(token.error("Program does not fit into memory"));
+        }
+        self.memory[self.here..self.here + bytes.len()].copy_from_slice(bytes);
+        self.here += bytes.len();
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+
+    fn op(&mut self, token: &Token, op: Op) -> Result<(), AsmError> {
+        self.emit(token, &op.encode())
+    }
+
+    /// Emits an instruction taking the 12 bit address in `token`.
+    fn address_op(&mut self, token: &Token, op: fn(u16) -> Op) -> Result<(), AsmError> {
+        let address = self.address_of(token, 0xFFF, Use::Addr(self.here))?;
+        self.op(token, op(address))
+    }
+
+    fn define_label(&mut self, token: &Token, address: usize) -> Result<(), AsmError> {
+        if self.labels.contains_key(&token.text) || self.constants.contains_key(&token.text) {
+            return Err(token.error(format!("Name defined twice: {}", token.text)));
+        }
+        self.labels.insert(token.text.clone(), address);
+        for (token, usage) in self.uses.remove(&token.text).unwrap_or_default() {
+            self.patch(&token, usage, address)?;
+        }
+        Ok(())
+    }
+
+    fn patch(&mut self, token: &Token, usage: Use, address: usize) -> Result<(), AsmError> {
+        let max = match usage {
+            Use::Long(_) | Use::Unpack(_, None) => 0xFFFF,
+            Use::Addr(_) | Use::Unpack(_, Some(_)) => 0xFFF,
+        };
+        if address > max {
+            return Err(token.error(format!("Address {:#X} out of range", address)));
+        }
+        let [high, low] = (address as u16).to_be_bytes();
+        match usage {
+            Use::Addr(at) => {
+                self.memory[at] = self.memory[at] & 0xF0 | high;
+                self.memory[at + 1] = low;
+            }
+            Use::Long(at) => self.memory[at..at + 2].copy_from_slice(&[high, low]),
+            Use::Unpack(at, nibble) => {
+                self.memory[at + 1] = nibble.map_or(high, |nibble| nibble << 4 | high);
+                self.memory[at + 3] = low;
+            }
+        }
+        Ok(())
+    }
+
+    /// Patches the jump at `jump` to continue at the current address.
+    fn land(&mut self, token: &Token, jump: usize) -> Result<(), AsmError> {
+        self.patch(token, Use::Addr(jump), self.here)
+    }
+
+    /// Puts a jump to `main` in front of the program unless `token` starts
+    /// the definition of `main`.
+    fn entry(&mut self, token: &Token) -> Result<(), AsmError> {
+        self.entry_pending = false;
+        if token.is(":") && self.peek().is_some_and(|name| name.is("main")) {
+            return Ok(());
+        }
+        let main = Token {
+            text: "main".to_string(),
+            ..token.clone()
+        };
+        self.address_op(&main, Op::Jp)
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), AsmError> {
+        let t = &token;
+        let declaration = DECLARATIONS.contains(&token.text.as_str())
+            || self.macros.contains_key(&token.text)
+            || self.string_modes.contains_key(&token.text);
+        if self.entry_pending && !declaration {
+            self.entry(t)?;
+        }
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name(t)?;
+                self.define_label(&name, self.here)?;
+            }
+            ":next" => {
+                let name = self.name(t)?;
+                self.define_label(&name, self.here + 1)?;
+            }
+            ":alias" => {
+                let name = self.name(t)?;
+                let register = self.register(&name)?;
+                self.aliases.insert(name.text, register);
+            }
+            ":const" => {
+                let name = self.name(t)?;
+                let value = self.value(&name, i64::MIN, i64::MAX)?;
+                self.define_constant(&name, value as f64)?;
+            }
+            ":calc" => {
+                let name = self.name(t)?;
+                let value = self.calc(&name)?;
+                self.define_constant(&name, value)?;
+            }
+            ":unpack" => {
+                let nibble = match self.peek() {
+                    Some(long) if long.is("long") => {
+                        self.position += 1;
+                        None
+                    }
+                    _ => Some(self.nibble(t)?),
+                };
+                let (max, usage) = (
+                    if nibble.is_some() { 0xFFF } else { 0xFFFF },
+                    Use::Unpack(self.here, nibble),
+                );
+                let address = self.address(t, max, usage)?;
+                let [high, low] = address.to_be_bytes();
+                let byte = nibble.map_or(high, |nibble| nibble << 4 | high);
+                self.op(t, Op::LdVxByte { x: 0, byte })?;
+                self.op(t, Op::LdVxByte { x: 1, byte: low })?;
+            }
+            ":org" => {
+                let address = self.value(t, ROM_OFFSET as i64, XO_CHIP_RAM_SIZE as i64)?;
+                self.here = address as usize;
+            }
+            ":byte" => {
+                let byte = self.byte(t)?;
+                self.emit(t, &[byte])?;
+            }
+            ":pointer" => {
+                let address = self.address(t, 0xFFFF, Use::Long(self.here))?;
+                self.emit(t, &address.to_be_bytes())?;
+            }
+            ":call" => {
+                let target = self.expect(t)?;
+                self.address_op(&target, Op::Call)?;
+            }
+            ":macro" => self.define_macro(t)?,
+            ":stringmode" => self.define_string_mode(t)?,
+            ":assert" => {
+                let message = match self.peek().and_then(Token::string) {
+                    Some(message) => {
+                        let message = message.to_string();
+                        self.position += 1;
+                        message
+                    }
+                    None => "Assertion failed".to_string(),
+                };
+                if self.calc(t)? == 0.0 {
+                    return Err(token.error(message));
+                }
+            }
+            ":breakpoint" => {
+                self.name(t)?;
+            }
+            ":monitor" => {
+                self.expect(t)?;
+                self.expect(t)?;
+            }
+
+            ";" | "return" => self.op(t, Op::Ret)?,
+            "clear" => self.op(t, Op::Cls)?,
+            "hires" => self.op(t, Op::High)?,
+            "lores" => self.op(t, Op::Low)?,
+            "exit" => self.op(t, Op::Exit)?,
+            "audio" => self.op(t, Op::Audio)?,
+            "scroll-left" => self.op(t, Op::ScrollLeft)?,
+            "scroll-right" => self.op(t, Op::ScrollRight)?,
+            "scroll-down" => {
+                let n = self.nibble(t)?;
+                self.op(t, Op::ScrollDown(n))?;
+            }
+            "scroll-up" => {
+                let n = self.nibble(t)?;
+                self.op(t, Op::ScrollUp(n))?;
+            }
+            "plane" => {
+                let n = self.nibble(t)?;
+                self.op(t, Op::Plane(n))?;
+            }
+            "bcd" => {
+                let x = self.register(t)?;
+                self.op(t, Op::LdBVx(x))?;
+            }
+            "saveflags" => {
+                let x = self.register(t)?;
+                self.op(t, Op::StoreFlags(x))?;
+            }
+            "loadflags" => {
+                let x = self.register(t)?;
+                self.op(t, Op::LoadFlags(x))?;
+            }
+            "save" | "load" => {
+                let x = self.register(t)?;
+                let op = if self.peek().is_some_and(|dash| dash.is("-")) {
+                    self.position += 1;
+                    let y = self.register(t)?;
+                    if token.is("save") {
+                        Op::SaveRange { x, y }
+                    } else {
+                        Op::LoadRange { x, y }
+                    }
+                } else if token.is("save") {
+                    Op::StoreRegs(x)
+                } else {
+                    Op::LoadRegs(x)
+                };
+                self.op(t, op)?;
+            }
+            "sprite" => {
+                let x = self.register(t)?;
+                let y = self.register(t)?;
+                let n = self.nibble(t)?;
+                self.op(t, Op::Drw { x, y, n })?;
+            }
+            "jump" | "jump0" | "native" => {
+                let target = self.expect(t)?;
+                let op = match token.text.as_str() {
+                    "jump" => Op::Jp,
+                    "jump0" => Op::JpOffset,
+                    _ => Op::Sys,
+                };
+                self.address_op(&target, op)?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.keyword(t, ":=")?;
+                let x = self.register(t)?;
+                let op = match token.text.as_str() {
+                    "delay" => Op::LdDtVx(x),
+                    "buzzer" => Op::LdStVx(x),
+                    _ => Op::Pitch(x),
+                };
+                self.op(t, op)?;
+            }
+            "i" => self.index(t)?,
+
+            "if" => {
+                // The condition is followed by `then` or `begin`
+                let length = match self.tokens.get(self.position + 1) {
+                    Some(op) if op.is("key") || op.is("-key") => 2,
+                    _ => 3,
+                };
+                let end = self
+                    .tokens
+                    .get(self.position + length)
+                    .cloned()
+                    .ok_or_else(|| token.error("Expected a condition and then or begin"))?;
+                if end.is("then") {
+                    self.condition(t, false)?;
+                } else if end.is("begin") {
+                    // Jumps to the else branch unless the condition holds
+                    self.condition(t, true)?;
+                    self.blocks.push(Block::Branch {
+                        token: token.clone(),
+                        jump: self.here,
+                    });
+                    self.op(t, Op::Jp(0))?;
+                } else {
+                    return Err(end.error(format!("Expected then or begin, got {}", end.text)));
+                }
+                self.position += 1;
+            }
+            "else" => {
+                let Some(Block::Branch { jump, .. }) = self.blocks.pop() else {
+                    return Err(token.error("else without if ... begin"));
+                };
+                self.blocks.push(Block::Branch {
+                    token: token.clone(),
+                    jump: self.here,
+                });
+                self.op(t, Op::Jp(0))?;
+                self.land(t, jump)?;
+            }
+            "end" => {
+                let Some(Block::Branch { jump, .. }) = self.blocks.pop() else {
+                    return Err(token.error("end without if ... begin"));
+                };
+                self.land(t, jump)?;
+            }
+            "loop" => self.blocks.push(Block::Loop {
+                token: token.clone(),
+                start: self.here,
+                breaks: Vec::new(),
+            }),
+            "while" => {
+                self.condition(t, true)?;
+                let jump = self.here;
+                let Some(Block::Loop { breaks, .. }) = self
+                    .blocks
+                    .iter_mut()
+                    .rev()
+                    .find(|block| matches!(block, Block::Loop { .. }))
+                else {
+                    return Err(token.error("while outside of a loop"));
+                };
+                breaks.push(jump);
+                self.op(t, Op::Jp(0))?;
+            }
+            "again" => {
+                let Some(Block::Loop { start, breaks, .. }) = self.blocks.pop() else {
+                    return Err(token.error("again without loop"));
+                };
+                let jump = self.here;
+                self.op(t, Op::Jp(0))?;
+                self.patch(t, Use::Addr(jump), start)?;
+                for jump in breaks {
+                    self.land(t, jump)?;
+                }
+            }
+
+            _ => {
+                if let Some(x) = self.register_of(&token) {
+                    self.assignment(t, x)?;
+                } else if self.macros.contains_key(&token.text) {
+                    self.expand_macro(t)?;
+                } else if self.string_modes.contains_key(&token.text) {
+                    self.expand_string_mode(t)?;
+                } else if let Some(value) =
+                    number(&token.text).or_else(|| self.constants.get(&token.text).copied())
+                {
+                    let value = value.floor() as i64;
+                    if !(-0x80..=0xFF).contains(&value) {
+                        return Err(token.error(format!("Value {} out of range -128..=255", value)));
+                    }
+                    self.emit(t, &[value as u8])?;
+                } else if is_name(&token.text) {
+                    // A label on its own calls the subroutine
+                    self.address_op(t, Op::Call)?;
+                } else {
+                    return Err(token.error(format!("Unexpected {}", token.text)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn define_constant(&mut self, token: &Token, value: f64) -> Result<(), AsmError> {
+        if self.labels.contains_key(&token.text) {
+            return Err(token.error(format!("Name defined twice: {}", token.text)));
+        }
+        self.constants.insert(token.text.clone(), value);
+        Ok(())
+    }
+
+    /// `i := ...` and `i += vx`.
+    fn index(&mut self, token: &Token) -> Result<(), AsmError> {
+        let operator = self.expect(token)?;
+        if operator.is("+=") {
+            let x = self.register(token)?;
+            return self.op(token, Op::AddIVx(x));
+        }
+        if !operator.is(":=") {
+            return Err(operator.error(format!("Expected := or +=, got {}", operator.text)));
+        }
+        let value = self.expect(token)?;
+        match value.text.as_str() {
+            "hex" => {
+                let x = self.register(token)?;
+                self.op(token, Op::LdFVx(x))
+            }
+            "bighex" => {
+                let x = self.register(token)?;
+                self.op(token, Op::LdHfVx(x))
+            }
+            "long" => {
+                let address = self.address(token, 0xFFFF, Use::Long(self.here + 2))?;
+                self.op(token, Op::LdILong)?;
+                self.emit(token, &address.to_be_bytes())
+            }
+            _ => self.address_op(&value, Op::LdI),
+        }
+    }
+
+    /// `vx := ...`, `vx += ...` and the other operations on a register.
+    fn assignment(&mut self, token: &Token, x: u8) -> Result<(), AsmError> {
+        let operator = self.expect(token)?;
+        let source = self.expect(&operator)?;
+        let y = self.register_of(&source);
+        let op = match (operator.text.as_str(), y) {
+            (":=", Some(y)) => Op::LdVxVy { x, y },
+            (":=", None) if source.is("random") => Op::Rnd {
+                x,
+                byte: self.byte(&source)?,
+            },
+            (":=", None) if source.is("key") => Op::LdVxK(x),
+            (":=", None) if source.is("delay") => Op::LdVxDt(x),
+            ("+=", Some(y)) => Op::AddVxVy { x, y },
+            ("-=", Some(y)) => Op::Sub { x, y },
+            ("=-", Some(y)) => Op::Subn { x, y },
+            ("|=", Some(y)) => Op::Or { x, y },
+            ("&=", Some(y)) => Op::And { x, y },
+            ("^=", Some(y)) => Op::Xor { x, y },
+            (">>=", Some(y)) => Op::Shr { x, y },
+            ("<<=", Some(y)) => Op::Shl { x, y },
+            (":=" | "+=" | "-=", None) => {
+                self.position -= 1;
+                let byte = self.byte(&operator)?;
+                match operator.text.as_str() {
+                    ":=" => Op::LdVxByte { x, byte },
+                    "+=" => Op::AddVxByte { x, byte },
+                    _ => Op::AddVxByte {
+                        x,
+                        byte: byte.wrapping_neg(),
+                    },
+                }
+            }
+            ("=-" | "|=" | "&=" | "^=" | ">>=" | "<<=", None) => {
+                return Err(source.error(format!("Expected a register, got {}", source.text)))
+            }
+            _ => {
+                return Err(operator.error(format!("Unknown operator: {}", operator.text)));
+            }
+        };
+        self.op(token, op)
+    }
+
+    /// Emits instructions that skip the next one unless the condition holds,
+    /// or if it holds if `negate` is true.
+    fn condition(&mut self, token: &Token, negate: bool) -> Result<(), AsmError> {
+        let x = self.register(token)?;
+        let operator = self.expect(token)?;
+        let text = match (negate, operator.text.as_str()) {
+            (true, "==") => "!=",
+            (true, "!=") => "==",
+            (true, "<") => ">=",
+            (true, ">=") => "<",
+            (true, ">") => "<=",
+            (true, "<=") => ">",
+            (true, "key") => "-key",
+            (true, "-key") => "key",
+            (_, text) => text,
+        };
+        match text {
+            "key" => return self.op(token, Op::Sknp(x)),
+            "-key" => return self.op(token, Op::Skp(x)),
+            _ => {}
+        }
+
+        let source = self.expect(&operator)?;
+        let y = match self.register_of(&source) {
+            Some(y) => Ok(y),
+            None => {
+                self.position -= 1;
+                Err(self.byte(&operator)?)
+            }
+        };
+        const VF: u8 = 0xF;
+        // VF is set to whether VX >= Y, or Y >= VX for `>` and `<=`
+        let compare = |compiler: &mut Self, y_first: bool| {
+            let (load, subtract) = match (y, y_first) {
+                (Ok(y), false) => (Op::LdVxVy { x: VF, y: x }, Op::Sub { x: VF, y }),
+                (Err(byte), false) => (Op::LdVxByte { x: VF, byte }, Op::Subn { x: VF, y: x }),
+                (Ok(y), true) => (Op::LdVxVy { x: VF, y }, Op::Sub { x: VF, y: x }),
+                (Err(byte), true) => (Op::LdVxByte { x: VF, byte }, Op::Sub { x: VF, y: x }),
+            };
+            compiler.op(token, load)?;
+            compiler.op(token, subtract)
+        };
+        match (text, y) {
+            ("==", Ok(y)) => self.op(token, Op::SneVxVy { x, y }),
+            ("==", Err(byte)) => self.op(token, Op::SneVxByte { x, byte }),
+            ("!=", Ok(y)) => self.op(token, Op::SeVxVy { x, y }),
+            ("!=", Err(byte)) => self.op(token, Op::SeVxByte { x, byte }),
+            ("<" | ">=" | ">" | "<=", _) => {
+                compare(self, text == ">" || text == "<=")?;
+                let byte = u8::from(text == "<" || text == ">");
+                self.op(token, Op::SeVxByte { x: VF, byte })
+            }
+            _ => Err(operator.error(format!("Unknown comparison: {}", operator.text))),
+        }
+    }
+
+    /// The tokens inside the braces following `after`.
+    fn braces(&mut self, after: &Token) -> Result<Vec<Token>, AsmError> {
+        let open = self.keyword(after, "{")?;
+        let mut depth = 1;
+        let mut tokens = Vec::new();
+        loop {
+            let token = self
+                .next()
+                .ok_or_else(|| open.error("This { is never closed"))?;
+            if token.is("{") {
+                depth += 1;
+            } else if token.is("}") {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(tokens);
+                }
+            }
+            tokens.push(token);
+        }
+    }
+
+    fn define_macro(&mut self, token: &Token) -> Result<(), AsmError> {
+        let name = self.name(token)?;
+        let mut args = Vec::new();
+        while self.peek().is_some_and(|token| !token.is("{")) {
+            args.push(self.name(&name)?.text);
+        }
+        let body = self.braces(&name)?;
+        self.macros.insert(
+            name.text,
+            Macro {
+                args,
+                body,
+                calls: 0,
+            },
+        );
+        Ok(())
+    }
+
+    /// Replaces the arguments of the macro `token` by the tokens following it.
+    fn expand_macro(&mut self, token: &Token) -> Result<(), AsmError> {
+        let count = self.macros[&token.text].args.len();
+        let mut args = Vec::new();
+        for _ in 0..count {
+            args.push(self.expect(token)?.text);
+        }
+        let calls = self.macros[&token.text].calls;
+        let mut bindings: HashMap<String, String> = self.macros[&token.text]
+            .args
+            .iter()
+            .cloned()
+            .zip(args)
+            .collect();
+        bindings.insert("CALLS".to_string(), calls.to_string());
+        let body = self.macros[&token.text].body.clone();
+        self.macros.get_mut(&token.text).unwrap().calls += 1;
+        self.insert(token, body, &bindings)
+    }
+
+    fn define_string_mode(&mut self, token: &Token) -> Result<(), AsmError> {
+        let name = self.name(token)?;
+        let alphabet = self.expect(&name)?;
+        let Some(chars) = alphabet.string() else {
+            return Err(alphabet.error("Expected the characters in quotes"));
+        };
+        let chars: Vec<char> = chars.chars().collect();
+        let body = self.braces(&name)?;
+        let mode = self.string_modes.entry(name.text).or_default();
+        for (value, char) in chars.into_iter().enumerate() {
+            mode.insert(char, (value, body.clone()));
+        }
+        Ok(())
+    }
+
+    /// Expands the body of the string mode `token` for every character of the
+    /// string following it.
+    fn expand_string_mode(&mut self, token: &Token) -> Result<(), AsmError> {
+        let string = self.expect(token)?;
+        let Some(text) = string.string() else {
+            return Err(string.error("Expected a string"));
+        };
+        let mut expanded = Vec::new();
+        for (index, char) in text.chars().enumerate() {
+            let (value, body) = self.string_modes[&token.text]
+                .get(&char)
+                .ok_or_else(|| string.error(format!("{} can't show {:?}", token.text, char)))?;
+            let bindings = HashMap::from([
+                ("CHAR".to_string(), u32::from(char).to_string()),
+                ("INDEX".to_string(), index.to_string()),
+                ("VALUE".to_string(), value.to_string()),
+            ]);
+            expanded.extend(body.iter().map(|token| substitute(token, &bindings)));
+        }
+        self.insert(token, expanded, &HashMap::new())
+    }
+
+    /// Inserts `body` with `bindings` substituted as the next tokens.
+    fn insert(
+        &mut self,
+        token: &Token,
+        body: Vec<Token>,
+        bindings: &HashMap<String, String>,
+    ) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(token.error("Too many macro expansions, is a macro recursive?"));
+        }
+        let body = body.iter().map(|token| substitute(token, bindings));
+        self.tokens.splice(self.position..self.position, body);
+        Ok(())
+    }
+
+    /// Evaluates the `:calc` expression in the braces following `after`.
+    fn calc(&mut self, after: &Token) -> Result<f64, AsmError> {
+        let tokens = self.braces(after)?;
+        let mut position = 0;
+        let value = self.expression(after, &tokens, &mut position)?;
+        match tokens.get(position) {
+            Some(token) => Err(token.error(format!("Unexpected {}", token.text))),
+            None => Ok(value),
+        }
+    }
+
+    fn expression(
+        &self,
+        after: &Token,
+        tokens: &[Token],
+        position: &mut usize,
+    ) -> Result<f64, AsmError> {
+        let token = tokens
+            .get(*position)
+            .ok_or_else(|| after.error("Expected a value"))?;
+        *position += 1;
+
+        let unary: Option<fn(f64) -> f64> = match token.text.as_str() {
+            "-" => Some(|value| -value),
+            "~" => Some(|value| !(value as i64) as f64),
+            "!" => Some(|value| f64::from(u8::from(value == 0.0))),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(|value: f64| if value == 0.0 { 0.0 } else { value.signum() }),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(unary) = unary {
+            return Ok(unary(self.expression(token, tokens, position)?));
+        }
+        if token.is("@") {
+            let address = self.expression(token, tokens, position)?;
+            return Ok(self
+                .memory
+                .get(address as usize)
+                .map_or(0.0, |&byte| byte.into()));
+        }
+        if token.is("strlen") {
+            let string = tokens
+                .get(*position)
+                .and_then(Token::string)
+                .ok_or_else(|| token.error("Expected a string"))?;
+            *position += 1;
+            return Ok(string.chars().count() as f64);
+        }
+
+        let left = match token.text.as_str() {
+            "(" => {
+                let value = self.expression(token, tokens, position)?;
+                match tokens.get(*position) {
+                    Some(close) if close.is(")") => *position += 1,
+                    _ => return Err(token.error("This ( is never closed")),
+                }
+                value
+            }
+            "HERE" => self.here as f64,
+            "PI" => consts::PI,
+            "E" => consts::E,
+            text => match text.parse::<f64>() {
+                Ok(value) if text.starts_with(|char: char| char.is_ascii_digit()) => value,
+                _ => self
+                    .lookup(text)
+                    .ok_or_else(|| token.error(format!("Undefined name: {}", text)))?,
+            },
+        };
+
+        let Some(operator) = tokens.get(*position).filter(|token| !token.is(")")) else {
+            return Ok(left);
+        };
+        *position += 1;
+        let right = self.expression(operator, tokens, position)?;
+        let (a, b) = (left as i64, right as i64);
+        let boolean = |value: bool| f64::from(u8::from(value));
+        Ok(match operator.text.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => a.checked_shl(b as u32).unwrap_or(0) as f64,
+            ">>" => a.checked_shr(b as u32).unwrap_or(0) as f64,
+            "<" => boolean(left < right),
+            ">" => boolean(left > right),
+            "<=" => boolean(left <= right),
+            ">=" => boolean(left >= right),
+            "==" => boolean(left == right),
+            "!=" => boolean(left != right),
+            _ => return Err(operator.error(format!("Unknown operator: {}", operator.text))),
+        })
+    }
+}
+
+/// Replaces `token` if it is bound in `bindings`.
+fn substitute(token: &Token, bindings: &HashMap<String, String>) -> Token {
+    match bindings.get(&token.text) {
+        Some(text) => Token {
+            text: text.clone(),
+            ..token.clone()
+        },
+        None => token.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        disasm::{disassemble, format_op, Syntax},
+        display::NullRenderer,
+        emulator::Emulator,
+        platform::Platform,
+        quirks::Quirks,
+    };
+    use std::collections::BTreeMap;
+
+    /// Runs `source`, which ends in an endless loop, and returns V0.
+    fn run(source: &str) -> u8 {
+        let rom = compile(source).unwrap();
+        let mut emulator = Emulator::new(NullRenderer, 600, Platform::XoChip, Quirks::XO_CHIP);
+        emulator.load_rom(&rom).unwrap();
+        for _ in 0..1000 {
+            emulator.step().unwrap();
+        }
+        emulator.cpu.get_register(0).unwrap()
+    }
+
+    #[test]
+    fn test_statements() {
+        let rom = compile(": main\nclear v1 := 0x20 sprite v1 v2 5 i := long 0x1234").unwrap();
+        assert_eq!(
+            rom,
+            [0x00, 0xE0, 0x61, 0x20, 0xD1, 0x25, 0xF0, 0x00, 0x12, 0x34]
+        );
+        let rom = compile(": main v3 -= 1 v3 =- v4 save v1 - v3 load v2 :byte 7 -1").unwrap();
+        assert_eq!(
+            rom,
+            [0x73, 0xFF, 0x83, 0x47, 0x51, 0x32, 0xF2, 0x65, 7, 0xFF]
+        );
+    }
+
+    /// Starts with data, so a jump to `main` is put in front of it.
+    const LABELS: &str = "
+        : shape 0xF0 0x90   # data
+        : main
+            i := shape
+            draw
+            :call draw
+            jump main
+        : draw
+            :unpack 0xA shape
+            i := long far
+            return
+        :org 0x300
+        : far 0x01
+    ";
+
+    #[test]
+    fn test_labels() {
+        let rom = compile(LABELS).unwrap();
+        assert_eq!(rom[..4], [0x12, 0x04, 0xF0, 0x90]);
+        assert_eq!(rom[4..12], [0xA2, 0x02, 0x22, 0x0C, 0x22, 0x0C, 0x12, 0x04]);
+        assert_eq!(
+            rom[12..22],
+            [0x60, 0xA2, 0x61, 0x02, 0xF0, 0x00, 0x03, 0x00, 0x00, 0xEE]
+        );
+        assert_eq!(rom.len(), 0x101);
+        assert_eq!(rom[0x100], 0x01);
+    }
+
+    #[test]
+    fn test_control_flow() {
+        // Sums 1 to 10 and doubles if the sum is 55
+        let source = "
+            : main
+                v1 := 0
+                loop
+                    v1 += 1
+                    v0 += v1
+                    while v1 != 10
+                again
+                if v0 == 55 begin
+                    v0 += v0
+                else
+                    v0 := 0
+                end
+            : done
+                jump done
+        ";
+        assert_eq!(run(source), 110);
+    }
+
+    #[test]
+    fn test_comparisons() {
+        for (a, op, b, result) in [
+            (3, "<", 5, true),
+            (5, "<", 5, false),
+            (5, ">", 3, true),
+            (5, ">", 5, false),
+            (5, "<=", 5, true),
+            (6, "<=", 5, false),
+            (5, ">=", 5, true),
+            (4, ">=", 5, false),
+        ] {
+            for operand in [format!("{}", b), "v2".to_string()] {
+                let source = format!(
+                    ": main v1 := {} v2 := {} v0 := 0 if v1 {} {} then v0 := 1 : done jump done",
+                    a, b, op, operand
+                );
+                assert_eq!(run(&source), u8::from(result), "{}", source);
+                let source = format!(
+                    ": main v1 := {} v2 := {} v0 := 0 if v1 {} {} begin v0 := 1 else v0 := 2 end \
+                     : done jump done",
+                    a, b, op, operand
+                );
+                assert_eq!(run(&source), if result { 1 } else { 2 }, "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn test_directives() {
+        let source = "
+            :const SPEED 3
+            :alias speed v5
+            :calc DOUBLE { SPEED * 2 + 1 }
+            :macro add-twice REG VALUE { REG += VALUE REG += VALUE :byte CALLS }
+            :stringmode text \"AB\" { :byte { VALUE + INDEX * 16 } }
+            : main
+                speed := SPEED
+                add-twice speed DOUBLE
+                add-twice v0 1
+                text \"BAB\"
+                :assert \"Too long\" { HERE < 0x300 }
+                :byte { @ 0x201 }
+        ";
+        let rom = compile(source).unwrap();
+        assert_eq!(
+            rom,
+            [
+                0x65, 0x03, 0x75, 0x09, 0x75, 0x09, 0x00, 0x70, 0x01, 0x70, 0x01, 0x01, 0x01, 0x10,
+                0x21, 0x03
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = compile(": main\n  v0 := missing").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 9));
+        assert_eq!(error.message, "Undefined name: missing");
+
+        let error = compile(": main\n  jump nowhere").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 8));
+
+        let error = compile("clear").unwrap_err();
+        assert_eq!(error.message, "Undefined name: main");
+        let error = compile(": main loop v0 += 1").unwrap_err();
+        assert_eq!(error.message, "This loop has no again");
+        let error = compile(": main : main").unwrap_err();
+        assert_eq!(error.message, "Name defined twice: main");
+        let error = compile(": main v0 := 256").unwrap_err();
+        assert_eq!(error.message, "Value 256 out of range -128..=255");
+        let error = compile(": main :assert \"Nope\" { 1 == 2 }").unwrap_err();
+        assert_eq!(error.message, "Nope");
+        let error = compile(":macro forever { forever } : main forever").unwrap_err();
+        assert!(error.message.starts_with("Too many macro expansions"));
+    }
+
+    #[test]
+    fn test_round_trip_ops() {
+        let labels = BTreeMap::new();
+        for opcode in 0..=u16::MAX {
+            let [first, second] = opcode.to_be_bytes();
+            let op = match Op::decode(first, second) {
+                Ok(Op::LdILong) | Err(_) => continue,
+                Ok(op) => op,
+            };
+            let text = format!(": main {}", format_op(&op, 0, Syntax::Octo, &labels));
+            assert_eq!(compile(&text), Ok(vec![first, second]), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_disassembly() {
+        let rom = compile(LABELS).unwrap();
+        let source = disassemble(&rom, Platform::XoChip).format(Syntax::Octo);
+        assert_eq!(compile(&source), Ok(rom));
+    }
+}
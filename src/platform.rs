@@ -0,0 +1,95 @@
+use alloc::{format, string::String};
+use core::{fmt, str::FromStr};
+
+use crate::{
+    quirks::Quirks,
+    ram::{RAM_SIZE, XO_CHIP_RAM_SIZE},
+    savestate::{Persist, Reader, StateError, Writer},
+};
+
+/// The instruction set a ROM is written for.
+///
+/// Platforms are ordered by the instruction sets they support, every platform
+/// supports the instructions of the platforms before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Platform {
+    /// The original CHIP-8 instruction set.
+    #[default]
+    Chip8,
+    /// SUPER-CHIP 1.1, adding a high resolution mode, scrolling, 16x16
+    /// sprites, a big font and the RPL user flags.
+    SuperChip,
+    /// XO-CHIP as defined by Octo, building on SUPER-CHIP with 64 KiB of
+    /// memory, a second bitplane and programmable audio.
+    XoChip,
+}
+
+impl Platform {
+    /// Whether the SUPER-CHIP instructions are available.
+    pub fn supports_super_chip(self) -> bool {
+        self != Self::Chip8
+    }
+
+    /// Whether the XO-CHIP instructions are available.
+    pub fn supports_xo_chip(self) -> bool {
+        self == Self::XoChip
+    }
+
+    /// The amount of addressable memory.
+    pub fn ram_size(self) -> usize {
+        if self.supports_xo_chip() {
+            XO_CHIP_RAM_SIZE
+        } else {
+            RAM_SIZE
+        }
+    }
+
+    /// The quirks most ROMs written for this platform expect.
+    pub fn default_quirks(self) -> Quirks {
+        match self {
+            Self::Chip8 => Quirks::COSMAC_VIP,
+            Self::SuperChip => Quirks::SUPER_CHIP,
+            Self::XoChip => Quirks::XO_CHIP,
+        }
+    }
+}
+
+impl Persist for Platform {
+    fn save(&self, out: &mut Writer) {
+        out.u8(*self as u8);
+    }
+
+    fn restore(input: &mut Reader) -> Result<Self, StateError> {
+        match input.u8()? {
+            0 => Ok(Self::Chip8),
+            1 => Ok(Self::SuperChip),
+            2 => Ok(Self::XoChip),
+            _ => Err(StateError::Invalid("platform")),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Chip8 => "chip8",
+            Self::SuperChip => "schip",
+            Self::XoChip => "xochip",
+        })
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    /// Parses the names produced by the [`Display`](fmt::Display)
+    /// implementation.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chip8" => Ok(Self::Chip8),
+            "schip" => Ok(Self::SuperChip),
+            "xochip" => Ok(Self::XoChip),
+            _ => Err(format!("Unknown platform: {}", s)),
+        }
+    }
+}
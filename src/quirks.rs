@@ -0,0 +1,115 @@
+use alloc::{format, string::String};
+use core::str::FromStr;
+
+use crate::savestate::{Persist, Reader, StateError, Writer};
+
+/// Behavioural differences between the various CHIP-8 interpreters.
+///
+/// Over the years CHIP-8 was reimplemented for several platforms and each
+/// of them changed the semantics of a few instructions slightly. ROMs are
+/// usually written with one of these interpreters in mind, so the presets
+/// below should be picked according to the platform a ROM targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// `8XY6`/`8XYE` shift VY and store the result in VX. If this is false
+    /// VX is shifted in place and VY is ignored.
+    pub shift_uses_vy: bool,
+    /// `BNNN` jumps to NNN + V0. If this is true the instruction is
+    /// interpreted as `BXNN` and jumps to XNN + VX instead.
+    pub jump_uses_vx: bool,
+    /// `FX55`/`FX65` leave I pointing past the last accessed address.
+    pub load_store_increments_i: bool,
+    /// `8XY1`/`8XY2`/`8XY3` reset VF to zero.
+    pub vf_reset: bool,
+    /// Sprites drawn past the edge of the screen are clipped. If this is
+    /// false they wrap around to the opposite edge.
+    pub clip_sprites: bool,
+    /// `DXYN` waits for the next vertical blank (i.e. the next timer tick)
+    /// before execution continues.
+    pub display_wait: bool,
+}
+
+impl Quirks {
+    /// The original interpreter running on the COSMAC VIP.
+    pub const COSMAC_VIP: Self = Self {
+        shift_uses_vy: true,
+        jump_uses_vx: false,
+        load_store_increments_i: true,
+        vf_reset: true,
+        clip_sprites: true,
+        display_wait: true,
+    };
+
+    /// CHIP-48 for the HP-48 graphing calculators.
+    pub const CHIP_48: Self = Self {
+        shift_uses_vy: false,
+        jump_uses_vx: true,
+        load_store_increments_i: true,
+        vf_reset: false,
+        clip_sprites: true,
+        display_wait: false,
+    };
+
+    /// SUPER-CHIP 1.1, the successor of CHIP-48.
+    pub const SUPER_CHIP: Self = Self {
+        shift_uses_vy: false,
+        jump_uses_vx: true,
+        load_store_increments_i: false,
+        vf_reset: false,
+        clip_sprites: true,
+        display_wait: false,
+    };
+
+    /// XO-CHIP as implemented by Octo.
+    pub const XO_CHIP: Self = Self {
+        shift_uses_vy: true,
+        jump_uses_vx: false,
+        load_store_increments_i: true,
+        vf_reset: false,
+        clip_sprites: false,
+        display_wait: false,
+    };
+}
+
+impl FromStr for Quirks {
+    type Err = String;
+
+    /// Parses the name of a preset: `vip`, `chip48`, `schip` or `xochip`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vip" => Ok(Self::COSMAC_VIP),
+            "chip48" => Ok(Self::CHIP_48),
+            "schip" => Ok(Self::SUPER_CHIP),
+            "xochip" => Ok(Self::XO_CHIP),
+            _ => Err(format!("Unknown quirks: {}", s)),
+        }
+    }
+}
+
+impl Persist for Quirks {
+    fn save(&self, out: &mut Writer) {
+        out.bool(self.shift_uses_vy);
+        out.bool(self.jump_uses_vx);
+        out.bool(self.load_store_increments_i);
+        out.bool(self.vf_reset);
+        out.bool(self.clip_sprites);
+        out.bool(self.display_wait);
+    }
+
+    fn restore(input: &mut Reader) -> Result<Self, StateError> {
+        Ok(Self {
+            shift_uses_vy: input.bool()?,
+            jump_uses_vx: input.bool()?,
+            load_store_increments_i: input.bool()?,
+            vf_reset: input.bool()?,
+            clip_sprites: input.bool()?,
+            display_wait: input.bool()?,
+        })
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self::COSMAC_VIP
+    }
+}
@@ -1,21 +1,80 @@
-use anyhow::{bail, Result};
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::ops::RangeInclusive;
+
+use crate::{
+    cpu::CpuError,
+    savestate::{Persist, Reader, StateError, Writer},
+};
 
 pub const RAM_SIZE: usize = 4096;
+/// XO-CHIP extends the address space to 64 KiB.
+pub const XO_CHIP_RAM_SIZE: usize = 0x10000;
 
 /// Ram is a safe wrapper to access an array serving as memory for the emulator.
 /// Addresses are checked for validity to prevent panics when indexing the array
-/// out of bounds.
-pub struct Ram([u8; RAM_SIZE]);
+/// out of bounds. Only the addressable bytes are allocated, so CHIP-8 and
+/// SUPER-CHIP don't pay for the memory of XO-CHIP.
+pub struct Ram {
+    memory: Box<[u8]>,
+    // Boxed and optional so writes only pay for a single check while nothing
+    // is watched
+    watchpoints: Option<Box<Watchpoints>>,
+}
+
+/// A write to a watched address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub address: usize,
+    pub old: u8,
+    pub new: u8,
+}
+
+#[derive(Default)]
+struct Watchpoints {
+    ranges: Vec<RangeInclusive<usize>>,
+    hit: Option<WatchHit>,
+}
 
 impl Ram {
+    /// Creates a new [`Ram`] with `size` addressable bytes.
+    ///
+    /// # Panics
+    /// Panics if `size` is bigger than [`XO_CHIP_RAM_SIZE`].
+    pub fn new(size: usize) -> Self {
+        assert!(size <= XO_CHIP_RAM_SIZE, "Ram size too big: {}", size);
+        Self {
+            memory: vec![0; size].into_boxed_slice(),
+            watchpoints: None,
+        }
+    }
+
+    /// The amount of addressable bytes.
+    pub fn size(&self) -> usize {
+        self.memory.len()
+    }
+
     /// Updates the value at an address.
     ///
     /// # Errors
     /// An error might occur when the address is not in the bounds of the
     /// memory.
-    pub fn set(&mut self, address: usize, value: u8) -> Result<()> {
-        is_valid_address(address)?;
-        self.0[address] = value;
+    pub fn set(&mut self, address: usize, value: u8) -> Result<(), CpuError> {
+        self.is_valid_address(address)?;
+        if let Some(watchpoints) = &mut self.watchpoints {
+            if watchpoints.hit.is_none()
+                && watchpoints
+                    .ranges
+                    .iter()
+                    .any(|range| range.contains(&address))
+            {
+                watchpoints.hit = Some(WatchHit {
+                    address,
+                    old: self.memory[address],
+                    new: value,
+                });
+            }
+        }
+        self.memory[address] = value;
         Ok(())
     }
 
@@ -24,31 +83,92 @@ impl Ram {
     /// # Errors
     /// An error might occur when the address is not in the bounds of the
     /// memory.
-    pub fn get(&self, address: usize) -> Result<u8> {
-        is_valid_address(address)?;
-        Ok(self.0[address])
+    pub fn get(&self, address: usize) -> Result<u8, CpuError> {
+        self.is_valid_address(address)?;
+        Ok(self.memory[address])
     }
 
     /// Might be removed soon.
-    pub fn get_slice(&self, address: usize, length: usize) -> Result<&[u8]> {
-        is_valid_address(address)?;
-        is_valid_address(address + length)?;
-        Ok(&self.0[address..(address + length)])
+    pub fn get_slice(&self, address: usize, length: usize) -> Result<&[u8], CpuError> {
+        self.is_valid_address(address)?;
+        self.is_valid_address(address + length)?;
+        Ok(&self.memory[address..(address + length)])
+    }
+
+    /// Records writes to the addresses in `range`. See [`Ram::take_watch_hit`].
+    pub fn watch(&mut self, range: RangeInclusive<usize>) {
+        self.watchpoints
+            .get_or_insert_with(Default::default)
+            .ranges
+            .push(range);
+    }
+
+    /// Stops watching a range given to [`Ram::watch`]. Returns whether it
+    /// was watched.
+    pub fn unwatch(&mut self, range: &RangeInclusive<usize>) -> bool {
+        let Some(watchpoints) = &mut self.watchpoints else {
+            return false;
+        };
+        let count = watchpoints.ranges.len();
+        watchpoints.ranges.retain(|watched| watched != range);
+        let removed = watchpoints.ranges.len() != count;
+        if watchpoints.ranges.is_empty() {
+            self.watchpoints = None;
+        }
+        removed
+    }
+
+    /// The watched address ranges.
+    pub fn watched(&self) -> &[RangeInclusive<usize>] {
+        self.watchpoints
+            .as_ref()
+            .map_or(&[], |watchpoints| &watchpoints.ranges)
+    }
+
+    /// Returns the first write to a watched address since the last call.
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watchpoints.as_mut()?.hit.take()
+    }
+
+    /// Moves the watchpoints of `other` to this [`Ram`], e.g. when the
+    /// memory is replaced by a save state.
+    pub(crate) fn take_watchpoints(&mut self, other: &mut Ram) {
+        self.watchpoints = other.watchpoints.take();
+    }
+
+    /// Checks if an address is outside of the addressable memory.
+    fn is_valid_address(&self, address: usize) -> Result<(), CpuError> {
+        if address >= self.size() {
+            Err(CpuError::OutOfBounds {
+                address,
+                size: self.size(),
+            })
+        } else {
+            Ok(())
+        }
     }
 }
 
-impl Default for Ram {
-    fn default() -> Self {
-        Self([0u8; RAM_SIZE])
+impl Persist for Ram {
+    fn save(&self, out: &mut Writer) {
+        out.u32(self.size() as u32);
+        out.bytes(&self.memory);
+    }
+
+    fn restore(input: &mut Reader) -> Result<Self, StateError> {
+        let size = input.u32()? as usize;
+        if size > XO_CHIP_RAM_SIZE {
+            return Err(StateError::Invalid("memory size"));
+        }
+        let mut ram = Self::new(size);
+        ram.memory.copy_from_slice(input.bytes(size)?);
+        Ok(ram)
     }
 }
 
-/// Checks if an address would panic if accessed.
-fn is_valid_address(address: usize) -> Result<()> {
-    if address >= RAM_SIZE {
-        bail!("Address out of bounds: {} >= {}", address, RAM_SIZE);
-    } else {
-        Ok(())
+impl Default for Ram {
+    fn default() -> Self {
+        Self::new(RAM_SIZE)
     }
 }
 
@@ -58,14 +178,28 @@ mod tests {
 
     #[test]
     fn test_invalid_addresses() {
-        assert!(is_valid_address(RAM_SIZE).is_err());
-        assert!(is_valid_address(RAM_SIZE + 10).is_err());
+        let ram = Ram::default();
+        assert!(ram.is_valid_address(RAM_SIZE).is_err());
+        assert!(ram.is_valid_address(RAM_SIZE + 10).is_err());
     }
 
     #[test]
     fn test_valid_addresses() {
-        assert!(is_valid_address(0).is_ok());
-        assert!(is_valid_address(RAM_SIZE - 1).is_ok());
+        let ram = Ram::default();
+        assert!(ram.is_valid_address(0).is_ok());
+        assert!(ram.is_valid_address(RAM_SIZE - 1).is_ok());
+    }
+
+    #[test]
+    fn test_xo_chip_addresses() {
+        let ram = Ram::new(XO_CHIP_RAM_SIZE);
+        assert!(ram.is_valid_address(RAM_SIZE).is_ok());
+        assert!(ram.is_valid_address(XO_CHIP_RAM_SIZE - 1).is_ok());
+        assert!(ram.is_valid_address(XO_CHIP_RAM_SIZE).is_err());
+        assert_eq!(
+            (ram.size(), Ram::default().size()),
+            (XO_CHIP_RAM_SIZE, RAM_SIZE)
+        );
     }
 
     #[test]
@@ -81,4 +215,28 @@ mod tests {
         assert!(ram.get_slice(0, RAM_SIZE).is_err());
         assert!(ram.get_slice(RAM_SIZE / 2, RAM_SIZE + 5).is_err());
     }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut ram = Ram::default();
+        ram.set(0x300, 1).unwrap();
+        ram.watch(0x300..=0x302);
+        ram.set(0x2FF, 1).unwrap();
+        assert_eq!(ram.take_watch_hit(), None);
+
+        ram.set(0x301, 5).unwrap();
+        ram.set(0x300, 7).unwrap();
+        let hit = WatchHit {
+            address: 0x301,
+            old: 0,
+            new: 5,
+        };
+        assert_eq!(ram.take_watch_hit(), Some(hit));
+        assert_eq!(ram.take_watch_hit(), None);
+
+        assert!(ram.unwatch(&(0x300..=0x302)));
+        assert!(ram.watched().is_empty());
+        ram.set(0x300, 2).unwrap();
+        assert_eq!(ram.take_watch_hit(), None);
+    }
 }
@@ -0,0 +1,277 @@
+//! A ring buffer of save states to step backwards through time.
+
+use alloc::{collections::VecDeque, vec::Vec};
+
+/// Every this many snapshots a complete state is stored. The others only
+/// store the bytes which differ from the last complete state.
+const KEYFRAME_INTERVAL: usize = 30;
+
+enum Snapshot {
+    Keyframe(Vec<u8>),
+    /// Runs of the form `equal: u32, different: u32, bytes[different]`
+    /// relative to the previous keyframe.
+    Delta(Vec<u8>),
+}
+
+impl Snapshot {
+    fn size(&self) -> usize {
+        match self {
+            Snapshot::Keyframe(data) | Snapshot::Delta(data) => data.len(),
+        }
+    }
+}
+
+/// Stores save states created by [`Emulator::save_state`] while their total
+/// size stays within a memory budget. The oldest states are dropped first.
+///
+/// [`Emulator::save_state`]: crate::emulator::Emulator::save_state
+pub struct RewindBuffer {
+    snapshots: VecDeque<Snapshot>,
+    // Indices into `snapshots`, the first snapshot is always a keyframe
+    keyframes: VecDeque<usize>,
+    budget: usize,
+    used: usize,
+}
+
+impl RewindBuffer {
+    /// Creates a new [`RewindBuffer`] which uses at most `budget` bytes for
+    /// the states.
+    pub fn new(budget: usize) -> Self {
+        Self {
+            snapshots: VecDeque::new(),
+            keyframes: VecDeque::new(),
+            budget,
+            used: 0,
+        }
+    }
+
+    /// The amount of stored states.
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// The amount of bytes used by the stored states.
+    pub fn memory(&self) -> usize {
+        self.used
+    }
+
+    /// Adds the newest state.
+    pub fn push(&mut self, state: Vec<u8>) {
+        let keyframe = self
+            .keyframes
+            .back()
+            .map(|&index| (index, &self.snapshots[index]));
+        let snapshot = match keyframe {
+            Some((index, Snapshot::Keyframe(keyframe)))
+                if self.snapshots.len() - index < KEYFRAME_INTERVAL
+                    && keyframe.len() == state.len() =>
+            {
+                Snapshot::Delta(diff(keyframe, &state))
+            }
+            _ => {
+                self.keyframes.push_back(self.snapshots.len());
+                Snapshot::Keyframe(state)
+            }
+        };
+        self.used += snapshot.size();
+        self.snapshots.push_back(snapshot);
+
+        // A keyframe can only be dropped together with its deltas
+        while self.used > self.budget && !self.snapshots.is_empty() {
+            let end = self
+                .keyframes
+                .get(1)
+                .copied()
+                .unwrap_or(self.snapshots.len());
+            for snapshot in self.snapshots.drain(..end) {
+                self.used -= snapshot.size();
+            }
+            self.keyframes.pop_front();
+            for index in &mut self.keyframes {
+                *index -= end;
+            }
+        }
+    }
+
+    /// Removes and returns the newest state.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let snapshot = self.snapshots.pop_back()?;
+        self.used -= snapshot.size();
+        match snapshot {
+            Snapshot::Keyframe(state) => {
+                self.keyframes.pop_back();
+                Some(state)
+            }
+            Snapshot::Delta(delta) => {
+                let index = *self.keyframes.back()?;
+                match &self.snapshots[index] {
+                    Snapshot::Keyframe(keyframe) => Some(patch(keyframe, &delta)),
+                    Snapshot::Delta(_) => unreachable!("keyframe indices point at keyframes"),
+                }
+            }
+        }
+    }
+
+    /// Returns the state at `index`, counting from the oldest state.
+    pub fn get(&self, index: usize) -> Option<Vec<u8>> {
+        match self.snapshots.get(index)? {
+            Snapshot::Keyframe(state) => Some(state.clone()),
+            Snapshot::Delta(delta) => {
+                let keyframe = self
+                    .keyframes
+                    .partition_point(|&keyframe| keyframe <= index);
+                match &self.snapshots[self.keyframes[keyframe - 1]] {
+                    Snapshot::Keyframe(keyframe) => Some(patch(keyframe, delta)),
+                    Snapshot::Delta(_) => unreachable!("keyframe indices point at keyframes"),
+                }
+            }
+        }
+    }
+
+    /// Removes the newest states until at most `len` states are left.
+    pub fn truncate(&mut self, len: usize) {
+        for snapshot in self.snapshots.drain(len.min(self.snapshots.len())..) {
+            self.used -= snapshot.size();
+        }
+        while self.keyframes.back().is_some_and(|&index| index >= len) {
+            self.keyframes.pop_back();
+        }
+    }
+
+    /// Removes all states.
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.keyframes.clear();
+        self.used = 0;
+    }
+}
+
+/// Encodes the bytes of `new` which differ from `old`. Both have the same
+/// length.
+fn diff(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut position = 0;
+    while position < new.len() {
+        let equal = (position..new.len())
+            .find(|&i| old[i] != new[i])
+            .unwrap_or(new.len())
+            - position;
+        let start = position + equal;
+        if start == new.len() {
+            break;
+        }
+        let different = (start..new.len())
+            .find(|&i| old[i] == new[i])
+            .unwrap_or(new.len())
+            - start;
+        delta.extend_from_slice(&(equal as u32).to_le_bytes());
+        delta.extend_from_slice(&(different as u32).to_le_bytes());
+        delta.extend_from_slice(&new[start..start + different]);
+        position = start + different;
+    }
+    delta
+}
+
+/// Applies a delta created by [`diff`] to `old`.
+fn patch(old: &[u8], mut delta: &[u8]) -> Vec<u8> {
+    let mut new = old.to_vec();
+    let mut position = 0;
+    let read = |delta: &mut &[u8]| {
+        let (value, rest) = delta.split_at(4);
+        *delta = rest;
+        u32::from_le_bytes(value.try_into().unwrap()) as usize
+    };
+    while !delta.is_empty() {
+        position += read(&mut delta);
+        let different = read(&mut delta);
+        new[position..position + different].copy_from_slice(&delta[..different]);
+        delta = &delta[different..];
+        position += different;
+    }
+    new
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(frame: u8) -> Vec<u8> {
+        let mut state = vec![0; 100];
+        state[frame as usize] = frame;
+        state[99] = frame;
+        state
+    }
+
+    #[test]
+    fn test_diff_and_patch() {
+        let old = [1, 2, 3, 4, 5, 6];
+        for new in [[1, 2, 3, 4, 5, 6], [9, 2, 3, 4, 5, 9], [1, 9, 9, 4, 9, 6]] {
+            assert_eq!(patch(&old, &diff(&old, &new)), new);
+        }
+        assert!(diff(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_push_and_pop() {
+        let mut buffer = RewindBuffer::new(usize::MAX);
+        for frame in 0..70 {
+            buffer.push(state(frame));
+        }
+        assert_eq!(buffer.len(), 70);
+        // Deltas are smaller than complete states
+        assert!(buffer.memory() < 70 * 100 / 2);
+        for frame in (0..70).rev() {
+            assert_eq!(buffer.pop(), Some(state(frame)));
+        }
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.memory(), 0);
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut buffer = RewindBuffer::new(1000);
+        for frame in 0..90 {
+            buffer.push(state(frame));
+            assert!(buffer.memory() <= 1000);
+        }
+        // The oldest keyframes were dropped with their deltas
+        assert_eq!(buffer.len(), KEYFRAME_INTERVAL);
+        let mut last = None;
+        while let Some(state) = buffer.pop() {
+            last = Some(state);
+        }
+        assert_eq!(last, Some(state(90 - KEYFRAME_INTERVAL as u8)));
+    }
+
+    #[test]
+    fn test_get_and_truncate() {
+        let mut buffer = RewindBuffer::new(usize::MAX);
+        for frame in 0..40 {
+            buffer.push(state(frame));
+        }
+        assert_eq!(buffer.get(35), Some(state(35)));
+        assert_eq!(buffer.get(40), None);
+        buffer.truncate(32);
+        assert_eq!(buffer.len(), 32);
+        buffer.truncate(40);
+        assert_eq!(buffer.len(), 32);
+        buffer.push(state(40));
+        assert_eq!(buffer.pop(), Some(state(40)));
+        assert_eq!(buffer.pop(), Some(state(31)));
+    }
+
+    #[test]
+    fn test_different_sizes() {
+        let mut buffer = RewindBuffer::new(usize::MAX);
+        buffer.push(vec![1; 10]);
+        buffer.push(vec![2; 20]);
+        buffer.push(vec![3; 20]);
+        assert_eq!(buffer.pop(), Some(vec![3; 20]));
+        assert_eq!(buffer.pop(), Some(vec![2; 20]));
+        assert_eq!(buffer.pop(), Some(vec![1; 10]));
+    }
+}
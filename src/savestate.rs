@@ -0,0 +1,324 @@
+//! The binary format of save states.
+//!
+//! A save state starts with [`MAGIC`] and the format [`VERSION`] as
+//! little-endian [`u16`], followed by the cpu, the [`EmulatorState`], the
+//! cycle counter of the [`Emulator`] and the state of its random number
+//! generator. All numbers are little-endian.
+//!
+//! [`EmulatorState`]: crate::emulator::EmulatorState
+//! [`Emulator`]: crate::emulator::Emulator
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::cpu::KeyState;
+
+/// Identifies a save state.
+pub const MAGIC: [u8; 4] = *b"C8ST";
+/// The version of the format written by this crate.
+pub const VERSION: u16 = 1;
+
+/// An error that occurred while loading a save state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    /// The data does not start with [`MAGIC`].
+    NotAState,
+    /// The state was written by an incompatible version of the format.
+    UnsupportedVersion(u16),
+    /// The data ended before the state was complete.
+    Truncated,
+    /// The state contains a value that can't be restored.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAState => write!(f, "Not a save state"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported save state version: {}", version)
+            }
+            Self::Truncated => write!(f, "Save state is truncated"),
+            Self::Invalid(what) => write!(f, "Invalid {} in save state", what),
+        }
+    }
+}
+
+impl core::error::Error for StateError {}
+
+/// A part of the emulator that is included in save states.
+pub(crate) trait Persist: Sized {
+    fn save(&self, out: &mut Writer);
+    fn restore(input: &mut Reader) -> Result<Self, StateError>;
+}
+
+pub(crate) struct Writer(Vec<u8>);
+
+impl Writer {
+    /// Creates a [`Writer`] which already contains the save state header.
+    pub fn new() -> Self {
+        Self::with_header(&MAGIC, VERSION)
+    }
+
+    /// Creates a [`Writer`] for another format that uses the same kind of
+    /// header.
+    pub fn with_header(magic: &[u8; 4], version: u16) -> Self {
+        let mut out = Self(Vec::new());
+        out.bytes(magic);
+        out.u16(version);
+        out
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.0
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value.into());
+    }
+
+    /// Writes a [`KeyState`] as bitmask.
+    pub fn keys(&mut self, keys: &KeyState) {
+        let mask = (0..16).fold(0, |mask, key| mask | u16::from(keys[key]) << key);
+        self.u16(mask);
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Creates a [`Reader`] after checking the save state header.
+    pub fn new(data: &'a [u8]) -> Result<Self, StateError> {
+        Self::with_header(data, &MAGIC, VERSION)
+    }
+
+    /// Creates a [`Reader`] for another format that uses the same kind of
+    /// header.
+    pub fn with_header(data: &'a [u8], magic: &[u8; 4], version: u16) -> Result<Self, StateError> {
+        let mut input = Self { data };
+        if input.bytes(magic.len()).ok() != Some(&magic[..]) {
+            return Err(StateError::NotAState);
+        }
+        match input.u16()? {
+            found if found == version => Ok(input),
+            found => Err(StateError::UnsupportedVersion(found)),
+        }
+    }
+
+    /// Checks that all data was read.
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(StateError::Invalid("trailing data"))
+        }
+    }
+
+    pub fn bytes(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < length {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        // The slice has exactly N bytes
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid("boolean")),
+        }
+    }
+
+    pub fn keys(&mut self) -> Result<KeyState, StateError> {
+        let mask = self.u16()?;
+        Ok(core::array::from_fn(|key| mask & 1 << key != 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{display::NullRenderer, emulator::Emulator, platform::Platform, quirks::Quirks};
+
+    // Draws a growing column of pixels in a subroutine:
+    // 200: LD I, 0x20C; 202: CALL 0x208; 204: ADD V1, 1; 206: JP 0x202
+    // 208: DRW V0, V1, 1; 20A: RET; 20C: DB 0x80
+    fn emulator() -> Emulator<NullRenderer> {
+        let mut emulator = Emulator::new(NullRenderer, 600, Platform::XoChip, Quirks::XO_CHIP);
+        emulator
+            .load_rom(&[
+                0xA2, 0x0C, 0x22, 0x08, 0x71, 0x01, 0x12, 0x02, 0xD0, 0x11, 0x00, 0xEE, 0x80,
+            ])
+            .unwrap();
+        emulator
+    }
+
+    #[test]
+    fn test_header() {
+        let data = Writer::new().finish();
+        assert!(Reader::new(&data).unwrap().finish().is_ok());
+        assert_eq!(
+            Reader::new(b"C8SU\x01\x00").err(),
+            Some(StateError::NotAState)
+        );
+        assert_eq!(
+            Reader::new(b"C8ST\x07\x00").err(),
+            Some(StateError::UnsupportedVersion(7))
+        );
+        assert_eq!(Reader::new(b"C8ST\x01").err(), Some(StateError::Truncated));
+    }
+
+    #[test]
+    fn test_values() {
+        let mut out = Writer::new();
+        out.u8(0xAB);
+        out.u16(0x1234);
+        out.u32(0xDEAD_BEEF);
+        out.u64(u64::MAX - 1);
+        out.bool(true);
+        out.u8(2);
+        let data = out.finish();
+
+        let mut input = Reader::new(&data).unwrap();
+        assert_eq!(input.u8(), Ok(0xAB));
+        assert_eq!(input.u16(), Ok(0x1234));
+        assert_eq!(input.u32(), Ok(0xDEAD_BEEF));
+        assert_eq!(input.u64(), Ok(u64::MAX - 1));
+        assert_eq!(input.bool(), Ok(true));
+        assert_eq!(input.bool(), Err(StateError::Invalid("boolean")));
+        assert_eq!(input.u8(), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut emulator = emulator();
+        for _ in 0..50 {
+            emulator.step().unwrap();
+        }
+        emulator.state.delay_timer.set(30);
+        emulator.state.key_state[0xA] = true;
+        emulator.state.rpl_flags[3] = 7;
+        let saved = emulator.save_state();
+        let frame_buffer = emulator.state.frame_buffer.clone();
+        let (pc, stack) = (emulator.cpu.pc, emulator.cpu.stack.clone());
+
+        for _ in 0..50 {
+            emulator.step().unwrap();
+        }
+        assert_ne!(emulator.state.frame_buffer, frame_buffer);
+
+        emulator.load_state(&saved).unwrap();
+        assert_eq!(emulator.state.frame_buffer, frame_buffer);
+        assert_eq!((emulator.cpu.pc, &emulator.cpu.stack), (pc, &stack));
+        assert_eq!(emulator.state.delay_timer.get(), 30);
+        assert!(emulator.state.key_state[0xA]);
+        assert_eq!(emulator.state.rpl_flags[3], 7);
+        assert_eq!(emulator.save_state(), saved);
+    }
+
+    #[test]
+    fn test_random_state() {
+        // 200: RND V0, 0xFF; 202: JP 0x200
+        let mut emulator = emulator();
+        emulator.load_rom(&[0xC0, 0xFF, 0x12, 0x00]).unwrap();
+        emulator.seed(7);
+        let numbers = |emulator: &mut Emulator<NullRenderer>| -> Vec<u8> {
+            (0..10)
+                .map(|_| {
+                    emulator.step().unwrap();
+                    emulator.step().unwrap();
+                    emulator.cpu.get_register(0).unwrap()
+                })
+                .collect()
+        };
+
+        let saved = emulator.save_state();
+        let first = numbers(&mut emulator);
+        emulator.load_state(&saved).unwrap();
+        assert_eq!(numbers(&mut emulator), first);
+
+        emulator.seed(7);
+        emulator.load_rom(&[0xC0, 0xFF, 0x12, 0x00]).unwrap();
+        assert_eq!(numbers(&mut emulator), first);
+    }
+
+    #[test]
+    fn test_key_wait() {
+        // 200: LD V2, K
+        let mut emulator = emulator();
+        emulator.load_rom(&[0xF2, 0x0A]).unwrap();
+        emulator.state.key_state[4] = true;
+        emulator.step().unwrap();
+        let saved = emulator.save_state();
+
+        emulator.state.key_state[4] = false;
+        emulator.step().unwrap();
+        emulator.load_state(&saved).unwrap();
+        assert!(emulator.cpu.waiting_for_key());
+        // Key 4 is still held from before FX0A
+        emulator.step().unwrap();
+        emulator.state.key_state[4] = false;
+        emulator.step().unwrap();
+        assert!(emulator.cpu.waiting_for_key());
+    }
+
+    #[test]
+    fn test_invalid_states() {
+        let mut emulator = emulator();
+        let saved = emulator.save_state();
+        emulator.step().unwrap();
+
+        let truncated = &saved[..saved.len() - 1];
+        assert_eq!(emulator.load_state(truncated), Err(StateError::Truncated));
+        let mut trailing = saved.clone();
+        trailing.push(0);
+        assert!(emulator.load_state(&trailing).is_err());
+        // Failed loads leave the emulator untouched
+        assert_eq!(emulator.cpu.pc, 0x202);
+    }
+}
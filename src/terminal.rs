@@ -0,0 +1,238 @@
+//! A front-end for terminals, e.g. to play over SSH.
+//!
+//! [`TerminalRenderer`] draws two pixels per character cell using the upper
+//! half block `▀` with the top pixel as foreground and the bottom pixel as
+//! background colour. [`TerminalInput`] reads keys from stdin in raw mode.
+
+use std::{
+    io::{self, Stdout, Write},
+    time::{Duration, Instant},
+};
+
+use crossterm::{
+    cursor::{Hide, MoveTo, Show},
+    event::{
+        self, Event, KeyCode, KeyEvent, KeyEventKind, KeyboardEnhancementFlags,
+        PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+    },
+    execute, queue,
+    style::{Color, Colors, Print, ResetColor, SetColors},
+    terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+};
+
+use crate::display::{FrameBuffer, Palette, Render, RenderError, DEFAULT_PALETTE};
+
+/// How long a key counts as held after the terminal last sent it, if the
+/// terminal does not report releases.
+const HOLD_TIME: Duration = Duration::from_millis(200);
+
+/// Draws the [`FrameBuffer`] on the alternate screen of the terminal with ANSI
+/// colours. Only cells that changed since the last draw are redrawn.
+pub struct TerminalRenderer<W: Write = Stdout> {
+    out: W,
+    palette: Palette,
+    width: usize,
+    // The top and bottom pixel of every cell as last drawn, `None` if the cell
+    // has to be drawn
+    cells: Vec<Option<(u8, u8)>>,
+}
+
+impl TerminalRenderer {
+    /// Switches to the alternate screen of stdout, which is left again when
+    /// the renderer is dropped.
+    pub fn new() -> io::Result<Self> {
+        Self::with_output(io::stdout())
+    }
+}
+
+impl<W: Write> TerminalRenderer<W> {
+    /// Creates a renderer writing to another terminal than stdout.
+    pub fn with_output(mut out: W) -> io::Result<Self> {
+        execute!(out, EnterAlternateScreen, Hide, Clear(ClearType::All))?;
+        Ok(Self {
+            out,
+            palette: DEFAULT_PALETTE,
+            width: 0,
+            cells: Vec::new(),
+        })
+    }
+
+    /// Changes the colours used to draw the planes.
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        self.cells.fill(None);
+    }
+
+    fn queue_changes(&mut self, frame_buffer: &FrameBuffer) -> io::Result<()> {
+        let out = &mut self.out;
+        // Every cell shows two rows of pixels
+        let (width, rows) = (frame_buffer.width(), frame_buffer.height() / 2);
+        if width != self.width {
+            self.width = width;
+            self.cells.clear();
+            queue!(out, Clear(ClearType::All))?;
+        }
+        self.cells.resize(width * rows, None);
+
+        // The cursor moves to the right by itself after printing a cell and
+        // the colours stay set
+        let (mut cursor, mut colors) = (None, None);
+        for row in 0..rows {
+            for x in 0..width {
+                let cell = (
+                    frame_buffer.get(x, row * 2),
+                    frame_buffer.get(x, row * 2 + 1),
+                );
+                let drawn = &mut self.cells[row * width + x];
+                if *drawn == Some(cell) {
+                    continue;
+                }
+                *drawn = Some(cell);
+                if cursor != Some((x, row)) {
+                    queue!(out, MoveTo(x as u16, row as u16))?;
+                }
+                if colors != Some(cell) {
+                    let (top, bottom) =
+                        (self.palette[cell.0 as usize], self.palette[cell.1 as usize]);
+                    queue!(out, SetColors(Colors::new(to_color(top), to_color(bottom))))?;
+                    colors = Some(cell);
+                }
+                queue!(out, Print('▀'))?;
+                cursor = Some((x + 1, row));
+            }
+        }
+        queue!(out, ResetColor)
+    }
+}
+
+fn to_color([r, g, b]: [u8; 3]) -> Color {
+    Color::Rgb { r, g, b }
+}
+
+impl<W: Write> Render for TerminalRenderer<W> {
+    fn draw(&mut self, frame_buffer: &FrameBuffer) -> Result<(), RenderError> {
+        self.queue_changes(frame_buffer)?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for TerminalRenderer<W> {
+    fn drop(&mut self) {
+        // Nothing can be done if the terminal can't be restored
+        let _ = execute!(self.out, ResetColor, Show, LeaveAlternateScreen);
+    }
+}
+
+/// Reads key events from stdin in raw mode, which is left again when the
+/// input is dropped.
+///
+/// Most terminals only report key presses and repeat them while a key is
+/// held. If the terminal does not support reporting releases, a key counts as
+/// released once it was not repeated for a short while.
+pub struct TerminalInput {
+    reports_releases: bool,
+    // The keys currently held and when the terminal last sent them
+    held: Vec<(KeyCode, Instant)>,
+}
+
+impl TerminalInput {
+    pub fn new() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let reports_releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if reports_releases {
+            execute!(
+                io::stdout(),
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )?;
+        }
+        Ok(Self {
+            reports_releases,
+            held: Vec::new(),
+        })
+    }
+
+    /// Returns the key events that arrived since the last call without
+    /// blocking. Repeated presses of a held key are left out and every press
+    /// is eventually followed by a release.
+    pub fn poll(&mut self) -> io::Result<Vec<KeyEvent>> {
+        let mut events = Vec::new();
+        while event::poll(Duration::ZERO)? {
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            let held = self.held.iter().position(|(code, _)| *code == key.code);
+            match (key.kind, held) {
+                (KeyEventKind::Release, Some(index)) => {
+                    self.held.remove(index);
+                    events.push(key);
+                }
+                (KeyEventKind::Release, None) => {}
+                (_, Some(index)) => self.held[index].1 = Instant::now(),
+                (_, None) => {
+                    self.held.push((key.code, Instant::now()));
+                    events.push(KeyEvent {
+                        kind: KeyEventKind::Press,
+                        ..key
+                    });
+                }
+            }
+        }
+        if !self.reports_releases {
+            self.held.retain(|(code, time)| {
+                let held = time.elapsed() < HOLD_TIME;
+                if !held {
+                    events.push(KeyEvent {
+                        kind: KeyEventKind::Release,
+                        ..KeyEvent::from(*code)
+                    });
+                }
+                held
+            });
+        }
+        Ok(events)
+    }
+}
+
+impl Drop for TerminalInput {
+    fn drop(&mut self) {
+        // Nothing can be done if the terminal can't be restored
+        if self.reports_releases {
+            let _ = execute!(io::stdout(), PopKeyboardEnhancementFlags);
+        }
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counts the cells drawn since the last call
+    fn drawn(renderer: &mut TerminalRenderer<Vec<u8>>) -> usize {
+        let cells = String::from_utf8_lossy(&renderer.out).matches('▀').count();
+        renderer.out.clear();
+        cells
+    }
+
+    #[test]
+    fn test_redraws_changed_cells() {
+        let mut renderer = TerminalRenderer::with_output(Vec::new()).unwrap();
+        let mut frame_buffer = FrameBuffer::default();
+        renderer.draw(&frame_buffer).unwrap();
+        assert_eq!(drawn(&mut renderer), 64 * 16);
+        renderer.draw(&frame_buffer).unwrap();
+        assert_eq!(drawn(&mut renderer), 0);
+
+        // Both pixels share a cell
+        frame_buffer.set(3, 4, 1);
+        frame_buffer.set(3, 5, 1);
+        frame_buffer.set(10, 31, 1);
+        renderer.draw(&frame_buffer).unwrap();
+        assert_eq!(drawn(&mut renderer), 2);
+
+        frame_buffer.set_hires(true);
+        renderer.draw(&frame_buffer).unwrap();
+        assert_eq!(drawn(&mut renderer), 128 * 32);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::savestate::{Persist, Reader, StateError, Writer};
+
 #[derive(Default)]
 pub struct Timer(u8);
 
@@ -22,6 +24,16 @@ impl Timer {
     }
 }
 
+impl Persist for Timer {
+    fn save(&self, out: &mut Writer) {
+        out.u8(self.0);
+    }
+
+    fn restore(input: &mut Reader) -> Result<Self, StateError> {
+        Ok(Self(input.u8()?))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;